@@ -0,0 +1,603 @@
+use crate::cgroup::RunCgroup;
+use crate::{CgroupReport, InternalError, IoStats, MemoryCounter, SandboxError, Termination};
+use nix::sys::signal::Signal;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// 监视一次运行时需要的 cgroup 与子进程操作
+///
+/// 真实实现为 `CgroupBackend`，测试中使用内存中的 `mock::MockBackend`，
+/// 使得超时、清理和结果分类的逻辑不需要 root 权限也能测试
+pub(crate) trait SandboxBackend: Send + 'static {
+    /// 等待程序进入 cgroup
+    fn wait_ready(&self, timeout: Duration) -> Result<Ready, SandboxError>;
+    /// cgroup 内是否还有进程
+    fn is_empty(&self) -> Result<bool, SandboxError>;
+    /// 到目前为止消耗的 CPU 时间
+    fn cpu_time(&self) -> Result<Duration, SandboxError>;
+    /// 能否统计 CPU 时间（主机上是否有 cpuacct controller）
+    fn has_cpu_time(&self) -> bool;
+    /// memsw 的峰值，不可用时为不含 swap 的峰值
+    fn max_memory(&self) -> Result<(u64, MemoryCounter), SandboxError>;
+    /// 内存峰值减去页缓存
+    fn max_rss(&self) -> Result<u64, SandboxError>;
+    /// 运行中是否用到了 swap，memsw 不可用时为 None
+    fn swap_used(&self) -> Result<Option<bool>, SandboxError>;
+    /// 块设备 `major:minor` 上的读写字节数
+    fn io_stats(&self, device: (u64, u64)) -> Result<Option<IoStats>, SandboxError>;
+    /// cgroup 是否因达到内存限制而被暂停
+    fn under_oom(&self) -> Result<bool, SandboxError>;
+    /// cgroup 内线程最多的进程的线程数
+    fn max_threads(&self) -> Result<u32, SandboxError>;
+    /// 至今读取到的最大进程（线程）数，每次调用都会采样一次
+    fn max_pids(&self) -> Result<u64, SandboxError>;
+    /// 因达到 Pid 限制而失败的 fork 次数
+    fn pids_denied(&self) -> Result<u64, SandboxError>;
+    /// 读取原始的 cgroup 统计
+    fn report(&self) -> Result<CgroupReport, SandboxError>;
+    /// 子进程报告的程序执行的指令数和是否超过上限，没有计数时为 None，只在子进程退出后调用
+    ///
+    /// 子进程报告等待程序结束失败时返回 `InternalError::WaitFailed`
+    fn instructions(&self) -> Result<Option<(u64, bool)>, SandboxError>;
+    /// 向 cgroup 内所有进程发送 `signal`
+    fn signal_all_tasks(&self, signal: Signal) -> Result<(), SandboxError>;
+    /// 杀死 cgroup 内所有进程
+    fn kill_all_tasks(&self, timeout: Duration) -> Result<(), SandboxError>;
+    /// 两次检查之间等待最多 `timeout`，fork 出的子进程退出时提前返回
+    fn wait_exit(&self, timeout: Duration) -> Result<(), SandboxError>;
+    /// 在 `grace` 时间内等待 fork 出的子进程退出，仍在运行时返回 None
+    fn wait_child(&self, grace: Duration) -> Result<Option<Termination>, SandboxError>;
+    /// 杀死 fork 出的子进程并回收
+    fn kill_child(&self) -> Result<(), SandboxError>;
+    /// 回收子进程时得到的（用户态，内核态）CPU 时间，还没有回收时为 None
+    fn rusage(&self) -> Option<(Duration, Duration)>;
+}
+
+/// 子进程通过启动通知管道报告的结果
+#[derive(Debug)]
+pub(crate) enum Ready {
+    /// 程序已经进入 cgroup 并开始运行
+    Started,
+    /// 程序没能启动（fork 1 号进程、exec 前的设置或 exec 本身失败），包含子进程报告的错误
+    Failed(std::io::Error),
+    /// 超过等待时间，或子进程没有报告就退出了
+    Unknown,
+}
+
+/// 启动通知管道中的消息：成功时为 `READY_STARTED`，
+/// 失败时为 `READY_FAILED` 后接 4 字节（本机字节序）的 errno
+///
+/// 程序启动后子进程自身出错（如等待程序结束失败）时同样写入 `READY_FAILED`
+pub(crate) const READY_STARTED: u8 = 1;
+pub(crate) const READY_FAILED: u8 = 0;
+
+/// 程序结束后子进程写入的指令数：`READY_INSTRUCTIONS` 后接 8 字节（本机字节序）的指令数
+/// 和 1 字节的是否超过上限
+pub(crate) const READY_INSTRUCTIONS: u8 = 2;
+
+/// 子进程中写入程序执行的指令数，`exceeded` 为程序是否因超过上限被杀死
+pub(crate) fn report_instructions<W: std::io::Write>(write: &mut W, count: u64, exceeded: bool) {
+    let mut message = vec![READY_INSTRUCTIONS];
+    message.extend_from_slice(&count.to_ne_bytes());
+    message.push(exceeded as u8);
+    write.write_all(&message).ok();
+}
+
+/// 子进程中写入启动通知，`result` 为启动程序的结果
+pub(crate) fn report_ready<W: std::io::Write, T>(write: &mut W, result: &std::io::Result<T>) {
+    match result {
+        Ok(_) => {
+            write.write_all(&[READY_STARTED]).ok();
+        }
+        Err(err) => report_failure(write, err),
+    }
+}
+
+/// 子进程中报告自身的错误，不使用退出码，以免与程序的退出码混淆
+pub(crate) fn report_failure<W: std::io::Write>(write: &mut W, err: &std::io::Error) {
+    let mut message = vec![READY_FAILED];
+    message.extend_from_slice(&err.raw_os_error().unwrap_or(0).to_ne_bytes());
+    write.write_all(&message).ok();
+}
+
+/// 读取 `READY_FAILED` 之后的 errno，没有时使用 `default`
+fn read_failure(mut read: &std::fs::File, default: &str) -> std::io::Error {
+    use std::io::Read;
+    let mut errno = [0u8; 4];
+    match read.read_exact(&mut errno) {
+        Ok(()) if i32::from_ne_bytes(errno) != 0 => {
+            std::io::Error::from_raw_os_error(i32::from_ne_bytes(errno))
+        }
+        _ => std::io::Error::other(default.to_string()),
+    }
+}
+
+/// 基于 cgroup 和 fork 出的子进程的真实实现
+#[derive(Debug)]
+pub(crate) struct CgroupBackend {
+    pub(crate) child: nix::unistd::Pid,
+    pub(crate) cgroup: Arc<RunCgroup>,
+    /// 子进程启动程序后写入一个字节：1 表示程序已进入 cgroup，0 表示启动失败
+    pub(crate) ready: std::fs::File,
+    /// 子进程的 pidfd，子进程退出后可读；内核不支持（早于 5.3）时为 None
+    pub(crate) pidfd: Option<OwnedFd>,
+    /// 已经通过 pidfd 观察到子进程退出，之后 pidfd 一直可读，只能按固定间隔等待
+    pub(crate) child_exited: AtomicBool,
+    /// 杀死所有进程前是否先冻结 cgroup，见 `SandboxConfig::use_freezer_on_kill`
+    pub(crate) use_freezer: bool,
+    /// 回收子进程时 `wait4` 得到的（用户态，内核态）CPU 时间
+    pub(crate) rusage: Mutex<Option<(Duration, Duration)>>,
+}
+
+impl CgroupBackend {
+    /// 用 `wait4` 回收子进程并记录其 CPU 时间，`options` 含 WNOHANG 且子进程仍在运行时
+    /// 返回 `WaitStatus::StillAlive`
+    fn wait4(&self, options: libc::c_int) -> Result<nix::sys::wait::WaitStatus, SandboxError> {
+        use nix::sys::wait::WaitStatus;
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        let pid = loop {
+            let pid = unsafe { libc::wait4(self.child.as_raw(), &mut status, options, &mut usage) };
+            match nix::errno::Errno::result(pid) {
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                result => break result?,
+            }
+        };
+        if pid == 0 {
+            return Ok(WaitStatus::StillAlive);
+        }
+        let time = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+        *self.rusage.lock().unwrap_or_else(|err| err.into_inner()) =
+            Some((time(usage.ru_utime), time(usage.ru_stime)));
+        Ok(WaitStatus::from_raw(self.child, status)?)
+    }
+}
+
+/// `pidfd_open` 的系统调用号，所有架构相同
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+
+/// 打开 `pid` 的 pidfd，内核不支持时返回 None
+pub(crate) fn pidfd_open(pid: nix::unistd::Pid) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid.as_raw(), 0) };
+    if fd < 0 {
+        log::debug!(
+            "pidfd_open is not available: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    // 系统调用返回的 fd 由 OwnedFd 接管
+    Some(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+impl SandboxBackend for CgroupBackend {
+    fn wait_ready(&self, timeout: Duration) -> Result<Ready, SandboxError> {
+        use nix::poll::{poll, PollFd, PollFlags};
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        let mut fds = [PollFd::new(self.ready.as_raw_fd(), PollFlags::POLLIN)];
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            match poll(&mut fds, remaining.as_millis() as i32) {
+                Ok(0) => return Ok(Ready::Unknown),
+                Ok(_) => break,
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let mut byte = [0u8];
+        // 子进程没有写入就退出时读到 EOF
+        if (&self.ready).read(&mut byte)? == 0 {
+            return Ok(Ready::Unknown);
+        }
+        match byte[0] {
+            READY_STARTED => Ok(Ready::Started),
+            READY_FAILED => Ok(Ready::Failed(read_failure(
+                &self.ready,
+                "command failed to start",
+            ))),
+            _ => Ok(Ready::Unknown),
+        }
+    }
+    fn is_empty(&self) -> Result<bool, SandboxError> {
+        self.cgroup.is_empty()
+    }
+    fn cpu_time(&self) -> Result<Duration, SandboxError> {
+        self.cgroup.get_cpu_time()
+    }
+    fn has_cpu_time(&self) -> bool {
+        self.cgroup.has_cpu_time()
+    }
+    fn max_memory(&self) -> Result<(u64, MemoryCounter), SandboxError> {
+        self.cgroup.get_peak_memory()
+    }
+    fn max_rss(&self) -> Result<u64, SandboxError> {
+        self.cgroup.get_max_rss()
+    }
+    fn swap_used(&self) -> Result<Option<bool>, SandboxError> {
+        self.cgroup.get_swap_used()
+    }
+    fn io_stats(&self, device: (u64, u64)) -> Result<Option<IoStats>, SandboxError> {
+        self.cgroup.get_io_stats(device)
+    }
+    fn under_oom(&self) -> Result<bool, SandboxError> {
+        self.cgroup.under_oom()
+    }
+    fn max_threads(&self) -> Result<u32, SandboxError> {
+        self.cgroup.get_max_threads()
+    }
+    fn max_pids(&self) -> Result<u64, SandboxError> {
+        self.cgroup.get_max_pids()
+    }
+    fn pids_denied(&self) -> Result<u64, SandboxError> {
+        self.cgroup.get_pids_denied()
+    }
+    fn report(&self) -> Result<CgroupReport, SandboxError> {
+        self.cgroup.get_report()
+    }
+    fn instructions(&self) -> Result<Option<(u64, bool)>, SandboxError> {
+        use nix::poll::{poll, PollFd, PollFlags};
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        // 子进程已经退出，消息如果存在已经在管道中；其他子进程可能还持有写入端，不能等待 EOF
+        let mut fds = [PollFd::new(self.ready.as_raw_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, 0)? == 0 {
+            return Ok(None);
+        }
+        let mut kind = [0u8];
+        if (&self.ready).read(&mut kind)? == 0 {
+            return Ok(None);
+        }
+        match kind[0] {
+            READY_INSTRUCTIONS => {
+                let mut message = [0u8; 9];
+                if (&self.ready).read(&mut message)? != message.len() {
+                    return Ok(None);
+                }
+                let mut count = [0u8; 8];
+                count.copy_from_slice(&message[..8]);
+                Ok(Some((u64::from_ne_bytes(count), message[8] != 0)))
+            }
+            READY_FAILED => Err(SandboxError::Internal(InternalError::WaitFailed(
+                read_failure(&self.ready, "failed to wait for command"),
+            ))),
+            _ => Ok(None),
+        }
+    }
+    fn signal_all_tasks(&self, signal: Signal) -> Result<(), SandboxError> {
+        self.cgroup.signal_all_tasks(signal)
+    }
+    fn kill_all_tasks(&self, timeout: Duration) -> Result<(), SandboxError> {
+        self.cgroup.kill_all_tasks(timeout, self.use_freezer)
+    }
+    fn wait_exit(&self, timeout: Duration) -> Result<(), SandboxError> {
+        use nix::poll::{poll, PollFd, PollFlags};
+        match &self.pidfd {
+            Some(pidfd) if !self.child_exited.load(Ordering::Acquire) => {
+                let mut fds = [PollFd::new(pidfd.as_raw_fd(), PollFlags::POLLIN)];
+                // 向上取整，避免不足 1ms 时 poll 立即返回
+                match poll(&mut fds, timeout.as_micros().div_ceil(1000) as i32) {
+                    Ok(0) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+                    Ok(_) => self.child_exited.store(true, Ordering::Release),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            _ => std::thread::sleep(timeout),
+        }
+        Ok(())
+    }
+    fn wait_child(&self, grace: Duration) -> Result<Option<Termination>, SandboxError> {
+        use nix::sys::wait::WaitStatus;
+        let delay = Duration::from_millis(10);
+        let mut grace = grace;
+        loop {
+            match self.wait4(libc::WNOHANG)? {
+                WaitStatus::Exited(_pid, code) => return Ok(Some(Termination::Exited(code))),
+                WaitStatus::Signaled(_pid, signal, _) => {
+                    return Ok(Some(Termination::Signaled(signal)))
+                }
+                _ => {}
+            }
+            if grace < delay {
+                return Ok(None);
+            }
+            std::thread::sleep(delay);
+            grace -= delay;
+        }
+    }
+    fn kill_child(&self) -> Result<(), SandboxError> {
+        nix::sys::signal::kill(self.child, Signal::SIGKILL)?;
+        self.wait4(0)?;
+
+        Ok(())
+    }
+    fn rusage(&self) -> Option<(Duration, Duration)> {
+        *self.rusage.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::{Ready, SandboxBackend};
+    use crate::{
+        CgroupReport, InternalError, IoStats, MemoryCounter, SandboxError, TaskState, Termination,
+    };
+    use nix::sys::signal::Signal;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// 按预设剧本运行的假程序
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockProgram {
+        /// 程序自行结束的时间，None 表示永不结束
+        pub(crate) exit_after: Option<Duration>,
+        /// 程序自行结束时的结束方式
+        pub(crate) termination: Termination,
+        /// 收到后会自行结束的信号
+        pub(crate) handles: Option<Signal>,
+        /// 结束时的 CPU 时间
+        pub(crate) cpu_time: Duration,
+        /// 内存峰值
+        pub(crate) max_memory: u64,
+        /// 读取资源统计时返回错误
+        pub(crate) fail_reads: bool,
+        /// 在这个时间之后 cgroup 会短暂地（一次检查）为空
+        pub(crate) transient_empty_at: Option<Duration>,
+        /// 在这个时间之后达到内存限制并被暂停
+        pub(crate) oom_after: Option<Duration>,
+        /// 结束后内存峰值的计数器被清零
+        pub(crate) peak_reset_on_exit: bool,
+        /// memsw 的计数器不可用
+        pub(crate) memsw_unavailable: bool,
+        /// 内存峰值从 0 开始，在 `exit_after` 时线性增长到 `max_memory`
+        pub(crate) memory_growth: bool,
+        /// 线程数
+        pub(crate) threads: u32,
+        /// 进程数的峰值
+        pub(crate) pids: u64,
+        /// 因达到 Pid 限制而失败的 fork 次数
+        pub(crate) pids_denied: u64,
+        /// 没有 cpuacct controller
+        pub(crate) no_cpuacct: bool,
+        /// 运行中用到了 swap
+        pub(crate) swap_used: bool,
+        /// 无法被杀死的进程，`kill_all_tasks()` 时返回 `KillTimeout`
+        pub(crate) survivors: Vec<TaskState>,
+        /// 启动失败时子进程报告的 errno，此时程序不会运行
+        pub(crate) spawn_error: Option<i32>,
+        /// 子进程报告的指令数和是否超过上限
+        pub(crate) instructions: Option<(u64, bool)>,
+        /// 子进程等待程序结束失败时报告的 errno
+        pub(crate) wait_error: Option<i32>,
+        /// 回收子进程时得到的（用户态，内核态）CPU 时间，None 表示无法得到
+        pub(crate) rusage: Option<(Duration, Duration)>,
+    }
+
+    impl Default for MockProgram {
+        fn default() -> MockProgram {
+            MockProgram {
+                exit_after: Some(Duration::from_millis(0)),
+                termination: Termination::Exited(0),
+                handles: None,
+                cpu_time: Duration::from_millis(10),
+                max_memory: 1024,
+                fail_reads: false,
+                transient_empty_at: None,
+                oom_after: None,
+                peak_reset_on_exit: false,
+                memory_growth: false,
+                memsw_unavailable: false,
+                threads: 1,
+                pids: 1,
+                pids_denied: 0,
+                no_cpuacct: false,
+                swap_used: false,
+                survivors: Vec::new(),
+                spawn_error: None,
+                instructions: None,
+                wait_error: None,
+                rusage: Some((Duration::from_millis(8), Duration::from_millis(2))),
+            }
+        }
+    }
+
+    /// 记录沙箱对程序做了什么
+    #[derive(Debug, Default)]
+    pub(crate) struct MockLog {
+        pub(crate) signals: Vec<Signal>,
+        pub(crate) child_killed: bool,
+        pub(crate) tasks_killed: bool,
+    }
+
+    /// 内存中的 `SandboxBackend`
+    #[derive(Debug)]
+    pub(crate) struct MockBackend {
+        program: MockProgram,
+        start: Instant,
+        log: Arc<Mutex<MockLog>>,
+        transient_empty_seen: Mutex<bool>,
+    }
+
+    impl MockBackend {
+        pub(crate) fn new(program: MockProgram) -> (MockBackend, Arc<Mutex<MockLog>>) {
+            let log = Arc::new(Mutex::new(MockLog::default()));
+            let backend = MockBackend {
+                program,
+                start: Instant::now(),
+                log: log.clone(),
+                transient_empty_seen: Mutex::new(false),
+            };
+            (backend, log)
+        }
+        /// 模拟资源统计的读取
+        fn read(&self) -> Result<(), SandboxError> {
+            if self.program.fail_reads {
+                return Err(SandboxError::Io(std::io::Error::from_raw_os_error(
+                    nix::libc::EIO,
+                )));
+            }
+            Ok(())
+        }
+        /// 程序的结束方式，仍在运行时返回 None
+        fn finished(&self) -> Option<Termination> {
+            let log = self.log.lock().unwrap();
+            if log.child_killed || log.tasks_killed {
+                return Some(Termination::Signaled(Signal::SIGKILL));
+            }
+            if let Some(signal) = self.program.handles {
+                if log.signals.contains(&signal) {
+                    return Some(Termination::Signaled(signal));
+                }
+            }
+            match self.program.exit_after {
+                Some(exit_after) if self.start.elapsed() >= exit_after => {
+                    Some(self.program.termination)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    impl SandboxBackend for MockBackend {
+        fn wait_ready(&self, _timeout: Duration) -> Result<Ready, SandboxError> {
+            match self.program.spawn_error {
+                Some(errno) => Ok(Ready::Failed(std::io::Error::from_raw_os_error(errno))),
+                None => Ok(Ready::Started),
+            }
+        }
+        fn is_empty(&self) -> Result<bool, SandboxError> {
+            if let Some(at) = self.program.transient_empty_at {
+                let mut seen = self.transient_empty_seen.lock().unwrap();
+                if !*seen && self.start.elapsed() >= at {
+                    *seen = true;
+                    return Ok(true);
+                }
+            }
+            Ok(self.finished().is_some())
+        }
+        fn cpu_time(&self) -> Result<Duration, SandboxError> {
+            if self.program.no_cpuacct {
+                return Err(SandboxError::MissingController(String::from("cpuacct")));
+            }
+            self.read()?;
+            Ok(std::cmp::min(self.start.elapsed(), self.program.cpu_time))
+        }
+        fn has_cpu_time(&self) -> bool {
+            !self.program.no_cpuacct
+        }
+        fn max_memory(&self) -> Result<(u64, MemoryCounter), SandboxError> {
+            self.read()?;
+            let counter = if self.program.memsw_unavailable {
+                MemoryCounter::Usage
+            } else {
+                MemoryCounter::Memsw
+            };
+            if self.program.peak_reset_on_exit && self.finished().is_some() {
+                return Ok((0, counter));
+            }
+            match self.program.exit_after {
+                Some(exit_after) if self.program.memory_growth => {
+                    let elapsed = std::cmp::min(self.start.elapsed(), exit_after);
+                    let peak = self.program.max_memory as f64 * elapsed.as_secs_f64()
+                        / exit_after.as_secs_f64();
+                    Ok((peak as u64, counter))
+                }
+                _ => Ok((self.program.max_memory, counter)),
+            }
+        }
+        fn max_rss(&self) -> Result<u64, SandboxError> {
+            Ok(self.max_memory()?.0 / 2)
+        }
+        fn swap_used(&self) -> Result<Option<bool>, SandboxError> {
+            self.read()?;
+            if self.program.memsw_unavailable {
+                return Ok(None);
+            }
+            Ok(Some(self.program.swap_used))
+        }
+        fn io_stats(&self, _device: (u64, u64)) -> Result<Option<IoStats>, SandboxError> {
+            self.read()?;
+            Ok(Some(IoStats::default()))
+        }
+        fn under_oom(&self) -> Result<bool, SandboxError> {
+            Ok(self.finished().is_none()
+                && self
+                    .program
+                    .oom_after
+                    .is_some_and(|oom_after| self.start.elapsed() >= oom_after))
+        }
+        fn max_threads(&self) -> Result<u32, SandboxError> {
+            Ok(self.program.threads)
+        }
+        fn max_pids(&self) -> Result<u64, SandboxError> {
+            self.read()?;
+            Ok(self.program.pids)
+        }
+        fn pids_denied(&self) -> Result<u64, SandboxError> {
+            self.read()?;
+            Ok(self.program.pids_denied)
+        }
+        fn report(&self) -> Result<CgroupReport, SandboxError> {
+            self.read()?;
+            Ok(CgroupReport {
+                pids_peak: Some(self.program.pids),
+                ..Default::default()
+            })
+        }
+        fn instructions(&self) -> Result<Option<(u64, bool)>, SandboxError> {
+            if let Some(errno) = self.program.wait_error {
+                return Err(SandboxError::Internal(InternalError::WaitFailed(
+                    std::io::Error::from_raw_os_error(errno),
+                )));
+            }
+            Ok(self.program.instructions)
+        }
+        fn signal_all_tasks(&self, signal: Signal) -> Result<(), SandboxError> {
+            self.log.lock().unwrap().signals.push(signal);
+            Ok(())
+        }
+        fn kill_all_tasks(&self, _timeout: Duration) -> Result<(), SandboxError> {
+            self.log.lock().unwrap().tasks_killed = true;
+            if !self.program.survivors.is_empty() {
+                return Err(SandboxError::KillTimeout(self.program.survivors.clone()));
+            }
+            Ok(())
+        }
+        fn wait_child(&self, grace: Duration) -> Result<Option<Termination>, SandboxError> {
+            let delay = Duration::from_millis(10);
+            let mut grace = grace;
+            loop {
+                if let Some(termination) = self.finished() {
+                    return Ok(Some(termination));
+                }
+                if grace < delay {
+                    return Ok(None);
+                }
+                std::thread::sleep(delay);
+                grace -= delay;
+            }
+        }
+        fn wait_exit(&self, timeout: Duration) -> Result<(), SandboxError> {
+            let timeout = match self.program.exit_after {
+                Some(exit_after) if self.finished().is_none() => {
+                    std::cmp::min(timeout, exit_after.saturating_sub(self.start.elapsed()))
+                }
+                _ => timeout,
+            };
+            std::thread::sleep(timeout);
+            Ok(())
+        }
+        fn kill_child(&self) -> Result<(), SandboxError> {
+            self.log.lock().unwrap().child_killed = true;
+            Ok(())
+        }
+        fn rusage(&self) -> Option<(Duration, Duration)> {
+            self.program.rusage
+        }
+    }
+}
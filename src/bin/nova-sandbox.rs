@@ -67,6 +67,29 @@ fn main() {
                 .help("WARN/INFO/DEBUG/TRACE")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("compile")
+                .long("compile")
+                .value_name("COMMAND")
+                .help("Compile command, run before the command with its own limits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compile-time")
+                .long("compile-time")
+                .value_name("INT")
+                .help("Time limit of the compile command in ms")
+                .default_value("10000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compile-memory")
+                .long("compile-memory")
+                .value_name("INT")
+                .help("Memory limit of the compile command in KiB")
+                .default_value("524288")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("command")
                 .help("The command which you want to run.")
@@ -76,13 +99,14 @@ fn main() {
         .get_matches();
 
     let mut builder = pretty_env_logger::formatted_builder();
-    builder.parse_filters(matches.value_of("debug").unwrap_or_else(|| "INFO"));
+    builder.parse_filters(matches.value_of("debug").unwrap_or("INFO"));
     builder.try_init().unwrap();
 
+    let pids = matches.value_of("pids").unwrap().parse::<u16>().unwrap();
     let config = nova_sandbox::SandboxConfig::new(
         matches.value_of("time").unwrap().parse::<u64>().unwrap(),
         matches.value_of("memory").unwrap().parse::<u64>().unwrap() * 1024,
-        matches.value_of("pids").unwrap().parse::<u16>().unwrap(),
+        pids,
         matches.value_of("command").unwrap(),
         Stdio::inherit(),
         Stdio::inherit(),
@@ -97,6 +121,51 @@ fn main() {
     )
     .unwrap();
 
+    if let Some(compile) = matches.value_of("compile") {
+        let compile_config = nova_sandbox::SandboxConfig::new(
+            matches
+                .value_of("compile-time")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap(),
+            matches
+                .value_of("compile-memory")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+                * 1024,
+            pids,
+            compile,
+            Stdio::inherit(),
+            Stdio::inherit(),
+            Stdio::inherit(),
+        );
+        log::trace!("{:?}", compile_config);
+        let status = sandbox.run(compile_config);
+        log::debug!("Compile status: {:?}", status);
+        // 编译的资源占用与运行分开报告，编译超时或超内存只作为编译失败的原因
+        if let Ok(status) = &status {
+            log::info!(
+                "Compile: {:?}, used {:?} ms, wall {} ms, memory {:?} KiB",
+                status.status,
+                status.used_time,
+                status.wall_time,
+                status.max_memory.map(|memory| memory / 1024)
+            );
+        }
+        let failure = match &status {
+            Ok(status) if status.status == nova_sandbox::SandboxStatusKind::Success => None,
+            Ok(status) => Some(format!("{:?}", status.status)),
+            Err(err) => Some(err.to_string()),
+        };
+        if let Some(reason) = failure {
+            log::error!("Compile failed ({}), skip the command", reason);
+            // 先卸载沙箱再退出
+            drop(sandbox);
+            std::process::exit(1);
+        }
+    }
+
     let status = sandbox.run(config);
-    log::info!("{:?}", status);
+    log::info!("Run status: {:?}", status);
 }
@@ -0,0 +1,518 @@
+use crate::{
+    ConcurrencyLimit, ControllerSpec, MemoryLimitMode, Sandbox, SandboxError, StaleMount, Unmount,
+};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::{Mode, SFlag};
+use std::ffi::OsStr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// 沙箱的挂载选项，调用 `mount()` 后得到 `Sandbox`
+///
+/// 所有选项的默认值与 `Sandbox::new()` 相同：只有 rootfs 一层 lower 目录，
+/// 不挂载 tmpfs、`/dev` 和额外的目录，不使用额外的 cgroup controller
+#[derive(Debug, Clone)]
+pub struct SandboxBuilder {
+    pub(crate) rootfs_directory: PathBuf,
+    pub(crate) work_directory: PathBuf,
+    pub(crate) sandbox_directory: PathBuf,
+    pub(crate) lower_directories: Vec<PathBuf>,
+    pub(crate) tmpfs_size: Option<usize>,
+    pub(crate) extra_mounts: ExtraMounts,
+    pub(crate) extra_controllers: Vec<ControllerSpec>,
+    /// 每次运行的 cgroup 所在的父 cgroup，见 `SandboxBuilder::cgroup_parent()`
+    pub(crate) cgroup_parent: Option<PathBuf>,
+    /// 加在每个命令之前的 argv
+    pub(crate) command_prefix: Vec<String>,
+    pub(crate) memory_limit_mode: MemoryLimitMode,
+    pub(crate) stale_mount: StaleMount,
+    /// 挂载前 rootfs 应有的摘要，见 `SandboxBuilder::rootfs_digest()`
+    pub(crate) rootfs_digest: Option<String>,
+    /// 挂载前需要获取名额的并发上限
+    pub(crate) concurrency_limit: Option<ConcurrencyLimit>,
+    /// 调用者打开的目录，见 `SandboxBuilder::from_fds()`
+    pub(crate) directory_fds: Option<Arc<DirectoryFds>>,
+    /// 自动创建 work 目录和挂载点时所在的目录，见 `SandboxBuilder::ephemeral()`
+    ephemeral_base: Option<PathBuf>,
+}
+
+/// `SandboxBuilder::from_fds()` 传入的目录，沙箱卸载前一直保持打开
+#[derive(Debug)]
+pub(crate) struct DirectoryFds {
+    sandbox: OwnedFd,
+    /// rootfs 和 work 目录，只需要保持打开
+    _paths: [OwnedFd; 2],
+}
+
+/// overlay 挂载之后再挂载到沙箱里的目录
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExtraMounts {
+    /// bind mount 列表
+    binds: Vec<BindMount>,
+    /// 是否挂载 `/dev`
+    dev: bool,
+}
+
+#[derive(Debug, Clone)]
+struct BindMount {
+    source: PathBuf,
+    /// 沙箱内的路径（相对于挂载点）
+    target: PathBuf,
+    readonly: bool,
+}
+
+/// 挂载到沙箱 `/dev` 中的主机设备
+const DEVICES: [&str; 5] = ["null", "zero", "full", "random", "urandom"];
+
+impl SandboxBuilder {
+    /// 参数含义见 [Sandbox](struct.Sandbox.html)
+    pub fn new<T, U, V>(
+        rootfs_directory: T,
+        work_directory: U,
+        sandbox_directory: V,
+    ) -> SandboxBuilder
+    where
+        T: AsRef<Path>,
+        U: AsRef<Path>,
+        V: AsRef<Path>,
+    {
+        SandboxBuilder {
+            rootfs_directory: rootfs_directory.as_ref().to_path_buf(),
+            work_directory: work_directory.as_ref().to_path_buf(),
+            sandbox_directory: sandbox_directory.as_ref().to_path_buf(),
+            lower_directories: Vec::new(),
+            tmpfs_size: None,
+            extra_mounts: Default::default(),
+            extra_controllers: Vec::new(),
+            cgroup_parent: None,
+            command_prefix: Vec::new(),
+            memory_limit_mode: MemoryLimitMode::default(),
+            stale_mount: StaleMount::default(),
+            rootfs_digest: None,
+            concurrency_limit: None,
+            directory_fds: None,
+            ephemeral_base: None,
+        }
+    }
+    /// 用已经打开的目录 fd 指定 rootfs、work 目录和挂载点，避免路径在检查和使用之间被替换
+    ///
+    /// rootfs 和 work 目录通过 `/proc/self/fd` 访问，不再按路径查找。
+    /// 挂载点需要按路径 chroot 和卸载，挂载前会解析一次路径，并检查它与 fd 是同一个目录，
+    /// 否则返回 `SandboxError::DirectoryChanged`
+    pub fn from_fds(rootfs: OwnedFd, work: OwnedFd, sandbox: OwnedFd) -> SandboxBuilder {
+        let mut builder = SandboxBuilder::new(fd_path(&rootfs), fd_path(&work), fd_path(&sandbox));
+        builder.directory_fds = Some(Arc::new(DirectoryFds {
+            sandbox,
+            _paths: [rootfs, work],
+        }));
+        builder
+    }
+    /// 用解压好的 OCI 镜像的各层作为只读的 rootfs，按 overlay `lowerdir` 的顺序给出（最上层在前）
+    ///
+    /// 注意与镜像 manifest 中的顺序（最底层在前）相反。最后一层相当于 `new()` 的 rootfs，
+    /// 其余各层从后往前依次通过 `lower()` 叠在上面，写入仍落在 work 目录中。
+    /// 各层需要是 overlay 格式：删除的文件为 0/0 字符设备、不透明目录带有
+    /// `trusted.overlay.opaque` 属性（如 containerd、Docker 的 overlay 快照），
+    /// 直接解压 tar 得到的 `.wh.` 文件不会被转换。只读取各层目录，
+    /// 不读取镜像配置中的环境变量和工作目录。`layers` 为空时返回 `SandboxError::InvalidConfig`
+    pub fn from_oci_layers<I, P, U, V>(
+        layers: I,
+        work_directory: U,
+        sandbox_directory: V,
+    ) -> Result<SandboxBuilder, SandboxError>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+        U: AsRef<Path>,
+        V: AsRef<Path>,
+    {
+        let mut layers: Vec<PathBuf> = layers
+            .into_iter()
+            .map(|layer| layer.as_ref().to_path_buf())
+            .collect();
+        let base = layers
+            .pop()
+            .ok_or_else(|| SandboxError::InvalidConfig(String::from("no layers")))?;
+        Ok(layers.into_iter().rev().fold(
+            SandboxBuilder::new(base, work_directory, sandbox_directory),
+            SandboxBuilder::lower,
+        ))
+    }
+    /// 不指定 work 目录和挂载点，挂载时在 `base_directory()`（默认为系统临时目录）下
+    /// 新建一个唯一的目录存放它们，沙箱 drop 时删除
+    pub fn ephemeral<T: AsRef<Path>>(rootfs_directory: T) -> SandboxBuilder {
+        let mut builder = SandboxBuilder::new(rootfs_directory, "", "");
+        builder.ephemeral_base = Some(std::env::temp_dir());
+        builder
+    }
+    /// 设置 `ephemeral()` 沙箱的目录所在的位置
+    pub fn base_directory<T: AsRef<Path>>(mut self, directory: T) -> SandboxBuilder {
+        self.ephemeral_base = Some(directory.as_ref().to_path_buf());
+        self
+    }
+    /// 在 rootfs 之上增加一层只读的 lower 目录，后加的层在上面
+    pub fn lower<T: AsRef<Path>>(mut self, directory: T) -> SandboxBuilder {
+        self.lower_directories
+            .push(directory.as_ref().to_path_buf());
+        self
+    }
+    /// 在 work 目录上挂载一个大小为 `size` bytes 的 tmpfs，见 `Sandbox::new_with_tmpfs()`
+    pub fn tmpfs(mut self, size: usize) -> SandboxBuilder {
+        self.tmpfs_size = Some(size);
+        self
+    }
+    /// 将主机上的 `source` bind mount 到沙箱内的 `target`
+    ///
+    /// `target` 是沙箱内的路径，不存在时会在沙箱中创建。`readonly` 为 false 时程序可以直接修改
+    /// 主机上的 `source`，见 `output_directory()`
+    pub fn bind<T, U>(mut self, source: T, target: U, readonly: bool) -> SandboxBuilder
+    where
+        T: AsRef<Path>,
+        U: AsRef<Path>,
+    {
+        let target = target.as_ref();
+        self.extra_mounts.binds.push(BindMount {
+            source: source.as_ref().to_path_buf(),
+            target: target.strip_prefix("/").unwrap_or(target).to_path_buf(),
+            readonly,
+        });
+        self
+    }
+    /// 将主机上的 `source` 可读写地 bind mount 到沙箱内的 `target`，用于让程序输出文件给主机
+    ///
+    /// 写入直接落在 `source` 上而不是 overlay 的 upper 目录中，`reset()` 和快照都不会清除。
+    /// 不受信任的程序可以任意修改这个目录，`source` 应当是每次运行专用的空目录。
+    /// `remove()` 时与其他挂载一起卸载
+    pub fn output_directory<T, U>(self, source: T, target: U) -> SandboxBuilder
+    where
+        T: AsRef<Path>,
+        U: AsRef<Path>,
+    {
+        self.bind(source, target, false)
+    }
+    /// 在沙箱的 `/dev` 上挂载 tmpfs，并 bind mount 主机的 null、zero、full、random
+    /// 和 urandom 设备
+    pub fn dev(mut self, dev: bool) -> SandboxBuilder {
+        self.extra_mounts.dev = dev;
+        self
+    }
+    /// 每次运行时额外加入 `spec` 描述的 cgroup controller
+    ///
+    /// 挂载时会检查 controller 是否存在，不存在时返回 `SandboxError::MissingController`
+    pub fn controller(mut self, spec: ControllerSpec) -> SandboxBuilder {
+        self.extra_controllers.push(spec);
+        self
+    }
+    /// 在 `parent` 之下创建每次运行的 cgroup，而不是直接在各 controller 的根之下，默认为根
+    ///
+    /// `parent` 是相对于 controller 挂载点的路径，如 `judge` 对应 `/sys/fs/cgroup/memory/judge`
+    /// 等目录，开头的 `/` 会被忽略。评测机本身运行在受限的 cgroup 中时，可以设为被委派的
+    /// cgroup，让所有程序的资源占用之和受其限制（v1 的 memory 需要开启 `memory.use_hierarchy`）。
+    /// 父 cgroup 需要提前创建好，挂载时检查每个要使用的 controller 下都有这个目录且可写，
+    /// 否则返回 `SandboxError::CgroupParent`
+    pub fn cgroup_parent<T: AsRef<Path>>(mut self, parent: T) -> SandboxBuilder {
+        let parent = parent.as_ref();
+        self.cgroup_parent = Some(parent.strip_prefix("/").unwrap_or(parent).to_path_buf());
+        self
+    }
+    /// 在每次运行的命令前加上 `prefix`（如 `["taskset", "-c", "0"]`），由 `prefix[0]` 启动真正的命令
+    ///
+    /// 组合方式见 `SandboxCommand::with_prefix()`：shell 模式的命令通过 `bash -c` 传给包装程序，
+    /// 不需要调用者再拼接和转义。组合后的命令同样受 `max_command_length` 等检查，
+    /// `validate_exec()` 检查的是 `prefix[0]`（按路径检查，建议使用绝对路径）。
+    /// 不对 `setup_command` 生效，默认不加
+    pub fn command_prefix<I, S>(mut self, prefix: I) -> SandboxBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.command_prefix = prefix.into_iter().map(Into::into).collect();
+        self
+    }
+    /// 每次运行设置内存限制的方式，默认为 `MemoryLimitMode::Memsw`
+    ///
+    /// 为 `MemoryLimitMode::StrictRss` 时挂载不再要求内核开启 swap 统计，
+    /// 语义上的区别见 [MemoryLimitMode](enum.MemoryLimitMode.html)
+    pub fn memory_limit_mode(mut self, mode: MemoryLimitMode) -> SandboxBuilder {
+        self.memory_limit_mode = mode;
+        self
+    }
+    /// 挂载点（使用 tmpfs 时还有 work 目录）上已有挂载时的处理方式，默认为
+    /// `StaleMount::Refuse`
+    pub fn stale_mount(mut self, mode: StaleMount) -> SandboxBuilder {
+        self.stale_mount = mode;
+        self
+    }
+    /// 挂载前检查 rootfs 目录的摘要是否为 `digest`（由 `rootfs_digest()` 计算），
+    /// 不同时返回 `SandboxError::RootfsIntegrity`
+    ///
+    /// 需要读取 rootfs 中的全部文件，默认不检查。只检查 rootfs，不检查 `lower()` 添加的目录
+    pub fn rootfs_digest<T: Into<String>>(mut self, digest: T) -> SandboxBuilder {
+        self.rootfs_digest = Some(digest.into());
+        self
+    }
+    /// 挂载前从 `limit` 获取一个名额，没有剩余名额时阻塞，沙箱完全卸载（或 `LeakedSandbox`
+    /// 清理）后归还
+    ///
+    /// 多个沙箱共用同一个 `limit` 才能限制它们的总数，默认不限制
+    pub fn concurrency_limit(mut self, limit: &ConcurrencyLimit) -> SandboxBuilder {
+        self.concurrency_limit = Some(limit.clone());
+        self
+    }
+    /// 检查环境并挂载沙箱
+    pub fn mount(mut self) -> Result<Sandbox, SandboxError> {
+        if let Some(fds) = &self.directory_fds {
+            self.sandbox_directory = fds.resolve_sandbox()?;
+        }
+        let base = match self.ephemeral_base.take() {
+            Some(base) => base,
+            None => return Sandbox::mount(self),
+        };
+        if !base.exists() {
+            log::error!("{:?} Not Found!", base);
+            return Err(SandboxError::DirectoryNotFound(base));
+        }
+        let directory = base.join(format!("nova-sandbox-{}", uuid::Uuid::new_v4()));
+        self.work_directory = directory.join("work");
+        self.sandbox_directory = directory.join("sandbox");
+        let created = std::fs::create_dir(&directory)
+            .and_then(|_| std::fs::create_dir(&self.work_directory))
+            .and_then(|_| std::fs::create_dir(&self.sandbox_directory));
+        let mounted = created
+            .map_err(SandboxError::from)
+            .and_then(|_| Sandbox::mount(self));
+        match mounted {
+            Ok(mut sandbox) => {
+                sandbox.owned_directory = Some(directory);
+                Ok(sandbox)
+            }
+            Err(err) => {
+                remove_owned_directory(&directory);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl DirectoryFds {
+    /// 解析挂载点 fd 对应的路径，并检查路径现在指向的仍是这个目录
+    fn resolve_sandbox(&self) -> Result<PathBuf, SandboxError> {
+        let path = std::fs::read_link(fd_path(&self.sandbox))?;
+        let opened = nix::sys::stat::fstat(self.sandbox.as_raw_fd())?;
+        // 目录被删除后路径会带上 " (deleted)" 后缀，找不到时同样视为被替换
+        let current = match nix::sys::stat::lstat(&path) {
+            Ok(current) => Some((current.st_dev, current.st_ino)),
+            Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => None,
+            Err(err) => return Err(err.into()),
+        };
+        if current != Some((opened.st_dev, opened.st_ino)) {
+            log::error!("{:?} was replaced after being opened", path);
+            return Err(SandboxError::DirectoryChanged(path));
+        }
+        Ok(path)
+    }
+}
+
+/// 通过 `/proc/self/fd` 访问 `fd` 所指目录的路径
+fn fd_path(fd: &OwnedFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+impl ExtraMounts {
+    /// 在挂载点 `sandbox_directory` 中依次挂载，返回挂载点列表（按挂载顺序）
+    ///
+    /// 失败时卸载已经挂载的目录
+    pub(crate) fn mount(&self, sandbox_directory: &Path) -> Result<Vec<PathBuf>, SandboxError> {
+        let mut mounted = Vec::new();
+        let result = self.mount_all(sandbox_directory, &mut mounted);
+        if let Err(err) = result {
+            umount_all(&mut mounted).ok();
+            return Err(err);
+        }
+        Ok(mounted)
+    }
+    fn mount_all(
+        &self,
+        sandbox_directory: &Path,
+        mounted: &mut Vec<PathBuf>,
+    ) -> Result<(), SandboxError> {
+        if self.dev {
+            let dev_directory = sandbox_directory.join("dev");
+            let fd = open_in_root(sandbox_directory, Path::new("dev"), true)?;
+            crate::with_mount_timeout(&dev_directory, move || {
+                libmount::Tmpfs::new(fd_path(&fd))
+                    .size_bytes(64 * 1024)
+                    .mode(0o755)
+                    .mount()
+                    .map_err(|err| err.to_string())
+            })?
+            .map_err(SandboxError::Mount)?;
+            mounted.push(dev_directory.clone());
+            for device in DEVICES.iter() {
+                let source = Path::new("/dev").join(device);
+                mounted.push(bind(&source, &dev_directory, Path::new(device), false)?);
+            }
+        }
+        for mount in &self.binds {
+            mounted.push(bind(
+                &mount.source,
+                sandbox_directory,
+                &mount.target,
+                mount.readonly,
+            )?);
+        }
+        Ok(())
+    }
+}
+
+/// bind mount `source` 到 `root` 中的 `target`，`target` 不存在时按 `source` 的类型创建，
+/// 返回挂载点
+///
+/// 只读挂载需要再重新挂载一次，失败时卸载已经完成的 bind mount
+fn bind(
+    source: &Path,
+    root: &Path,
+    target: &Path,
+    readonly: bool,
+) -> Result<PathBuf, SandboxError> {
+    if !source.exists() {
+        log::error!("{:?} Not Found!", source);
+        return Err(SandboxError::DirectoryNotFound(source.to_path_buf()));
+    }
+    let fd = open_in_root(root, target, source.is_dir())?;
+    let target = root.join(target.strip_prefix("/").unwrap_or(target));
+    let source = source.to_path_buf();
+    crate::with_mount_timeout(&target, move || {
+        libmount::BindMount::new(&source, fd_path(&fd))
+            .mount()
+            .map_err(|err| err.to_string())
+    })?
+    .map_err(SandboxError::Mount)?;
+    if readonly {
+        let target_path = target.clone();
+        let remounted = crate::with_mount_timeout(&target, move || {
+            libmount::Remount::new(&target_path)
+                .bind(true)
+                .readonly(true)
+                .remount()
+                .map_err(|err| err.to_string())
+        })
+        .and_then(|result| result.map_err(SandboxError::Mount));
+        if let Err(err) = remounted {
+            crate::umount_or_log(&target);
+            return Err(err);
+        }
+    }
+    Ok(target)
+}
+
+/// 从 `root` 开始逐级打开 `target`（`root` 中的路径），不存在的部分会被创建，
+/// 最后一级按 `directory` 创建为目录或空文件
+///
+/// 每一级都不跟随符号链接：重置后 upper 目录中是程序写入过的内容，`out -> /etc`
+/// 这样的链接会让挂载落在主机的目录上。遇到符号链接或 `..` 时返回
+/// `SandboxError::UnsafeMountTarget`
+fn open_in_root(root: &Path, target: &Path, directory: bool) -> Result<OwnedFd, SandboxError> {
+    let unsafe_target = || SandboxError::UnsafeMountTarget(root.join(target));
+    let mut current = owned(nix::fcntl::open(
+        root,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?);
+    let components = target
+        .components()
+        .filter(|component| !matches!(component, Component::RootDir | Component::CurDir))
+        .collect::<Vec<_>>();
+    for (i, component) in components.iter().enumerate() {
+        let name = match component {
+            Component::Normal(name) => name,
+            _ => return Err(unsafe_target()),
+        };
+        let directory = directory || i + 1 < components.len();
+        current = match open_component(&current, name, directory) {
+            Err(nix::Error::Sys(Errno::ELOOP)) | Err(nix::Error::Sys(Errno::ENOTDIR)) => {
+                log::error!(
+                    "{:?} goes through a symlink in the sandbox",
+                    root.join(target)
+                );
+                return Err(unsafe_target());
+            }
+            result => result?,
+        };
+    }
+    Ok(current)
+}
+
+/// 以 `O_PATH` 打开 `parent` 中的 `name`，不存在时创建；是符号链接时返回 `ELOOP`，
+/// 要求是目录但不是时返回 `ENOTDIR`
+fn open_component(parent: &OwnedFd, name: &OsStr, directory: bool) -> nix::Result<OwnedFd> {
+    let open = || {
+        nix::fcntl::openat(
+            parent.as_raw_fd(),
+            name,
+            OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+    };
+    let fd = match open() {
+        Err(nix::Error::Sys(Errno::ENOENT)) => {
+            let created = if directory {
+                nix::sys::stat::mkdirat(parent.as_raw_fd(), name, Mode::from_bits_truncate(0o755))
+            } else {
+                nix::fcntl::openat(
+                    parent.as_raw_fd(),
+                    name,
+                    OFlag::O_CREAT
+                        | OFlag::O_EXCL
+                        | OFlag::O_WRONLY
+                        | OFlag::O_NOFOLLOW
+                        | OFlag::O_CLOEXEC,
+                    Mode::from_bits_truncate(0o644),
+                )
+                .and_then(nix::unistd::close)
+            };
+            match created {
+                Ok(()) | Err(nix::Error::Sys(Errno::EEXIST)) => open()?,
+                Err(err) => return Err(err),
+            }
+        }
+        result => result?,
+    };
+    let fd = owned(fd);
+    let kind =
+        SFlag::from_bits_truncate(nix::sys::stat::fstat(fd.as_raw_fd())?.st_mode) & SFlag::S_IFMT;
+    if kind == SFlag::S_IFLNK {
+        return Err(nix::Error::Sys(Errno::ELOOP));
+    }
+    if directory && kind != SFlag::S_IFDIR {
+        return Err(nix::Error::Sys(Errno::ENOTDIR));
+    }
+    Ok(fd)
+}
+
+fn owned(fd: std::os::unix::io::RawFd) -> OwnedFd {
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
+
+/// 按挂载的逆序卸载 `mounted` 中的挂载点，卸载成功的会从列表中移除
+pub(crate) fn umount_all(mounted: &mut Vec<PathBuf>) -> Result<Unmount, SandboxError> {
+    let mut result = Unmount::Clean;
+    while let Some(target) = mounted.last() {
+        result = result.max(crate::umount(target)?);
+        mounted.pop();
+    }
+    Ok(result)
+}
+
+/// 删除 `ephemeral()` 沙箱自动创建的目录，调用前必须已经卸载
+pub(crate) fn remove_owned_directory(directory: &Path) {
+    std::fs::remove_dir_all(directory).unwrap_or_else(|err| {
+        log::error!(
+            "Failed to remove sandbox directory {:?}: {}",
+            directory,
+            err
+        )
+    });
+}
@@ -0,0 +1,999 @@
+use crate::{
+    CgroupReport, IoLimit, IoStats, MemoryCounter, MemoryLimitMode, SandboxError, TaskState,
+};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// cgroup 文件系统的挂载点
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// 沙箱运行必需的 cgroup controller，缺少任意一个都无法运行
+const REQUIRED_CONTROLLERS: [&str; 3] = ["memory", "pids", "freezer"];
+
+/// 可选的 cgroup controller，缺少时只关闭依赖它的功能
+///
+/// 缺少 cpuacct 时无法统计 CPU 时间，改为按实际运行时间判断超时
+const OPTIONAL_CONTROLLERS: [&str; 5] = ["cpuacct", "blkio", "cpu", "cpuset", "hugetlb"];
+
+/// 解析每行为 `name value` 的统计文件，忽略无法解析的行
+fn parse_stat(content: &str) -> std::collections::BTreeMap<String, u64> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next().map(str::parse::<u64>)) {
+                (Some(name), Some(Ok(value))) => Some((name.to_string(), value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// 返回某个 controller 是否已经挂载
+pub(crate) fn controller_mounted(name: &str) -> bool {
+    std::path::Path::new(CGROUP_ROOT)
+        .join(name)
+        .join("tasks")
+        .exists()
+}
+
+/// 检查每个要使用的 controller 下都有可写的 `parent` 目录，见 `SandboxBuilder::cgroup_parent()`
+pub(crate) fn check_parent(
+    parent: &std::path::Path,
+    controllers: &CgroupControllers,
+    extra: &[ControllerSpec],
+) -> Result<(), SandboxError> {
+    use nix::unistd::{access, AccessFlags};
+    let mut names = REQUIRED_CONTROLLERS.to_vec();
+    names.extend(
+        ["cpuacct", "blkio"]
+            .iter()
+            .filter(|name| controllers.has(name)),
+    );
+    names.extend(extra.iter().map(|spec| spec.name()));
+    for name in names {
+        let path = std::path::Path::new(CGROUP_ROOT).join(name).join(parent);
+        if !path.is_dir() || access(&path, AccessFlags::W_OK).is_err() {
+            log::error!("cgroup parent {:?} does not exist or is not writable", path);
+            return Err(SandboxError::CgroupParent(path));
+        }
+    }
+    Ok(())
+}
+
+/// 主机上可用的 cgroup controller
+#[derive(Debug, Clone)]
+pub struct CgroupControllers {
+    available: Vec<&'static str>,
+}
+
+impl CgroupControllers {
+    /// 探测主机上的 controller
+    ///
+    /// 缺少必需的 controller 时返回 `SandboxError::MissingController`
+    pub fn probe() -> Result<CgroupControllers, SandboxError> {
+        for name in REQUIRED_CONTROLLERS.iter() {
+            if !controller_mounted(name) {
+                log::error!("Required cgroup controller {} is not mounted", name);
+                return Err(SandboxError::MissingController(name.to_string()));
+            }
+        }
+
+        let mut available = REQUIRED_CONTROLLERS.to_vec();
+        for name in OPTIONAL_CONTROLLERS.iter() {
+            if controller_mounted(name) {
+                available.push(name);
+            } else {
+                log::warn!(
+                    "Optional cgroup controller {} is not mounted, related features are disabled",
+                    name
+                );
+            }
+        }
+
+        Ok(CgroupControllers { available })
+    }
+    /// 返回某个 controller 是否可用
+    pub fn has(&self, name: &str) -> bool {
+        self.available.contains(&name)
+    }
+}
+
+/// 沙箱运行时额外加入的一个 cgroup controller，以及创建后要写入的值
+///
+/// 名字为 memory、pids、freezer、cpuacct 之一时不会额外创建 cgroup，只在沙箱本来就使用的
+/// cgroup 中写入这些值
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerSpec {
+    name: String,
+    values: Vec<(String, String)>,
+}
+
+impl ControllerSpec {
+    /// 新建一个不写入任何值的 controller
+    pub fn new<T: Into<String>>(name: T) -> ControllerSpec {
+        ControllerSpec {
+            name: name.into(),
+            values: Vec::new(),
+        }
+    }
+    /// 创建 cgroup 后将 `value` 写入文件 `file`（例如 `cpuset.cpus`），按添加的顺序写入
+    pub fn value<T, U>(mut self, file: T, value: U) -> ControllerSpec
+    where
+        T: Into<String>,
+        U: std::fmt::Display,
+    {
+        self.values.push((file.into(), value.to_string()));
+        self
+    }
+    /// controller 的名字
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// 调用者预先创建并自行管理的 cgroup，见 `SandboxConfig::cgroup`
+///
+/// 名字是相对于各 controller 挂载点的路径，如 `judge/run-1` 对应
+/// `/sys/fs/cgroup/memory/judge/run-1` 等目录。memory、pids、freezer（以及主机上有的 cpuacct）
+/// 下都要有这个 cgroup，使用 `SandboxBuilder::controller()` 或 `io_limit` 时还要有对应的
+/// controller 下的。沙箱只使用它，不会创建或删除它
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalCgroup {
+    name: std::path::PathBuf,
+}
+
+impl ExternalCgroup {
+    /// 开头的 `/` 会被忽略
+    pub fn new<P: AsRef<std::path::Path>>(name: P) -> ExternalCgroup {
+        let name = name.as_ref();
+        ExternalCgroup {
+            name: name.strip_prefix("/").unwrap_or(name).to_path_buf(),
+        }
+    }
+    /// cgroup 的名字
+    pub fn name(&self) -> &std::path::Path {
+        &self.name
+    }
+}
+
+/// 一个 controller 下的 cgroup
+#[derive(Debug)]
+pub(crate) enum CgroupHandle {
+    /// 由沙箱创建，drop 时删除
+    Managed(cgroups_fs::AutomanagedCgroup),
+    /// 由调用者管理，沙箱不删除，见 `ExternalCgroup`
+    External(cgroups_fs::Cgroup),
+}
+
+impl std::ops::Deref for CgroupHandle {
+    type Target = cgroups_fs::Cgroup;
+
+    fn deref(&self) -> &cgroups_fs::Cgroup {
+        match self {
+            CgroupHandle::Managed(cgroup) => cgroup,
+            CgroupHandle::External(cgroup) => cgroup,
+        }
+    }
+}
+
+impl AsRef<cgroups_fs::Cgroup> for CgroupHandle {
+    fn as_ref(&self) -> &cgroups_fs::Cgroup {
+        self
+    }
+}
+
+/// `SandboxCgroup::configure()` 写入的设置（`ControllerSpec` 中的值除外），
+/// 之后新增的 cgroup 设置也应加在这里
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct CgroupSettings {
+    /// 内存限制（以 bytes 为单位）
+    pub(crate) memory_limit: u64,
+    /// Pid 限制
+    pub(crate) pids_limit: u16,
+    pub(crate) memory_limit_mode: MemoryLimitMode,
+    /// 见 `SandboxConfig::legacy_memory_multiplier`
+    pub(crate) legacy_memory_multiplier: bool,
+    /// 是否开启内核的 OOM killer，见 `OomBehavior`
+    pub(crate) oom_killer: bool,
+    /// 块设备 `major:minor` 及其读写速度限制
+    pub(crate) io_limit: Option<((u64, u64), IoLimit)>,
+}
+
+/// 用于限制 Sandbox 的资源使用的 cgroup
+#[derive(Debug)]
+pub(crate) struct SandboxCgroup {
+    name: String,
+    pub(crate) freezer: CgroupHandle,
+    pub(crate) memory: CgroupHandle,
+    pub(crate) pids: CgroupHandle,
+    /// 主机上没有 cpuacct controller 时为 None
+    pub(crate) cpuacct: Option<CgroupHandle>,
+    /// 额外加入的 controller，见 `ControllerSpec`
+    extra: Vec<(String, CgroupHandle)>,
+    /// 在 `configure()` 中写入的 `ControllerSpec`
+    specs: Vec<ControllerSpec>,
+    /// 读取到的 `pids.current` 的最大值，v1 的 pids controller 没有峰值统计
+    pids_peak: AtomicU64,
+    /// `clear()` 时 `pids.events` 中 `max` 的值，这个计数器无法清零
+    pids_denied_base: AtomicU64,
+    /// `clear()` 时 `memory.oom_control` 中 `oom_kill` 的值，这个计数器无法清零
+    oom_kills_base: AtomicU64,
+    /// 已经记录过 memsw 峰值不可用的日志
+    memsw_unavailable: AtomicBool,
+    /// 按 `MemoryLimitMode::StrictRss` 设置了限制，内存峰值只读取不含 swap 的计数器
+    strict_rss: AtomicBool,
+}
+
+impl SandboxCgroup {
+    /// 在每个 controller 的 `parent` 之下（为 None 时在根之下）新建一个 Sandbox 组，
+    /// 并额外创建 `extra` 中的 controller
+    pub(crate) fn new(
+        cgroup_name: &str,
+        parent: Option<&std::path::Path>,
+        extra: &[ControllerSpec],
+    ) -> Result<SandboxCgroup, SandboxError> {
+        let name = match parent {
+            Some(parent) => parent.join(cgroup_name).to_string_lossy().into_owned(),
+            None => String::from(cgroup_name),
+        };
+        SandboxCgroup::build(name, extra, |name, controller| {
+            Ok(CgroupHandle::Managed(cgroups_fs::AutomanagedCgroup::init(
+                name, controller,
+            )?))
+        })
+    }
+    /// 使用调用者创建的 `external`，写入 `extra` 中的值，drop 时不删除
+    ///
+    /// 任意一个 controller 下没有这个 cgroup 时返回 `SandboxError::DirectoryNotFound`
+    pub(crate) fn open(
+        external: &ExternalCgroup,
+        extra: &[ControllerSpec],
+    ) -> Result<SandboxCgroup, SandboxError> {
+        let name = external.name.to_string_lossy().into_owned();
+        let cgroup = SandboxCgroup::build(name, extra, |name, controller| {
+            let path = std::path::Path::new(CGROUP_ROOT)
+                .join(controller)
+                .join(&external.name);
+            if !path.is_dir() {
+                log::error!("{:?} Not Found!", path);
+                return Err(SandboxError::DirectoryNotFound(path));
+            }
+            Ok(CgroupHandle::External(cgroups_fs::Cgroup::new(
+                name, controller,
+            )))
+        })?;
+        if !cgroup.is_empty()? {
+            log::error!("External cgroup {} is not empty", cgroup.name);
+            return Err(SandboxError::InvalidConfig(format!(
+                "cgroup {} is not empty",
+                cgroup.name
+            )));
+        }
+        Ok(cgroup)
+    }
+    /// 用 `handle` 得到每个 controller 下的 cgroup
+    fn build<F>(
+        name: String,
+        extra: &[ControllerSpec],
+        handle: F,
+    ) -> Result<SandboxCgroup, SandboxError>
+    where
+        F: Fn(&cgroups_fs::CgroupName, &str) -> Result<CgroupHandle, SandboxError>,
+    {
+        let cur_cgroup = cgroups_fs::CgroupName::new(&name);
+        let mut cgroup = SandboxCgroup {
+            name,
+            memory: handle(&cur_cgroup, "memory")?,
+            pids: handle(&cur_cgroup, "pids")?,
+            freezer: handle(&cur_cgroup, "freezer")?,
+            cpuacct: if controller_mounted("cpuacct") {
+                Some(handle(&cur_cgroup, "cpuacct")?)
+            } else {
+                None
+            },
+            extra: Vec::new(),
+            specs: extra.to_vec(),
+            pids_peak: AtomicU64::new(0),
+            pids_denied_base: AtomicU64::new(0),
+            oom_kills_base: AtomicU64::new(0),
+            memsw_unavailable: AtomicBool::new(false),
+            strict_rss: AtomicBool::new(false),
+        };
+        for spec in extra {
+            if cgroup.controller(&spec.name).is_none() {
+                let controller = handle(&cur_cgroup, &spec.name)?;
+                cgroup.extra.push((spec.name.clone(), controller));
+            }
+        }
+
+        Ok(cgroup)
+    }
+    /// 写入 `ControllerSpec` 中的值并按 `settings` 设置所有限制，任何一项失败时返回
+    /// 指明 controller 和文件的 `SandboxError::CgroupConfig`
+    ///
+    /// 所有的 cgroup 设置都在这里写入
+    pub(crate) fn configure(&self, settings: &CgroupSettings) -> Result<(), SandboxError> {
+        for spec in &self.specs {
+            for (file, value) in &spec.values {
+                self.configure_value(&spec.name, file, value)?;
+            }
+        }
+        let memory_limit = if settings.legacy_memory_multiplier {
+            settings.memory_limit * 4
+        } else {
+            settings.memory_limit
+        };
+        self.set_memory_limit(memory_limit, settings.memory_limit_mode)?;
+        self.set_pids_limit(settings.pids_limit)?;
+        if let Some((device, io_limit)) = &settings.io_limit {
+            self.set_io_limit(*device, io_limit)?;
+        }
+        self.set_oom_killer(settings.oom_killer)?;
+
+        Ok(())
+    }
+    /// 将 `value` 写入 `controller` 下的文件 `key`，失败时返回 `SandboxError::CgroupConfig`
+    fn configure_value<V: AsRef<[u8]>>(
+        &self,
+        controller: &str,
+        key: &str,
+        value: V,
+    ) -> Result<(), SandboxError> {
+        let cgroup = self
+            .controller(controller)
+            .ok_or_else(|| SandboxError::MissingController(controller.to_string()))?;
+        cgroup.set_raw_value(key, value).map_err(|source| {
+            log::error!(
+                "Failed to set {} of cgroup {} ({}): {}",
+                key,
+                self.name,
+                controller,
+                source
+            );
+            SandboxError::CgroupConfig {
+                controller: controller.to_string(),
+                key: key.to_string(),
+                source,
+            }
+        })
+    }
+    /// 返回名为 `name` 的 controller 下的 cgroup
+    fn controller(&self, name: &str) -> Option<&CgroupHandle> {
+        match name {
+            "memory" => Some(&self.memory),
+            "pids" => Some(&self.pids),
+            "freezer" => Some(&self.freezer),
+            "cpuacct" => self.cpuacct.as_ref(),
+            _ => self
+                .extra
+                .iter()
+                .find(|(extra, _)| extra == name)
+                .map(|(_, cgroup)| cgroup),
+        }
+    }
+    /// 返回所有需要加入的 cgroup
+    pub(crate) fn all(&self) -> Vec<&CgroupHandle> {
+        let mut cgroups = vec![&self.memory, &self.pids, &self.freezer];
+        cgroups.extend(&self.cpuacct);
+        cgroups.extend(self.extra.iter().map(|(_, cgroup)| cgroup));
+        cgroups
+    }
+    /// 返回每个 controller 下这个 cgroup 的目录
+    pub(crate) fn paths(&self) -> Vec<std::path::PathBuf> {
+        let mut controllers = REQUIRED_CONTROLLERS.to_vec();
+        if self.cpuacct.is_some() {
+            controllers.push("cpuacct");
+        }
+        controllers.extend(self.extra.iter().map(|(name, _)| name.as_str()));
+        controllers
+            .iter()
+            .map(|controller| {
+                std::path::Path::new(CGROUP_ROOT)
+                    .join(controller)
+                    .join(&self.name)
+            })
+            .collect()
+    }
+    /// 返回 cgroup 内是否还有进程
+    pub fn is_empty(&self) -> Result<bool, SandboxError> {
+        log::trace!("Current task list: {:?}", self.freezer.get_tasks()?);
+        Ok(self.freezer.get_tasks()?.is_empty())
+    }
+    /// 获取 cgroup 内线程最多的进程的线程数
+    ///
+    /// 读取 `cgroup.procs` 和每个进程的 `/proc/<pid>/task`，已经退出的进程会被忽略
+    pub fn get_max_threads(&self) -> Result<u32, SandboxError> {
+        let mut max_threads = 0;
+        for pid in self
+            .freezer
+            .get_raw_value("cgroup.procs")?
+            .split_whitespace()
+        {
+            let threads = match std::fs::read_dir(format!("/proc/{}/task", pid)) {
+                Ok(tasks) => tasks.count() as u32,
+                Err(_) => continue,
+            };
+            max_threads = std::cmp::max(max_threads, threads);
+        }
+
+        Ok(max_threads)
+    }
+    /// 读取当前的进程（线程）数，返回至今读取到的最大值
+    ///
+    /// 峰值只在每次读取时更新，两次读取之间短暂出现的进程不会被统计
+    pub fn get_max_pids(&self) -> Result<u64, SandboxError> {
+        let current = self.pids.get_value::<u64>("pids.current")?;
+        Ok(std::cmp::max(
+            self.pids_peak.fetch_max(current, Ordering::AcqRel),
+            current,
+        ))
+    }
+    /// 获取因达到 `pids.max` 而失败的 fork 次数（`pids.events` 中的 `max`）
+    pub fn get_pids_denied(&self) -> Result<u64, SandboxError> {
+        Ok(self
+            .read_pids_events()?
+            .saturating_sub(self.pids_denied_base.load(Ordering::Acquire)))
+    }
+    fn read_pids_events(&self) -> Result<u64, SandboxError> {
+        Ok(self
+            .pids
+            .get_raw_value("pids.events")?
+            .lines()
+            .find_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some("max"), Some(value)) => value.parse::<u64>().ok(),
+                    _ => None,
+                }
+            })
+            .unwrap_or(0))
+    }
+    /// 获取运行所消耗的 CPU 时间
+    ///
+    /// 没有 cpuacct controller 时返回 `SandboxError::MissingController`
+    pub fn get_cpu_time(&self) -> Result<std::time::Duration, SandboxError> {
+        let cpuacct = self
+            .cpuacct
+            .as_ref()
+            .ok_or_else(|| SandboxError::MissingController(String::from("cpuacct")))?;
+        Ok(std::time::Duration::from_nanos(
+            cpuacct.get_value::<u64>("cpuacct.usage")?,
+        ))
+    }
+    /// 是否能统计 CPU 时间
+    pub(crate) fn has_cpu_time(&self) -> bool {
+        self.cpuacct.is_some()
+    }
+    /// 获取当前的内存占用，memsw 不可用时为不含 swap 的占用
+    pub fn get_current_memory(&self) -> Result<u64, SandboxError> {
+        if !self.has_memsw() {
+            return Ok(self.memory.get_value::<u64>("memory.usage_in_bytes")?);
+        }
+        Ok(self
+            .memory
+            .get_value::<u64>("memory.memsw.usage_in_bytes")?)
+    }
+    /// 内核是否开启了 swap 统计（有 memsw 的计数器）
+    fn has_memsw(&self) -> bool {
+        self.memory
+            .get_raw_value("memory.memsw.usage_in_bytes")
+            .is_ok()
+    }
+    /// 获取最大的内存占用，见 `get_peak_memory()`
+    pub fn get_max_memory(&self) -> Result<u64, SandboxError> {
+        Ok(self.get_peak_memory()?.0)
+    }
+    /// 获取最大的内存占用和所用的计数器
+    ///
+    /// 优先使用 memsw 的峰值，读取失败或 `MemoryLimitMode::StrictRss` 时使用不含 swap 的峰值
+    pub fn get_peak_memory(&self) -> Result<(u64, MemoryCounter), SandboxError> {
+        if self.strict_rss.load(Ordering::Acquire) {
+            let peak = self.memory.get_value::<u64>("memory.max_usage_in_bytes")?;
+            return Ok((peak, MemoryCounter::Usage));
+        }
+        match self
+            .memory
+            .get_value::<u64>("memory.memsw.max_usage_in_bytes")
+        {
+            Ok(peak) => Ok((peak, MemoryCounter::Memsw)),
+            Err(err) => {
+                if !self.memsw_unavailable.swap(true, Ordering::AcqRel) {
+                    log::warn!(
+                        "Failed to read memsw peak of cgroup {}, fall back to memory peak: {}",
+                        self.name,
+                        err
+                    );
+                }
+                let peak = self.memory.get_value::<u64>("memory.max_usage_in_bytes")?;
+                Ok((peak, MemoryCounter::Usage))
+            }
+        }
+    }
+    /// 返回运行中是否用到了 swap（memsw 的峰值大于内存的峰值），
+    /// memsw 不可用或 `MemoryLimitMode::StrictRss` 时为 None
+    pub fn get_swap_used(&self) -> Result<Option<bool>, SandboxError> {
+        if self.strict_rss.load(Ordering::Acquire) {
+            return Ok(None);
+        }
+        let memsw = match self
+            .memory
+            .get_value::<u64>("memory.memsw.max_usage_in_bytes")
+        {
+            Ok(memsw) => memsw,
+            Err(_) => return Ok(None),
+        };
+        let usage = self.memory.get_value::<u64>("memory.max_usage_in_bytes")?;
+        Ok(Some(memsw > usage))
+    }
+    /// 获取最大的内存占用减去页缓存，不包括 swap
+    pub fn get_max_rss(&self) -> Result<u64, SandboxError> {
+        let max_usage = self.memory.get_value::<u64>("memory.max_usage_in_bytes")?;
+        let cache = self
+            .memory
+            .get_raw_value("memory.stat")?
+            .lines()
+            .find_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some("cache"), Some(value)) => value.parse::<u64>().ok(),
+                    _ => None,
+                }
+            })
+            .unwrap_or(0);
+        Ok(max_usage.saturating_sub(cache))
+    }
+    /// 读取各个 controller 的原始统计，单项读取失败时留空
+    pub fn get_report(&self) -> Result<CgroupReport, SandboxError> {
+        let memory_stat = parse_stat(&self.memory.get_raw_value("memory.stat")?);
+        let cpu_times = self.cpuacct.as_ref().and_then(|cpuacct| {
+            let stat = parse_stat(&cpuacct.get_raw_value("cpuacct.stat").ok()?);
+            // cpuacct.stat 以 USER_HZ 为单位
+            let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+            let time = |name: &str| {
+                stat.get(name)
+                    .map(|value| std::time::Duration::from_nanos(value * 1_000_000_000 / ticks))
+            };
+            Some((time("user"), time("system")))
+        });
+        Ok(CgroupReport {
+            memory_stat,
+            memory_failcnt: self.memory.get_value::<u64>("memory.failcnt").ok(),
+            oom_kills: self.read_oom_kills().ok().flatten().map(|oom_kills| {
+                oom_kills.saturating_sub(self.oom_kills_base.load(Ordering::Acquire))
+            }),
+            cpu_user: cpu_times.and_then(|(user, _)| user),
+            cpu_system: cpu_times.and_then(|(_, system)| system),
+            pids_current: self.pids.get_value::<u64>("pids.current").ok(),
+            pids_peak: self.get_max_pids().ok(),
+            cpu_stat: self
+                .controller("cpu")
+                .and_then(|cpu| cpu.get_raw_value("cpu.stat").ok())
+                .map(|stat| parse_stat(&stat)),
+            // 由父进程回收子进程后填入
+            rusage_user: None,
+            rusage_system: None,
+        })
+    }
+    /// 读取 `memory.oom_control` 中的 `oom_kill`，内核不支持时为 None
+    fn read_oom_kills(&self) -> Result<Option<u64>, SandboxError> {
+        Ok(
+            parse_stat(&self.memory.get_raw_value("memory.oom_control")?)
+                .get("oom_kill")
+                .copied(),
+        )
+    }
+    /// 将所有统计还原，只能通过独占的 `RunCgroup::clear()` 调用
+    fn reset_counters(&self) -> Result<(), SandboxError> {
+        // 没有开启 swapaccount 的主机（`MemoryLimitMode::StrictRss`）上没有 memsw 的计数器
+        if self.has_memsw() {
+            self.memory
+                .set_value("memory.memsw.max_usage_in_bytes", 0)?;
+        }
+        self.memory.set_value("memory.max_usage_in_bytes", 0)?;
+        if let Some(cpuacct) = &self.cpuacct {
+            cpuacct.set_value("cpuacct.usage", 0)?;
+        }
+        self.pids_peak.store(0, Ordering::Release);
+        self.pids_denied_base
+            .store(self.read_pids_events()?, Ordering::Release);
+        self.memory.set_value("memory.failcnt", 0)?;
+        if let Some(oom_kills) = self.read_oom_kills()? {
+            self.oom_kills_base.store(oom_kills, Ordering::Release);
+        }
+
+        Ok(())
+    }
+    /// 将 cgroup 的内存限制设置为 `memory_limit`
+    ///
+    /// `MemoryLimitMode::StrictRss` 时先关闭 swap，memsw 不可用时只设置 memory 的限制。
+    /// 内核要求 memory 的限制不超过 memsw 的限制，重复使用的 cgroup 放宽限制时先写 memsw
+    fn set_memory_limit(
+        &self,
+        memory_limit: u64,
+        mode: MemoryLimitMode,
+    ) -> Result<(), SandboxError> {
+        let limit = memory_limit.to_string();
+        self.strict_rss
+            .store(mode == MemoryLimitMode::StrictRss, Ordering::Release);
+        if mode == MemoryLimitMode::StrictRss {
+            self.configure_value("memory", "memory.swappiness", "0")?;
+        }
+        if mode != MemoryLimitMode::Memsw && !self.has_memsw() {
+            return self.configure_value("memory", "memory.limit_in_bytes", &limit);
+        }
+        let raising = self
+            .memory
+            .get_raw_value("memory.limit_in_bytes")
+            .ok()
+            .and_then(|current| current.trim().parse::<u64>().ok())
+            .is_some_and(|current| memory_limit > current);
+        let mut keys = ["memory.limit_in_bytes", "memory.memsw.limit_in_bytes"];
+        if raising {
+            keys.reverse();
+        }
+        for key in keys.iter() {
+            self.configure_value("memory", key, &limit)?;
+        }
+
+        Ok(())
+    }
+    /// 开启或关闭内核的 OOM killer，关闭时进程会在达到内存限制时被暂停
+    fn set_oom_killer(&self, enabled: bool) -> Result<(), SandboxError> {
+        self.configure_value(
+            "memory",
+            "memory.oom_control",
+            if enabled { "0" } else { "1" },
+        )?;
+
+        Ok(())
+    }
+    /// 返回 cgroup 是否因达到内存限制而被暂停
+    pub fn under_oom(&self) -> Result<bool, SandboxError> {
+        Ok(self
+            .memory
+            .get_raw_value("memory.oom_control")?
+            .lines()
+            .any(|line| line.trim() == "under_oom 1"))
+    }
+    /// 设置 Pid 限制
+    fn set_pids_limit(&self, pids_limit: u16) -> Result<(), SandboxError> {
+        self.configure_value("pids", "pids.max", pids_limit.to_string())?;
+
+        Ok(())
+    }
+    /// 设置块设备 `major:minor` 的读写速度限制
+    fn set_io_limit(&self, device: (u64, u64), limit: &IoLimit) -> Result<(), SandboxError> {
+        if self.controller("blkio").is_none() {
+            return Ok(());
+        }
+        if let Some(read_bps) = limit.read_bps {
+            self.configure_value(
+                "blkio",
+                "blkio.throttle.read_bps_device",
+                format!("{}:{} {}", device.0, device.1, read_bps),
+            )?;
+        }
+        if let Some(write_bps) = limit.write_bps {
+            self.configure_value(
+                "blkio",
+                "blkio.throttle.write_bps_device",
+                format!("{}:{} {}", device.0, device.1, write_bps),
+            )?;
+        }
+
+        Ok(())
+    }
+    /// 获取块设备 `major:minor` 上的读写字节数
+    pub fn get_io_stats(&self, device: (u64, u64)) -> Result<Option<IoStats>, SandboxError> {
+        let blkio = match self.controller("blkio") {
+            Some(blkio) => blkio,
+            None => return Ok(None),
+        };
+        let device = format!("{}:{}", device.0, device.1);
+        let mut stats = IoStats::default();
+        // 每行格式为 `major:minor Read 1024`
+        for line in blkio
+            .get_raw_value("blkio.throttle.io_service_bytes")?
+            .lines()
+        {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 || fields[0] != device {
+                continue;
+            }
+            let bytes = fields[2].parse::<u64>().unwrap_or(0);
+            match fields[1] {
+                "Read" => stats.read_bytes += bytes,
+                "Write" => stats.write_bytes += bytes,
+                _ => {}
+            }
+        }
+
+        Ok(Some(stats))
+    }
+    /// 向 cgroup 内所有进程发送 `signal`
+    pub fn signal_all_tasks(&self, signal: nix::sys::signal::Signal) -> Result<(), SandboxError> {
+        log::info!("Send {} to all in cgroup {:?}", signal, &self.freezer);
+        self.freezer.send_signal_to_all_tasks(signal)?;
+
+        Ok(())
+    }
+    /// 通过 freezer 暂停 cgroup 内所有进程，`timeout` 内没有全部暂停时解冻并返回
+    /// `SandboxError::FreezeTimeout`
+    pub fn freeze(&self, timeout: std::time::Duration) -> Result<(), SandboxError> {
+        let freezer = &self.freezer;
+        let delay = std::time::Duration::from_millis(10);
+        let mut timeout = timeout;
+        log::info!("Freeze cgroup {:?}", &freezer);
+        freezer.set_value::<&str>("freezer.state", "FROZEN")?;
+        while freezer.get_value::<String>("freezer.state")? != "FROZEN" {
+            if timeout < delay {
+                log::warn!("Timeout while freezing cgroup {:?}", &freezer);
+                let tasks = log_task_states(&freezer.get_tasks()?);
+                self.thaw()?;
+                return Err(SandboxError::FreezeTimeout(tasks));
+            }
+            std::thread::sleep(delay);
+            timeout -= delay;
+        }
+
+        Ok(())
+    }
+    /// 恢复被 `freeze()` 暂停的进程
+    pub fn thaw(&self) -> Result<(), SandboxError> {
+        log::info!("Thaw cgroup {:?}", &self.freezer);
+        self.freezer.set_value::<&str>("freezer.state", "THAWED")?;
+
+        Ok(())
+    }
+    /// 杀死 cgroup 内所有进程
+    ///
+    /// `freeze` 为 true 时先通过 freezer cgroup 冻结，然后发送 kill 指令；
+    /// 否则直接发送，并在每次检查时重新发送，尽量杀死发送期间新 fork 出的进程。
+    /// 两种方式最后都会解冻，被 `freeze()` 暂停的进程也能被杀死
+    pub fn kill_all_tasks(
+        &self,
+        timeout: std::time::Duration,
+        freeze: bool,
+    ) -> Result<(), SandboxError> {
+        let freezer = &self.freezer;
+        let delay = std::time::Duration::from_millis(100);
+        let mut timeout = timeout;
+
+        log::info!("Try kill all in cgroup {:?}", &freezer);
+        log::trace!("Current task list {:?}", freezer.get_tasks()?);
+
+        if self.is_empty()? {
+            return Ok(());
+        };
+
+        let mut frozen = false;
+        if freeze {
+            freezer.set_value::<&str>("freezer.state", "FROZEN")?;
+            while timeout > std::time::Duration::from_millis(0) {
+                if freezer.get_value::<String>("freezer.state")? == "FROZEN" {
+                    frozen = true;
+                    break;
+                }
+                std::thread::sleep(delay);
+                timeout -= delay;
+            }
+            if !frozen {
+                log::warn!("Timeout while freezing cgroup {:?}", &freezer);
+                log_task_states(&freezer.get_tasks()?);
+            }
+        }
+
+        freezer.send_signal_to_all_tasks(nix::sys::signal::Signal::SIGKILL)?;
+
+        // 冻结的进程在解冻前不会处理 SIGKILL
+        if freeze || freezer.get_value::<String>("freezer.state")? != "THAWED" {
+            freezer.set_value::<&str>("freezer.state", "THAWED")?;
+        }
+        while timeout > std::time::Duration::from_millis(0) {
+            log::trace!("{:?}: checking...", timeout);
+            if self.is_empty()? {
+                return Ok(());
+            }
+            if !freeze {
+                freezer.send_signal_to_all_tasks(nix::sys::signal::Signal::SIGKILL)?;
+            }
+            std::thread::sleep(delay);
+            timeout -= delay;
+        }
+
+        let tasks = freezer.get_tasks()?;
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        log::error!("Task(s) survived SIGKILL in cgroup {:?}", &freezer);
+        let tasks = log_task_states(&tasks);
+        if frozen || !freeze {
+            Err(SandboxError::KillTimeout(tasks))
+        } else {
+            Err(SandboxError::FreezeTimeout(tasks))
+        }
+    }
+}
+
+/// 限制固定的 cgroup，在多次运行之间复用
+///
+/// 同一时间最多只有一次运行持有它的 `FixedLease`，重置统计和读取统计都只能通过
+/// 持有的 `RunCgroup` 进行，因此一次运行的重置不会影响另一次运行的统计
+#[derive(Debug)]
+pub(crate) struct FixedCgroup {
+    /// 创建时写入的设置，设置相同的运行才能复用
+    pub(crate) settings: CgroupSettings,
+    cgroup: Arc<SandboxCgroup>,
+    /// 同一时间只能有一次运行使用，其余运行会新建 cgroup
+    in_use: Arc<AtomicBool>,
+}
+
+impl FixedCgroup {
+    pub(crate) fn new(settings: CgroupSettings, cgroup: SandboxCgroup) -> FixedCgroup {
+        FixedCgroup {
+            settings,
+            cgroup: Arc::new(cgroup),
+            in_use: Arc::new(AtomicBool::new(false)),
+        }
+    }
+    /// 尝试独占这个 cgroup，已被占用时返回 None
+    pub(crate) fn lease(&self) -> Option<FixedLease> {
+        self.in_use
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()?;
+        Some(FixedLease {
+            cgroup: self.cgroup.clone(),
+            in_use: self.in_use.clone(),
+        })
+    }
+}
+
+/// 对 `FixedCgroup` 的独占使用权，drop 时释放
+#[derive(Debug)]
+pub(crate) struct FixedLease {
+    cgroup: Arc<SandboxCgroup>,
+    in_use: Arc<AtomicBool>,
+}
+
+impl Drop for FixedLease {
+    fn drop(&mut self) {
+        self.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// 一次运行所使用的 cgroup
+#[derive(Debug)]
+pub(crate) enum RunCgroup {
+    /// 沙箱预先创建的 cgroup
+    Fixed(FixedLease),
+    /// 这次运行新建的 cgroup，运行结束后删除
+    Owned(SandboxCgroup),
+    /// 调用者提供的 cgroup，运行结束后不删除，见 `ExternalCgroup`
+    External(SandboxCgroup),
+}
+
+impl RunCgroup {
+    /// 将所有统计还原，调用者独占这个 cgroup
+    pub(crate) fn clear(&self) -> Result<(), SandboxError> {
+        self.reset_counters()
+    }
+}
+
+impl std::ops::Deref for RunCgroup {
+    type Target = SandboxCgroup;
+
+    fn deref(&self) -> &SandboxCgroup {
+        match self {
+            RunCgroup::Fixed(lease) => &lease.cgroup,
+            RunCgroup::Owned(cgroup) | RunCgroup::External(cgroup) => cgroup,
+        }
+    }
+}
+
+/// 读取并输出进程的状态，用于排查无法结束的进程
+fn log_task_states(tasks: &[nix::unistd::Pid]) -> Vec<TaskState> {
+    tasks
+        .iter()
+        .map(|pid| {
+            let task = TaskState::read(pid.as_raw());
+            log::warn!("Task {}", task);
+            task
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 用普通目录模拟一个没有开启 swapaccount 的主机上的 cgroup（没有 memsw 的文件）
+    fn without_memsw(dir: &std::path::Path) -> SandboxCgroup {
+        std::fs::create_dir_all(dir).unwrap();
+        for (file, value) in &[
+            ("memory.usage_in_bytes", "1024\n"),
+            ("memory.max_usage_in_bytes", "4096\n"),
+            ("memory.failcnt", "3\n"),
+            (
+                "memory.oom_control",
+                "oom_kill_disable 0\nunder_oom 0\noom_kill 2\n",
+            ),
+            ("pids.events", "max 1\n"),
+        ] {
+            std::fs::write(dir.join(file), value).unwrap();
+        }
+        // 名字为绝对路径时 cgroups-fs 直接使用它，不拼接挂载点
+        let handle = || {
+            CgroupHandle::External(cgroups_fs::Cgroup::new(
+                &cgroups_fs::CgroupName::new(dir),
+                "memory",
+            ))
+        };
+        SandboxCgroup {
+            name: String::from("test"),
+            freezer: handle(),
+            memory: handle(),
+            pids: handle(),
+            cpuacct: None,
+            extra: Vec::new(),
+            specs: Vec::new(),
+            pids_peak: AtomicU64::new(0),
+            pids_denied_base: AtomicU64::new(0),
+            oom_kills_base: AtomicU64::new(0),
+            memsw_unavailable: AtomicBool::new(false),
+            strict_rss: AtomicBool::new(false),
+        }
+    }
+
+    fn settings(memory_limit_mode: MemoryLimitMode) -> CgroupSettings {
+        CgroupSettings {
+            memory_limit: 1024 * 1024,
+            pids_limit: 5,
+            memory_limit_mode,
+            legacy_memory_multiplier: false,
+            oom_killer: true,
+            io_limit: None,
+        }
+    }
+
+    #[test]
+    fn strict_rss_without_memsw() {
+        let dir = std::env::temp_dir().join(format!("nova-sandbox-cgroup-{}", std::process::id()));
+        let cgroup = without_memsw(&dir);
+        cgroup
+            .configure(&settings(MemoryLimitMode::StrictRss))
+            .unwrap();
+        cgroup.reset_counters().unwrap();
+        // cgroupfs 中不能新建文件，写入 memsw 的文件在真实的主机上会失败
+        assert!(!dir.join("memory.memsw.limit_in_bytes").exists());
+        assert!(!dir.join("memory.memsw.max_usage_in_bytes").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("memory.swappiness")).unwrap(),
+            "0"
+        );
+        assert_eq!(cgroup.get_peak_memory().unwrap(), (0, MemoryCounter::Usage));
+        assert_eq!(cgroup.get_swap_used().unwrap(), None);
+        assert_eq!(cgroup.get_current_memory().unwrap(), 1024);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn configure_error() {
+        let dir =
+            std::env::temp_dir().join(format!("nova-sandbox-cgroup-error-{}", std::process::id()));
+        let cgroup = without_memsw(&dir);
+        // 写入目录会失败，模拟内核拒绝 OOM killer 的设置
+        std::fs::remove_file(dir.join("memory.oom_control")).unwrap();
+        std::fs::create_dir(dir.join("memory.oom_control")).unwrap();
+        match cgroup.configure(&settings(MemoryLimitMode::StrictRss)) {
+            Err(SandboxError::CgroupConfig {
+                controller, key, ..
+            }) => {
+                assert_eq!(controller, "memory");
+                assert_eq!(key, "memory.oom_control");
+            }
+            other => panic!("Wrong result: {:?}", other),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
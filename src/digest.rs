@@ -0,0 +1,203 @@
+use crate::SandboxError;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// 计算 rootfs 目录的摘要（SHA-256，小写十六进制）
+///
+/// 按文件名排序遍历目录，依次计算每一项的相对路径、类型、权限、所有者以及文件内容、
+/// 符号链接目标或设备号，不跟随符号链接。结果只用于和之前用同一函数得到的值比较，
+/// 与 `sha256sum` 等工具的输出无关。需要读取全部文件，对较大的 rootfs 开销较大
+pub fn rootfs_digest<P: AsRef<Path>>(directory: P) -> Result<String, SandboxError> {
+    let directory = directory.as_ref();
+    if !directory.is_dir() {
+        log::error!("{:?} Not Found!", directory);
+        return Err(SandboxError::DirectoryNotFound(directory.to_path_buf()));
+    }
+    let mut hasher = Sha256::new();
+    digest_directory(directory, Path::new(""), &mut hasher)?;
+    Ok(to_hex(&hasher.finish()))
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn digest_directory(root: &Path, relative: &Path, hasher: &mut Sha256) -> Result<(), SandboxError> {
+    let mut entries = std::fs::read_dir(root.join(relative))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for name in entries {
+        let relative = relative.join(name);
+        let path = root.join(&relative);
+        let metadata = std::fs::symlink_metadata(&path)?;
+        // 长度前缀保证不同的目录树不会得到相同的输入
+        let relative_bytes = relative.as_os_str().as_bytes();
+        hasher.update(&(relative_bytes.len() as u64).to_be_bytes());
+        hasher.update(relative_bytes);
+        hasher.update(&metadata.mode().to_be_bytes());
+        hasher.update(&metadata.uid().to_be_bytes());
+        hasher.update(&metadata.gid().to_be_bytes());
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            digest_directory(root, &relative, hasher)?;
+        } else if file_type.is_file() {
+            hasher.update(&metadata.len().to_be_bytes());
+            let mut file = std::fs::File::open(&path)?;
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let size = file.read(&mut buffer)?;
+                if size == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..size]);
+            }
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            let target = target.as_os_str().as_bytes();
+            hasher.update(&(target.len() as u64).to_be_bytes());
+            hasher.update(target);
+        } else {
+            hasher.update(&metadata.rdev().to_be_bytes());
+        }
+    }
+    // 目录结束标记，区分同名文件属于哪一层目录
+    hasher.update(&u64::MAX.to_be_bytes());
+    Ok(())
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256（FIPS 180-4）
+struct Sha256 {
+    state: [u32; 8],
+    /// 未满一个分组的输入
+    buffer: Vec<u8>,
+    /// 输入的总字节数
+    length: u64,
+}
+
+impl Sha256 {
+    fn new() -> Sha256 {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            buffer: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.buffer.is_empty() {
+            let size = std::cmp::min(64 - self.buffer.len(), data.len());
+            self.buffer.extend_from_slice(&data[..size]);
+            data = &data[size..];
+            if self.buffer.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.buffer);
+            self.compress(&block);
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.buffer.extend_from_slice(blocks.remainder());
+    }
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.length.wrapping_mul(8);
+        let padding = (119 - (self.length % 64) as usize) % 64 + 1;
+        let mut tail = vec![0u8; padding];
+        tail[0] = 0x80;
+        self.update(&tail);
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0u8; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, bytes) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        to_hex(&hasher.finish())
+    }
+
+    #[test]
+    fn sha256() {
+        assert_eq!(
+            hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // 跨越多个分组，且分多次输入
+        let mut hasher = Sha256::new();
+        for _ in 0..1000 {
+            hasher.update(&[b'a'; 1000]);
+        }
+        assert_eq!(
+            to_hex(&hasher.finish()),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+}
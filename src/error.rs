@@ -0,0 +1,230 @@
+use std::fmt;
+
+/// Sandbox 可能返回的错误
+#[derive(Debug)]
+pub enum SandboxError {
+    /// 缺少沙箱必需的 cgroup controller
+    MissingController(String),
+    /// 内核未开启 swap 统计
+    SwapAccountDisabled,
+    /// 目录不存在
+    DirectoryNotFound(std::path::PathBuf),
+    /// rootfs、work 目录和挂载点之间有重合或嵌套，包含重合的两个目录
+    OverlappingPaths(std::path::PathBuf, std::path::PathBuf),
+    /// work 目录不可写（如在只读的文件系统上），包含其路径
+    ReadOnlyDirectory(std::path::PathBuf),
+    /// `SandboxBuilder::from_fds()` 传入的目录在打开后被替换，包含解析到的路径
+    DirectoryChanged(std::path::PathBuf),
+    /// 挂载点正被当前进程中的另一个沙箱使用，包含挂载点
+    MountpointInUse(std::path::PathBuf),
+    /// 挂载点上已经有挂载，见 `StaleMount`
+    AlreadyMounted(std::path::PathBuf),
+    /// 额外挂载的目标在沙箱中的路径经过符号链接或 `..`，包含主机上的路径
+    UnsafeMountTarget(std::path::PathBuf),
+    /// 挂载或卸载失败
+    Mount(String),
+    /// 挂载或卸载超时，包含挂载点
+    MountTimeout(std::path::PathBuf),
+    /// rootfs 的摘要与 `SandboxBuilder::rootfs_digest()` 指定的不同，包含（指定的，实际的）摘要
+    RootfsIntegrity(String, String),
+    /// 沙箱的 rootfs 无法执行命令
+    InvalidRootfs(String),
+    /// 运行配置不合法，包含原因
+    InvalidConfig(String),
+    /// 命令无法在沙箱中启动，原因未知（子进程没有报告错误就退出了）
+    CommandFailed,
+    /// exec 之前的设置（chroot、切换用户、`child_setup` 等）或 exec 本身失败，
+    /// 包含子进程通过启动通知管道报告的错误，说明问题出在沙箱或命令，而不是程序的运行
+    SpawnFailed(std::io::Error),
+    /// 准备命令没有成功运行，包含准备命令的运行状态
+    SetupFailed(Box<crate::SandboxStatus>),
+    /// 沙箱累计的 CPU 时间已达到上限，包含累计的 CPU 时间
+    CpuBudgetExhausted(std::time::Duration),
+    /// 冻结 cgroup 超时，且之后仍有进程存活，包含存活进程及其状态
+    FreezeTimeout(Vec<TaskState>),
+    /// 发送 SIGKILL 后仍有进程存活，包含存活进程及其状态
+    KillTimeout(Vec<TaskState>),
+    /// `SandboxBuilder::cgroup_parent()` 指定的父 cgroup 不存在或不可写，包含其路径
+    CgroupParent(std::path::PathBuf),
+    /// 设置 cgroup 的某一项失败，包含 controller、文件名和原因
+    CgroupConfig {
+        controller: String,
+        key: String,
+        source: std::io::Error,
+    },
+    /// IO 错误（包括 cgroup 文件读写）
+    Io(std::io::Error),
+    /// 系统调用错误
+    Nix(nix::Error),
+    /// 沙箱内部错误，说明是沙箱或主机的问题，而不是程序的问题
+    Internal(InternalError),
+}
+
+/// 无法结束的进程及其状态，见 `SandboxError::KillTimeout`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskState {
+    /// 进程的 PID（主机 PID namespace 中）
+    pub pid: i32,
+    /// `/proc/<pid>/stat` 中的状态，如 `D`（不可中断的睡眠），进程已退出或无法读取时为 None
+    pub state: Option<char>,
+}
+
+impl TaskState {
+    /// 读取进程 `pid` 当前的状态
+    pub fn read(pid: i32) -> TaskState {
+        // 进程名可能包含空格和括号，状态在最后一个 `)` 之后
+        let state = std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .ok()
+            .and_then(|stat| {
+                let (_, rest) = stat.rsplit_once(')')?;
+                rest.trim_start().chars().next()
+            });
+        TaskState { pid, state }
+    }
+    /// 进程处于不可中断的睡眠（D），通常说明卡在内核或存储的操作中，是主机的问题
+    pub fn is_uninterruptible(&self) -> bool {
+        self.state == Some('D')
+    }
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.state {
+            Some(state) => write!(f, "{} ({})", self.pid, state),
+            None => write!(f, "{} (unknown)", self.pid),
+        }
+    }
+}
+
+impl SandboxError {
+    /// 错误是否由处于不可中断睡眠的进程引起，此时应认为主机不健康，而不是重试
+    pub fn has_uninterruptible_tasks(&self) -> bool {
+        match self {
+            SandboxError::FreezeTimeout(tasks) | SandboxError::KillTimeout(tasks) => {
+                tasks.iter().any(TaskState::is_uninterruptible)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// 沙箱内部错误
+#[derive(Debug)]
+pub enum InternalError {
+    /// `run()` 超过了 `max_run_duration`，与程序是否超时无关
+    Deadline,
+    /// 负责监视运行的线程 panic 了
+    SupervisorPanicked,
+    /// 沙箱 fork 出的子进程等待程序结束失败，程序的结束方式未知，包含子进程报告的错误
+    WaitFailed(std::io::Error),
+}
+
+impl fmt::Display for InternalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InternalError::Deadline => write!(f, "run exceeded max_run_duration"),
+            InternalError::SupervisorPanicked => write!(f, "supervisor thread panicked"),
+            InternalError::WaitFailed(err) => write!(f, "failed to wait for command: {}", err),
+        }
+    }
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SandboxError::MissingController(name) => {
+                write!(f, "cgroup controller \"{}\" is not mounted", name)
+            }
+            SandboxError::SwapAccountDisabled => write!(
+                f,
+                "Need \"cgroup_enable=memory swapaccount=1\" kernel parameter"
+            ),
+            SandboxError::DirectoryNotFound(path) => write!(f, "{:?} Not Found!", path),
+            SandboxError::OverlappingPaths(a, b) => {
+                write!(f, "{:?} overlaps with {:?}", a, b)
+            }
+            SandboxError::ReadOnlyDirectory(path) => write!(f, "{:?} is not writable", path),
+            SandboxError::DirectoryChanged(path) => {
+                write!(f, "{:?} was replaced after being opened", path)
+            }
+            SandboxError::MountpointInUse(path) => {
+                write!(f, "{:?} is used by another sandbox", path)
+            }
+            SandboxError::AlreadyMounted(path) => write!(f, "{:?} is already mounted", path),
+            SandboxError::UnsafeMountTarget(path) => {
+                write!(f, "{:?} goes through a symlink in the sandbox", path)
+            }
+            SandboxError::Mount(err) => write!(f, "Mount error: {}", err),
+            SandboxError::MountTimeout(path) => write!(f, "Timeout while (u)mounting {:?}", path),
+            SandboxError::RootfsIntegrity(expected, actual) => write!(
+                f,
+                "Rootfs digest mismatch, expected {} but got {}",
+                expected, actual
+            ),
+            SandboxError::InvalidRootfs(reason) => write!(f, "Invalid rootfs: {}", reason),
+            SandboxError::InvalidConfig(reason) => write!(f, "Invalid config: {}", reason),
+            SandboxError::CommandFailed => write!(f, "Failed to run command"),
+            SandboxError::SpawnFailed(err) => write!(f, "Failed to start command: {}", err),
+            SandboxError::SetupFailed(status) => {
+                write!(f, "Setup command failed: {:?}", status.status)
+            }
+            SandboxError::CpuBudgetExhausted(used) => {
+                write!(f, "CPU budget exhausted, {:?} used", used)
+            }
+            SandboxError::FreezeTimeout(tasks) => write!(
+                f,
+                "Failed to freeze cgroup, task(s) still alive: {}",
+                join_tasks(tasks)
+            ),
+            SandboxError::KillTimeout(tasks) => write!(
+                f,
+                "Failed to kill all task(s), still alive: {}",
+                join_tasks(tasks)
+            ),
+            SandboxError::CgroupParent(path) => write!(
+                f,
+                "cgroup parent {:?} does not exist or is not writable",
+                path
+            ),
+            SandboxError::CgroupConfig {
+                controller,
+                key,
+                source,
+            } => write!(f, "Failed to set {} ({}): {}", key, controller, source),
+            SandboxError::Io(err) => write!(f, "IO error: {}", err),
+            SandboxError::Nix(err) => write!(f, "System call error: {}", err),
+            SandboxError::Internal(err) => write!(f, "Internal error: {}", err),
+        }
+    }
+}
+
+fn join_tasks(tasks: &[TaskState]) -> String {
+    tasks
+        .iter()
+        .map(TaskState::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl std::error::Error for SandboxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SandboxError::Io(err) | SandboxError::SpawnFailed(err) => Some(err),
+            SandboxError::CgroupConfig { source, .. } => Some(source),
+            SandboxError::Nix(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SandboxError {
+    fn from(err: std::io::Error) -> SandboxError {
+        SandboxError::Io(err)
+    }
+}
+
+impl From<nix::Error> for SandboxError {
+    fn from(err: nix::Error) -> SandboxError {
+        SandboxError::Nix(err)
+    }
+}
@@ -1,21 +1,431 @@
-use std::error::Error;
 use std::os::unix::process::CommandExt;
 use std::process::Stdio;
 
+mod backend;
+mod builder;
+mod cgroup;
+mod digest;
+mod error;
+mod limit;
+mod output;
+mod perf;
+mod rootfs;
+mod run;
+pub mod testing;
+
+pub use builder::SandboxBuilder;
+pub use cgroup::{CgroupControllers, ControllerSpec, ExternalCgroup};
+use cgroup::{CgroupSettings, FixedCgroup, RunCgroup, SandboxCgroup};
+pub use digest::rootfs_digest;
+pub use error::{InternalError, SandboxError, TaskState};
+pub use limit::{ConcurrencyLimit, ConcurrencyPermit};
+pub use rootfs::SharedRootfs;
+pub use run::{classify, ResourceEvent, ResourceSnapshot, RunHandle, RunMonitor};
+
 /// Sandbox 运行配置
 #[derive(Debug)]
 pub struct SandboxConfig {
-    /// 将要执行的命令 bash
-    pub command: String,
+    /// 将要执行的命令，见 [SandboxCommand](enum.SandboxCommand.html)
+    pub command: SandboxCommand,
     /// 时间限制（以 ms 为单位）
     pub time_limit: u64,
+    /// 实际运行时间的上限（以 ms 为单位），默认为 `time_limit` + 500ms
+    ///
+    /// 从程序进入 cgroup 开始计时，到达后无论 cgroup 中的进程处于什么状态都会杀死所有进程，
+    /// 判为 `TimeLimitExceeded`（CPU 时间没有超限时为 `TimeDimension::Wall`）。
+    /// 大于默认值时，CPU 时间超过 `time_limit` + 500ms 的程序仍会被提前杀死
+    pub wall_time_limit: Option<u64>,
     /// 内存限制（以 bytes 为单位）
     pub memory_limit: u64,
+    /// 按旧的方式将 cgroup 的内存限制设置为 `memory_limit` 的 4 倍，默认为 true
+    ///
+    /// 旧版本把 `memory_limit` 乘了两次 2，程序在被 OOM killer 杀死前最多可以使用 4 倍的内存，
+    /// 超过 `memory_limit` 的部分再判为内存超限。设置为 false 时 cgroup 的限制就是
+    /// `memory_limit`（目前没有单独的 swap 额度），达到限制即判为内存超限，
+    /// 被杀死时已用的内存不会超过限制。依赖旧行为设定限制的部署应当检查后再关闭。
+    /// 已弃用：开启时会记录一次警告，下一个版本默认值将改为 false，之后移除。
+    /// `Sandbox::with_fixed_limits()` 的 cgroup 按默认值设置，与之不同的运行不复用它
+    pub legacy_memory_multiplier: bool,
     /// Pid 限制
     pub pids_limit: u16,
     pub stdin: Stdio,
     pub stdout: Stdio,
     pub stderr: Stdio,
+    /// 程序 stdin 的来源，默认为 `StdinMode::Stdio`，即使用 `stdin`
+    pub stdin_mode: StdinMode,
+    /// 对 work 目录所在块设备的读写限速，需要 blkio controller，默认不限制
+    pub io_limit: Option<IoLimit>,
+    /// 运行命令的用户 ID，默认不切换
+    pub uid: Option<u32>,
+    /// 运行命令的用户组 ID，默认不切换
+    pub gid: Option<u32>,
+    /// 切换用户后的附加用户组，为空时清除所有附加用户组
+    pub supplementary_groups: Vec<u32>,
+    /// 命令的 nice 值，默认不修改
+    ///
+    /// 降低优先级只影响实际运行时间，不影响按 CPU 时间判断的超时；
+    /// 负数（提高优先级）需要在切换用户前以 root 权限设置
+    pub nice: Option<i32>,
+    /// `run()` 整体的最长运行时间，默认不限制
+    ///
+    /// 与时间限制无关，只用于防止沙箱本身卡住。超过后会杀死所有进程并返回
+    /// `SandboxError::Internal(InternalError::Deadline)`，即使程序同时也超时了，
+    /// 也不会返回 `TimeLimitExceeded`：这说明沙箱或主机有问题，应当重新评测
+    pub max_run_duration: Option<std::time::Duration>,
+    /// 判断内存超限时使用的统计方式，默认为 `MemoryAccounting::MemswPeak`
+    pub memory_accounting: MemoryAccounting,
+    /// 超时后首先发送给所有进程的信号，默认为 SIGKILL
+    ///
+    /// 可以设置为 SIGTERM 等可捕获的信号，让程序有机会输出部分结果；
+    /// 程序在短暂等待后仍未结束时会被 SIGKILL 杀死。程序不是 PID namespace 中的 1 号进程，
+    /// 没有处理的信号会按默认行为结束程序
+    pub timeout_signal: nix::sys::signal::Signal,
+    /// 内存达到限制时的处理方式，默认为 `OomBehavior::Kill`
+    pub oom_behavior: OomBehavior,
+    /// 程序同时内存超限和运行出错时的判断方式，默认为 `VerdictPolicy::MleOverRe`
+    pub verdict_policy: VerdictPolicy,
+    /// 在命令之前运行的准备命令，例如解压数据，默认没有
+    ///
+    /// 使用宽松的固定限制，资源占用不计入结果；没有成功运行时返回
+    /// `SandboxError::SetupFailed`，不会运行命令
+    pub setup_command: Option<String>,
+    /// 每个进程最多的线程数，默认不限制
+    ///
+    /// 与 `pids_limit` 不同，只限制单个进程的线程数，可以用来禁止多线程。
+    /// 沙箱每 100ms 读取一次每个进程的 `/proc/<pid>/task`，超过时杀死所有进程并判为
+    /// `ThreadLimitExceeded`；两次检查之间短暂存在的线程可能不会被发现，
+    /// 进程很多时每次检查的开销也会相应增加
+    pub thread_limit: Option<u32>,
+    /// 运行结束后 cgroup 中仍有杀不死的进程时，返回 `SandboxError::FreezeTimeout`
+    /// 或 `SandboxError::KillTimeout` 而不是运行状态，默认为 false（只记录日志）
+    pub fail_on_leftover_tasks: bool,
+    /// 杀死 cgroup 内所有进程前先通过 freezer 冻结，默认为 true
+    ///
+    /// 冻结可以防止 fork 炸弹在发送信号期间产生新进程，但会增加延迟，冻结本身也可能超时。
+    /// 设置为 false 时直接反复发送 SIGKILL，新 fork 出的进程可能与信号竞争而存活，
+    /// 只适合 `pids_limit` 很小或已知程序很简单的情况
+    pub use_freezer_on_kill: bool,
+    /// 在结果中附带原始的 cgroup 统计（`SandboxStatus::detailed_stats`），默认为 false
+    pub collect_detailed_stats: bool,
+    /// 判断 CPU 时间超限和报告 `used_time` 时使用的统计来源，默认为 `CpuTimeSource::Cpuacct`
+    ///
+    /// 两种来源都能得到时，运行结束后会比较二者，相差超过 20ms 且超过 10% 时记录警告，
+    /// 用于发现主机上 cpuacct 的异常；两者都会出现在 `detailed_stats` 中。
+    /// 使用 `Rusage` 时运行中不再根据 cpuacct 提前结束程序，只由 `wall_time_limit` 限制
+    pub cpu_time_source: CpuTimeSource,
+    /// 统计程序执行的用户态指令数（`SandboxStatus::instructions`），默认为 false
+    ///
+    /// 比 CPU 时间更稳定，适合需要可复现结果的评测。通过 `perf_event_open` 读取硬件计数器，
+    /// 需要开启 `perf` feature，且主机有可用的 PMU（大多数虚拟机和容器中没有）；
+    /// 不满足时只记录日志，`instructions` 为 None。计数包括程序 fork 出的所有进程，
+    /// 以及 exec 之前沙箱自身的少量设置代码；同一程序在不同型号的 CPU 上结果可能略有不同
+    pub count_instructions: bool,
+    /// 用户态指令数的上限，超过时杀死所有进程并判为 `InstructionLimitExceeded`，默认不限制
+    ///
+    /// 设置后同时开启 `count_instructions`，限制条件相同，无法计数时不会生效。
+    /// 运行中每 10ms 检查一次计数器，所以程序会多执行最多 10ms 的指令后才被杀死，
+    /// 检查本身不计入程序的指令数
+    pub instruction_limit: Option<u64>,
+    /// exec 前放弃所有 capability，默认为 true
+    ///
+    /// 挂载、chroot 和进入 cgroup 都在这之前完成，程序即使以 root 运行也无法 mknod、
+    /// mount 或修改其他用户的文件
+    pub drop_capabilities: bool,
+    /// 单个文件的最大大小（以 bytes 为单位），通过 `RLIMIT_FSIZE` 限制，默认不限制
+    ///
+    /// 写入超过限制时程序收到 SIGXFSZ（默认结束程序），判为 `RuntimeError`。
+    /// 只限制单个文件，不限制所有文件的总大小
+    pub file_size_limit: Option<u64>,
+    /// exec 前设置 `PR_SET_NO_NEW_PRIVS`，默认为 true
+    ///
+    /// 程序执行 rootfs 中的 setuid 程序时不会切换用户
+    pub no_new_privs: bool,
+    /// 在 exec 前的子进程中执行的自定义准备，默认没有，见 `ChildSetup`
+    pub child_setup: Option<ChildSetup>,
+    /// 程序运行时将 stdout 分块发送到这个 channel，设置后 `stdout` 不再使用，默认不发送
+    ///
+    /// 沙箱在后台线程中读取输出，接收方处理慢不会阻塞程序；接收方关闭后输出被丢弃。
+    /// `join()` 返回前所有输出都已发送完毕，之后 channel 被关闭
+    pub stdout_stream: Option<std::sync::mpsc::Sender<Vec<u8>>>,
+    /// 程序结束后等待 `stdout_stream` 的管道读到 EOF 的最长时间，默认为 200ms
+    ///
+    /// 沙箱内的进程都结束后管道中的数据已经完整，正常情况下马上读到 EOF。
+    /// 写入端被泄漏到沙箱外的进程时（例如同一进程中同时 fork 出的另一个沙箱），
+    /// 等待这段时间后读完已有的数据就返回，输出同样完整，只是 `join()` 返回得更晚
+    pub output_drain_grace: std::time::Duration,
+    /// 将 stdout 连接到伪终端而不是管道，只在设置了 `stdout_stream` 时生效，默认为 false
+    ///
+    /// libc 在 stdout 是终端时按行缓冲，程序被杀死（如超时）前已经输出的行不会丢失在
+    /// 程序自己的缓冲区中。注意程序中 `isatty(1)` 会返回 true，部分程序会因此改变行为
+    /// （如输出颜色、提示符或改变缓冲方式）。终端设置为 raw 模式，输出的内容不被转换；
+    /// stdin 和 stderr 不受影响
+    pub stdout_tty: bool,
+    /// 在伪终端中运行程序，默认不使用
+    ///
+    /// 设置后沙箱新建一个窗口大小为该值的伪终端，作为程序的 stdin、stdout、stderr 和控制终端，
+    /// `stdin`、`stdin_mode`、`stdout` 和 `stderr` 不再使用，不能与 `stdout_stream` 同时设置。
+    /// 终端的 master 端通过 `RunHandle::take_tty()` 交给调用者，用于交互式程序（REPL、readline 等）
+    /// 或复现只在终端中出现的行为。终端使用默认设置，输出中的 `\n` 会变为 `\r\n`，
+    /// 写入的内容会被回显。程序结束（包括被杀死）后读取 master 返回 EIO。
+    /// 只适合与 `spawn()` 一起使用：没有人读取时，程序的输出填满终端的缓冲区后会阻塞
+    pub tty: Option<TtySize>,
+    /// 在调用者预先创建的 cgroup 中运行，而不是为这次运行新建 cgroup，默认不使用
+    ///
+    /// 沙箱会在其中设置限制、清零统计、放入程序，运行结束后杀死其中的所有进程，
+    /// 但不会删除它，它的创建和删除都由调用者负责；运行期间调用者不应让其他进程加入。
+    /// 运行开始时其中已有进程时返回 `SandboxError::InvalidConfig`。
+    /// 设置后不使用 `Sandbox::with_fixed_limits()` 的 cgroup，也不使用 `SandboxBuilder::cgroup_parent()`
+    pub cgroup: Option<ExternalCgroup>,
+    /// stdout 的最大字节数，只在设置了 `stdout_stream` 时生效，默认不限制
+    ///
+    /// 超过后只发送限制以内的部分，然后关闭 channel，杀死所有进程并判为 `OutputLimitExceeded`
+    pub output_limit: Option<u64>,
+    /// 运行期间每次检查（约 100ms）时将资源占用的采样发送到这个 channel，默认不发送
+    ///
+    /// 运行结束时最后发送 `ResourceEvent::Finished`，之后 channel 被关闭；运行出错时不发送，
+    /// 错误只由 `join()` 返回
+    pub resource_stream: Option<std::sync::mpsc::Sender<ResourceEvent>>,
+    /// 命令的最大长度（以 bytes 为单位），默认为 128KiB，见 `SandboxConfig::validate()`
+    ///
+    /// argv 模式下为程序路径与所有参数的长度之和
+    pub max_command_length: usize,
+    /// argv 模式下参数的最大个数，默认为 4096
+    pub max_argv_count: usize,
+}
+
+/// 伪终端的窗口大小，见 `SandboxConfig::tty`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtySize {
+    pub rows: u16,
+    pub columns: u16,
+}
+
+impl Default for TtySize {
+    /// 24 行 80 列
+    fn default() -> TtySize {
+        TtySize {
+            rows: 24,
+            columns: 80,
+        }
+    }
+}
+
+/// 在 exec 前的子进程中执行的闭包，用于沙箱没有提供的设置（如调度策略、其他 rlimit）
+///
+/// 执行时已经加入 cgroup、chroot 进沙箱并设置了 `nice` 和 `file_size_limit`，
+/// 之后才会放弃 capability、切换用户和设置 `PR_SET_NO_NEW_PRIVS`，所以仍以 root 运行，
+/// 做出的设置在此之后会被锁定。只对命令本身执行，不对 `setup_command` 执行。
+///
+/// 闭包在 fork 出的子进程中运行，父进程的其他线程不存在，因此只应调用 async-signal-safe
+/// 的函数，不要分配内存、加锁或打印日志。返回错误时程序不会启动，`run()` 返回
+/// 包含该错误（只保留 errno）的 `SandboxError::SpawnFailed`
+pub struct ChildSetup(Box<dyn FnOnce() -> std::io::Result<()> + Send>);
+
+impl ChildSetup {
+    pub fn new<F>(setup: F) -> ChildSetup
+    where
+        F: FnOnce() -> std::io::Result<()> + Send + 'static,
+    {
+        ChildSetup(Box::new(setup))
+    }
+}
+
+impl std::fmt::Debug for ChildSetup {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ChildSetup")
+    }
+}
+
+/// 在沙箱中执行的命令
+#[derive(Debug, Clone, PartialEq)]
+pub enum SandboxCommand {
+    /// 用 `bash -c` 执行的命令
+    Shell(String),
+    /// 不经过 shell，直接执行沙箱中的 `program`，`argv` 为完整的参数列表（包括 argv\[0\]）
+    ///
+    /// argv\[0\] 可以与 `program` 不同，用于根据 argv\[0\] 选择行为的程序；
+    /// `argv` 为空时以 `program` 作为 argv\[0\]
+    Argv {
+        program: std::path::PathBuf,
+        argv: Vec<String>,
+    },
+}
+
+impl SandboxCommand {
+    /// 在命令前加上 `prefix`（完整的 argv，`prefix[0]` 为要执行的程序），得到 argv 模式的命令
+    ///
+    /// shell 模式的命令变为 `prefix... bash -c <命令>`；argv 模式的命令变为
+    /// `prefix... <program> <argv[1..]>`，原来的 argv\[0\] 被丢弃（无法经过包装程序传递），
+    /// 不是 UTF-8 的 `program` 会被有损转换。`prefix` 为空时返回原来的命令
+    pub fn with_prefix(self, prefix: &[String]) -> SandboxCommand {
+        let program = match prefix.first() {
+            Some(program) => std::path::PathBuf::from(program),
+            None => return self,
+        };
+        let mut argv = prefix.to_vec();
+        match self {
+            SandboxCommand::Shell(shell) => {
+                argv.extend([String::from("bash"), String::from("-c"), shell]);
+            }
+            SandboxCommand::Argv {
+                program: inner,
+                argv: inner_argv,
+            } => {
+                argv.push(inner.to_string_lossy().into_owned());
+                argv.extend(inner_argv.into_iter().skip(1));
+            }
+        }
+        SandboxCommand::Argv { program, argv }
+    }
+}
+
+impl From<String> for SandboxCommand {
+    fn from(command: String) -> SandboxCommand {
+        SandboxCommand::Shell(command)
+    }
+}
+
+impl From<&str> for SandboxCommand {
+    fn from(command: &str) -> SandboxCommand {
+        SandboxCommand::Shell(command.to_string())
+    }
+}
+
+/// 准备命令的时间限制（以 ms 为单位）
+const SETUP_TIME_LIMIT: u64 = 10 * 1000;
+/// 准备命令的内存限制（以 bytes 为单位）
+const SETUP_MEMORY_LIMIT: u64 = 512 * 1024 * 1024;
+/// 准备命令的 Pid 限制
+const SETUP_PIDS_LIMIT: u16 = 64;
+/// 命令的默认最大长度（以 bytes 为单位）
+const DEFAULT_MAX_COMMAND_LENGTH: usize = 128 * 1024;
+/// argv 模式下参数的默认最大个数
+const DEFAULT_MAX_ARGV_COUNT: usize = 4096;
+
+/// 程序 stdin 的来源
+///
+/// 从 stdin 读到 EOF 为止的程序需要 stdin 被关闭才能结束，stdin 一直不关闭时程序会阻塞在
+/// `read()` 上直到超时
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StdinMode {
+    /// 使用 `SandboxConfig::stdin`，EOF 由调用者传入的 `Stdio` 决定
+    #[default]
+    Stdio,
+    /// 不提供任何输入，程序第一次读取就会读到 EOF
+    Closed,
+    /// 由沙箱在后台写入这些数据后关闭，程序读完后读到 EOF
+    ///
+    /// 程序没有读完就结束时剩余的数据被丢弃
+    Bytes(Vec<u8>),
+    /// 保持打开，写入端通过 `RunHandle::take_stdin()` 交给调用者，调用者 drop 它后程序读到 EOF
+    ///
+    /// 只能与 `spawn()` 一起使用；`run()` 不返回 `RunHandle`，写入端会被立即关闭
+    Open,
+}
+
+/// 内存达到限制时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OomBehavior {
+    /// 由内核的 OOM killer 立即杀死进程
+    #[default]
+    Kill,
+    /// 关闭 OOM killer，进程在达到限制时被暂停，由沙箱检测到后杀死并判为内存超限
+    ///
+    /// 程序不会在写到一半时被内核杀死，且判断结果不依赖内存峰值的统计
+    Pause,
+}
+
+/// 程序同时内存超限和运行出错时判为哪一种
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerdictPolicy {
+    /// 判为内存超限
+    #[default]
+    MleOverRe,
+    /// 程序自行出错（返回值非 0 或被 SIGKILL 以外的信号终止）时判为运行时错误
+    ///
+    /// 被 OOM killer 杀死的程序收到的是 SIGKILL，仍判为内存超限
+    ReOverMle,
+}
+
+/// 内存占用的统计方式
+///
+/// 同时决定 `SandboxStatus::max_memory` 的含义和是否判为内存超限
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryAccounting {
+    /// 使用 memsw 的峰值（内存 + swap）
+    ///
+    /// 包括内核计入 cgroup 的页缓存和 slab，程序读写大量文件时可能被误判为内存超限，
+    /// 但不会漏掉换出到 swap 的内存
+    #[default]
+    MemswPeak,
+    /// 使用内存峰值减去 `memory.stat` 中的页缓存
+    ///
+    /// 更接近程序自身的内存占用。页缓存是结束时的值而不是峰值时的值，
+    /// 所以结果只是近似；且不包括 swap
+    Rss,
+}
+
+/// 判断 CPU 时间超限时使用的统计来源，见 `SandboxConfig::cpu_time_source`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CpuTimeSource {
+    /// cgroup 的 `cpuacct.usage`，包括 cgroup 内的所有进程
+    #[default]
+    Cpuacct,
+    /// 父进程回收 fork 出的子进程时由 `wait4` 得到的 `ru_utime + ru_stime`
+    ///
+    /// 只包括被回收的进程：程序以及它 `wait` 过的子进程，还有沙箱自身在 fork 出的子进程中
+    /// 的少量开销；程序留在后台、由 PID namespace 的 1 号进程回收的进程不计入。
+    /// 无法得到时（例如沙箱在回收前出错）使用 cpuacct 并记录日志
+    Rusage,
+}
+
+/// 内存限制的设置方式，见 `SandboxBuilder::memory_limit_mode()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryLimitMode {
+    /// 同时限制 memory 和 memsw（内存 + swap），需要内核开启 swap 统计
+    #[default]
+    Memsw,
+    /// 关闭 cgroup 的 swap（`memory.swappiness` 设为 0），只按不含 swap 的内存限制
+    ///
+    /// 用于无法开启 swapaccount 的主机：不检查 swap 统计，memsw 可用时仍同时设置 memsw 限制。
+    /// 限制的是常驻内存（含页缓存）而不是内存 + swap，内存超限按 `memory.max_usage_in_bytes`
+    /// 的峰值判断，`memory_counter` 为 `MemoryCounter::Usage`，`swap_used` 为 None。
+    /// 内核在 cgroup 之外的全局内存回收时仍可能换出少量内存，这部分不会计入
+    StrictRss,
+}
+
+/// `SandboxStatus::max_memory` 实际来自哪个计数器
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryCounter {
+    /// `memory.memsw.max_usage_in_bytes`（内存 + swap 的峰值）
+    Memsw,
+    /// `memory.max_usage_in_bytes`（不含 swap 的峰值），memsw 读取失败时使用
+    Usage,
+    /// `memory.max_usage_in_bytes` 减去页缓存，见 `MemoryAccounting::Rss`
+    Rss,
+}
+
+/// Sandbox 资源限制
+///
+/// 单位与 [SandboxConfig](struct.SandboxConfig.html) 中对应的字段相同，
+/// 可以用 `SandboxConfig::with_limits()` 将同一组限制用于多个命令
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SandboxLimits {
+    /// 时间限制（以 ms 为单位）
+    pub time_limit: u64,
+    /// 内存限制（以 bytes 为单位）
+    pub memory_limit: u64,
+    /// Pid 限制
+    pub pids_limit: u16,
+}
+
+/// 块设备读写速度限制（以 bytes/s 为单位）
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoLimit {
+    /// 读速度限制
+    pub read_bps: Option<u64>,
+    /// 写速度限制
+    pub write_bps: Option<u64>,
 }
 
 impl SandboxConfig {
@@ -35,115 +445,117 @@ impl SandboxConfig {
         T: std::fmt::Display,
     {
         SandboxConfig {
-            time_limit: time_limit,
-            memory_limit: memory_limit,
-            pids_limit: pids_limit,
-            command: command.to_string(),
+            time_limit,
+            wall_time_limit: None,
+            memory_limit,
+            legacy_memory_multiplier: LEGACY_MEMORY_MULTIPLIER,
+            pids_limit,
+            command: SandboxCommand::Shell(command.to_string()),
             stdin,
             stdout,
             stderr,
+            io_limit: None,
+            uid: None,
+            gid: None,
+            supplementary_groups: Vec::new(),
+            nice: None,
+            max_run_duration: None,
+            memory_accounting: MemoryAccounting::default(),
+            timeout_signal: nix::sys::signal::Signal::SIGKILL,
+            oom_behavior: OomBehavior::default(),
+            verdict_policy: VerdictPolicy::default(),
+            setup_command: None,
+            thread_limit: None,
+            fail_on_leftover_tasks: false,
+            use_freezer_on_kill: true,
+            collect_detailed_stats: false,
+            cpu_time_source: CpuTimeSource::default(),
+            count_instructions: false,
+            instruction_limit: None,
+            drop_capabilities: true,
+            file_size_limit: None,
+            no_new_privs: true,
+            child_setup: None,
+            stdout_stream: None,
+            output_drain_grace: std::time::Duration::from_millis(200),
+            stdout_tty: false,
+            tty: None,
+            cgroup: None,
+            output_limit: None,
+            resource_stream: None,
+            stdin_mode: StdinMode::default(),
+            max_command_length: DEFAULT_MAX_COMMAND_LENGTH,
+            max_argv_count: DEFAULT_MAX_ARGV_COUNT,
         }
     }
-}
-
-/// 用于限制 Sandbox 的资源使用的 cgroup
-struct SandboxCgroup {
-    freezer: cgroups_fs::AutomanagedCgroup,
-    memory: cgroups_fs::AutomanagedCgroup,
-    pids: cgroups_fs::AutomanagedCgroup,
-    cpuacct: cgroups_fs::AutomanagedCgroup,
-}
-
-impl SandboxCgroup {
-    /// 新建一个 Sandbox 组
-    fn new(cgroup_name: &str) -> Result<SandboxCgroup, Box<dyn Error>> {
-        use cgroups_fs::*;
-        let cur_cgroup = CgroupName::new(cgroup_name);
-        Ok(SandboxCgroup {
-            memory: AutomanagedCgroup::init(&cur_cgroup, "memory")?,
-            pids: AutomanagedCgroup::init(&cur_cgroup, "pids")?,
-            freezer: AutomanagedCgroup::init(&cur_cgroup, "freezer")?,
-            cpuacct: AutomanagedCgroup::init(&cur_cgroup, "cpuacct")?,
-        })
-    }
-    /// 返回 cgroup 内是否还有进程
-    pub fn is_empty(&self) -> Result<bool, Box<dyn Error>> {
-        log::trace!("Current task list: {:?}", self.freezer.get_tasks()?);
-        Ok(self.freezer.get_tasks()?.is_empty())
-    }
-    /// 获取运行所消耗的 CPU 时间
-    pub fn get_cpu_time(&self) -> Result<std::time::Duration, Box<dyn Error>> {
-        Ok(std::time::Duration::from_nanos(
-            self.cpuacct.get_value::<u64>("cpuacct.usage")?,
-        ))
-    }
-    /// 获取最大的内存占用
-    pub fn get_max_memory(&self) -> Result<u64, Box<dyn Error>> {
-        Ok(self
-            .memory
-            .get_value::<u64>("memory.memsw.max_usage_in_bytes")?)
-    }
-    /// 将所有统计还原
-    pub fn clear(&self) -> Result<(), Box<dyn Error>> {
-        self.memory
-            .set_value("memory.memsw.max_usage_in_bytes", 0)?;
-        self.cpuacct.set_value("cpuacct.usage", 0)?;
-
-        Ok(())
-    }
-    /// 设置内存限制
-    pub fn set_memory_limit(&self, memory_limit: u64) -> Result<(), Box<dyn Error>> {
-        self.memory
-            .set_value("memory.limit_in_bytes", memory_limit * 2)?;
-        self.memory
-            .set_value("memory.memsw.limit_in_bytes", memory_limit * 2)?;
-
-        Ok(())
-    }
-    /// 设置 Pid 限制
-    pub fn set_pids_limit(&self, pids_limit: u16) -> Result<(), Box<dyn Error>> {
-        self.pids.set_value("pids.max", pids_limit)?;
-
-        Ok(())
-    }
-    /// 杀死 cgroup 内所有进程
+    /// 检查命令的长度和参数个数，防止过长的输入在启动时才出现难以理解的错误（如超过 `ARG_MAX`）
     ///
-    /// 先通过 freezer cgroup 冻结，然后发送 kill 指令
-    pub fn kill_all_tasks(&self, timeout: std::time::Duration) -> Result<(), Box<dyn Error>> {
-        let freezer = &self.freezer;
-        let delay = std::time::Duration::from_millis(100);
-        let mut timeout = timeout;
-
-        log::info!("Try kill all in cgroup {:?}", &freezer);
-        log::trace!("Current task list {:?}", freezer.get_tasks()?);
-
-        if self.is_empty()? {
+    /// 超过 `max_command_length` 或 `max_argv_count`，命令为空（shell 命令只有空白字符、
+    /// 或 `program` 为空），或同时设置了 `tty` 和 `stdout_stream` 时返回
+    /// `SandboxError::InvalidConfig`。
+    /// `spawn()` 和 `validate_exec()` 都会先调用它
+    pub fn validate(&self) -> Result<(), SandboxError> {
+        let (length, count, empty) = match &self.command {
+            SandboxCommand::Shell(command) => (command.len(), None, command.trim().is_empty()),
+            SandboxCommand::Argv { program, argv } => (
+                program.as_os_str().len() + argv.iter().map(String::len).sum::<usize>(),
+                Some(argv.len()),
+                program.as_os_str().is_empty(),
+            ),
+        };
+        // 空的 shell 命令会直接成功，通常是调用者忘记了设置命令
+        let err = if empty {
+            String::from("command is empty")
+        } else if length > self.max_command_length {
+            format!(
+                "command is {} bytes, longer than {} bytes",
+                length, self.max_command_length
+            )
+        } else if let Some(count) = count.filter(|count| *count > self.max_argv_count) {
+            format!(
+                "command has {} arguments, more than {}",
+                count, self.max_argv_count
+            )
+        } else if self.tty.is_some() && self.stdout_stream.is_some() {
+            String::from("tty can not be used with stdout_stream")
+        } else {
             return Ok(());
         };
-
-        freezer.set_value::<&str>("freezer.state", "FROZEN")?;
-
-        while timeout > std::time::Duration::from_millis(0) {
-            if freezer.get_value::<String>("freezer.state")? == "FROZEN" {
-                break;
-            }
-            std::thread::sleep(delay);
-            timeout -= delay;
-        }
-
-        freezer.send_signal_to_all_tasks(nix::sys::signal::Signal::SIGKILL)?;
-
-        freezer.set_value::<&str>("freezer.state", "THAWED")?;
-        while timeout > std::time::Duration::from_millis(0) {
-            log::trace!("{:?}: checking...", timeout);
-            if self.is_empty()? {
-                return Ok(());
-            }
-            std::thread::sleep(delay);
-            timeout -= delay;
+        log::error!("Invalid config: {}", err);
+        Err(SandboxError::InvalidConfig(err))
+    }
+    /// 用一组预先定义的限制创建 Config，其余选项与 `new()` 相同
+    ///
+    /// 同一组限制（例如一道题目的限制）可以用来创建多个 Config
+    pub fn with_limits<T>(
+        limits: SandboxLimits,
+        command: T,
+        stdin: Stdio,
+        stdout: Stdio,
+        stderr: Stdio,
+    ) -> SandboxConfig
+    where
+        T: Into<SandboxCommand>,
+    {
+        let mut config = SandboxConfig::new(
+            limits.time_limit,
+            limits.memory_limit,
+            limits.pids_limit,
+            "",
+            stdin,
+            stdout,
+            stderr,
+        );
+        config.command = command.into();
+        config
+    }
+    /// 这个 Config 的时间、内存和 Pid 限制
+    pub fn limits(&self) -> SandboxLimits {
+        SandboxLimits {
+            time_limit: self.time_limit,
+            memory_limit: self.memory_limit,
+            pids_limit: self.pids_limit,
         }
-
-        Err("Failed to kill all task(s)".to_string().into())
     }
 }
 
@@ -155,275 +567,1905 @@ pub struct Sandbox {
     /// Sandbox 的 work_dir，这个文件夹里的数据会覆盖 rootfs 目录里的数据，然后在挂载点形成一个新的 Rootfs
     work_directory: std::path::PathBuf,
     /// Rootfs 的目录
+    ///
+    /// 只作为 overlay 最下层的 lower 目录，沙箱不会写入其中，可以位于只读的文件系统上
+    /// （如只读挂载的共享镜像或 squashfs），沙箱中的写入都落在 work 目录中的 upper 目录
     rootfs_directory: std::path::PathBuf,
+    /// rootfs 之上额外的 lower 目录，后面的在上层
+    lower_directories: Vec<std::path::PathBuf>,
+    /// overlay 的 upper 目录，所有写入都会落在这里
+    upper_directory: std::path::PathBuf,
+    /// overlay 的 work 目录
+    overlay_work_directory: std::path::PathBuf,
+    /// `snapshot()` 保存的 upper 目录副本
+    snapshot_directory: Option<std::path::PathBuf>,
+    /// 新建沙箱（检查环境和挂载）所用的时间
+    mount_time: std::time::Duration,
+    /// work 目录上是否挂载了沙箱专用的 tmpfs
+    tmpfs: bool,
+    /// 共享的 rootfs，沙箱卸载后才释放
+    shared_rootfs: Option<std::sync::Arc<rootfs::SharedMount>>,
+    /// 所有运行累计消耗的 CPU 时间（以 ns 为单位）
+    total_cpu_time: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    /// 正在进行的运行数（`RunHandle` 的个数）
+    running: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    /// 累计 CPU 时间上限
+    cpu_budget: Option<std::time::Duration>,
+    /// 主机上可用的 cgroup controller
+    controllers: CgroupControllers,
+    /// 预先设置好限制的 cgroup，见 `Sandbox::with_fixed_limits()`
+    fixed_cgroup: Option<FixedCgroup>,
+    /// overlay 之上额外挂载的目录，见 `SandboxBuilder`
+    extra_mounts: builder::ExtraMounts,
+    /// 每次运行额外加入的 cgroup controller，见 `SandboxBuilder::controller()`
+    extra_controllers: Vec<ControllerSpec>,
+    /// 每次运行的 cgroup 所在的父 cgroup，见 `SandboxBuilder::cgroup_parent()`
+    cgroup_parent: Option<std::path::PathBuf>,
+    /// 加在每个命令之前的 argv，见 `SandboxBuilder::command_prefix()`
+    command_prefix: Vec<String>,
+    /// 内存限制的设置方式，见 `SandboxBuilder::memory_limit_mode()`
+    memory_limit_mode: MemoryLimitMode,
+    /// 已挂载的额外挂载点（按挂载顺序）
+    mounts: Vec<std::path::PathBuf>,
+    /// `SandboxBuilder::ephemeral()` 自动创建的目录，卸载后删除
+    owned_directory: Option<std::path::PathBuf>,
+    /// `SandboxBuilder::from_fds()` 传入的目录，rootfs 和 work 目录的路径依赖它们
+    directory_fds: Option<std::sync::Arc<builder::DirectoryFds>>,
+    /// 对挂载点的占用，卸载后释放
+    mountpoint: Option<MountpointGuard>,
+    /// `SandboxBuilder::concurrency_limit()` 的名额，完全卸载后归还
+    permit: Option<ConcurrencyPermit>,
     /// 是否已挂载
     mounted: bool,
 }
 
 /// Sandbox 运行状态种类
-/// 如果一个程序遇到了多个错误，那么优先级是 tle > mle > re > success
-#[derive(Debug)]
+/// 如果一个程序遇到了多个错误，那么优先级是
+/// output > thread > instruction > tle > mle > pids > re > success，
+/// mle 和 re 的顺序可以由 `VerdictPolicy` 调整，具体规则见 `classify()`
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SandboxStatusKind {
-    /// 超时
-    TimeLimitExceeded,
+    /// 超时，包含超过的是 CPU 时间还是实际运行时间
+    TimeLimitExceeded { kind: TimeDimension },
     /// 内存超限
     MemoryLimitExceeded,
     /// 运行时错误/返回值非 0
     RuntimeError,
+    /// 程序没有正常结束（同 `RuntimeError`），且运行中有 fork 因达到 `pids_limit` 而失败
+    PidsLimitExceeded,
+    /// 某个进程的线程数超过了 `thread_limit`
+    ThreadLimitExceeded,
+    /// stdout 超过了 `output_limit`
+    OutputLimitExceeded,
+    /// 用户态指令数超过了 `instruction_limit`
+    InstructionLimitExceeded,
     /// 正常
     Success,
 }
 
+impl SandboxStatusKind {
+    /// 是否为 `Success`
+    pub fn is_success(&self) -> bool {
+        *self == SandboxStatusKind::Success
+    }
+    /// 是否为任意种类的 `TimeLimitExceeded`
+    pub fn is_time_limit_exceeded(&self) -> bool {
+        matches!(self, SandboxStatusKind::TimeLimitExceeded { .. })
+    }
+}
+
+impl std::fmt::Display for SandboxStatusKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SandboxStatusKind::TimeLimitExceeded { kind } => match kind {
+                TimeDimension::Cpu => write!(f, "Time Limit Exceeded (cpu)"),
+                TimeDimension::Wall => write!(f, "Time Limit Exceeded (wall)"),
+            },
+            SandboxStatusKind::MemoryLimitExceeded => write!(f, "Memory Limit Exceeded"),
+            SandboxStatusKind::RuntimeError => write!(f, "Runtime Error"),
+            SandboxStatusKind::PidsLimitExceeded => write!(f, "Pids Limit Exceeded"),
+            SandboxStatusKind::ThreadLimitExceeded => write!(f, "Thread Limit Exceeded"),
+            SandboxStatusKind::OutputLimitExceeded => write!(f, "Output Limit Exceeded"),
+            SandboxStatusKind::InstructionLimitExceeded => {
+                write!(f, "Instruction Limit Exceeded")
+            }
+            SandboxStatusKind::Success => write!(f, "Success"),
+        }
+    }
+}
+
+/// 超时的种类
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeDimension {
+    /// CPU 时间超过了时间限制，通常是计算量太大
+    Cpu,
+    /// CPU 时间没有超限，但实际运行时间超过了时间限制，通常是程序在等待（sleep、读入等）
+    Wall,
+}
+
 /// 沙箱运行状态
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SandboxStatus {
     /// 分类
     pub status: SandboxStatusKind,
-    /// 使用时间
-    pub used_time: u128,
-    /// 使用内存
-    pub max_memory: u64,
-    /// 程序返回值
+    /// 使用时间（以 ms 为单位，向下取整），读取 cgroup 失败时为 None
+    ///
+    /// 超时（`TimeLimitExceeded`）时同样为实测的 CPU 时间，因实际运行时间超限被杀死的程序
+    /// 可能小于时间限制，超时的种类见 `TimeLimitExceeded` 的 `kind`
+    pub used_time: Option<u128>,
+    /// 使用时间（以 ns 为单位），与 `used_time` 相同但保留 cpuacct 的完整精度
+    pub used_time_ns: Option<u128>,
+    /// `used_time` 统计的是哪种时间，通常为 `TimeDimension::Cpu`
+    ///
+    /// 主机上没有 cpuacct controller 时为 `TimeDimension::Wall`，`used_time` 为实际运行时间，
+    /// 超时也只能按实际运行时间判断，精度较低
+    pub used_time_source: TimeDimension,
+    /// 实际运行时间（以 ms 为单位）
+    pub wall_time: u128,
+    /// 使用内存（以 bytes 为单位），读取 cgroup 失败时为 None
+    ///
+    /// 统计方式见 [MemoryAccounting](enum.MemoryAccounting.html)
+    pub max_memory: Option<u64>,
+    /// `max_memory` 来自哪个计数器，读取 cgroup 失败时为 None
+    ///
+    /// memsw 的计数器在这个 cgroup 上不可用时会退而使用不含 swap 的峰值
+    pub memory_counter: Option<MemoryCounter>,
+    /// 运行中是否用到了 swap（memsw 的峰值大于不含 swap 的峰值），memsw 不可用或读取失败时
+    /// 为 None
+    ///
+    /// 内存限制同时写入 memory 和 memsw，两者相同，所以起作用的总是包含 swap 的 memsw 限制；
+    /// 为 true 时说明程序的一部分内存被换出，`memory_counter` 为 `MemoryCounter::Memsw`
+    /// 时这部分仍计入了 `max_memory`
+    pub swap_used: Option<bool>,
+    /// 程序返回值，没有正常退出时为 -1
     pub return_code: i32,
+    /// 程序的结束方式
+    pub termination: Termination,
+    /// 块设备读写统计，仅在设置了 `io_limit` 且限速生效时存在
+    ///
+    /// blkio 不提供限速发生的次数，可与限速值和运行时间对比判断是否被限速
+    pub io_stats: Option<IoStats>,
+    /// 运行中同时存在的最大进程（线程）数，读取 cgroup 失败时为 None
+    ///
+    /// 每 100ms 采样一次 `pids.current`，可以与 `pids_limit` 对比判断程序是否达到了限制
+    pub max_pids: Option<u64>,
+    /// 沙箱主动结束程序（超时或 `OomBehavior::Pause` 下内存超限）时使用的信号，否则为 None
+    ///
+    /// 超时的程序在 `timeout_signal` 后自行结束时为 `timeout_signal`，否则为 SIGKILL
+    pub kill_signal: Option<nix::sys::signal::Signal>,
+    /// 程序执行的用户态指令数，只在设置了 `count_instructions` 且能够计数时存在
+    pub instructions: Option<u64>,
+    /// 原始的 cgroup 统计，只在设置了 `collect_detailed_stats` 时存在
+    pub detailed_stats: Option<CgroupReport>,
+    /// 沙箱自身的耗时
+    pub overhead: Overhead,
+    /// 这次运行的 ID，沙箱在这次运行中输出的日志都以 `[<run_id>]` 开头
+    ///
+    /// 新建 cgroup 时也用作 cgroup 的名字
+    pub run_id: String,
+}
+
+/// 一次运行中沙箱自身的耗时，不包括程序的运行时间 `wall_time`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Overhead {
+    /// 准备命令所用的时间
+    pub setup_command: std::time::Duration,
+    /// 准备 cgroup、fork 和启动监视线程所用的时间
+    pub prepare: std::time::Duration,
+    /// 程序结束后清理 cgroup 和读取统计所用的时间
+    pub teardown: std::time::Duration,
+}
+
+/// 程序的结束方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Termination {
+    /// 程序自行退出，包含返回值
+    Exited(i32),
+    /// 程序被信号终止，信号不是沙箱发出的
+    Signaled(nix::sys::signal::Signal),
+    /// 程序超时后被沙箱杀死
+    Killed,
+}
+
+/// 块设备读写统计（以 bytes 为单位）
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoStats {
+    /// 读取字节数
+    pub read_bytes: u64,
+    /// 写入字节数
+    pub write_bytes: u64,
+}
+
+/// 运行结束时读取的原始 cgroup 统计，见 `SandboxConfig::collect_detailed_stats`
+///
+/// 在杀死 cgroup 内剩余进程之前读取，读取失败或缺少对应 controller 的项为空
+#[derive(Debug, Clone, Default)]
+pub struct CgroupReport {
+    /// `memory.stat` 的全部内容（以 bytes 或次数为单位）
+    pub memory_stat: std::collections::BTreeMap<String, u64>,
+    /// 内存用量达到限制的次数（`memory.failcnt`）
+    pub memory_failcnt: Option<u64>,
+    /// 这次运行中被 OOM killer 杀死的进程数（`memory.oom_control` 中的 `oom_kill`），
+    /// 内核不支持时为 None
+    pub oom_kills: Option<u64>,
+    /// 用户态 CPU 时间（`cpuacct.stat`，精度为 `USER_HZ`）
+    pub cpu_user: Option<std::time::Duration>,
+    /// 内核态 CPU 时间（`cpuacct.stat`，精度为 `USER_HZ`）
+    pub cpu_system: Option<std::time::Duration>,
+    /// 用户态 CPU 时间（回收子进程时 `wait4` 得到的 `ru_utime`），见 `CpuTimeSource::Rusage`
+    pub rusage_user: Option<std::time::Duration>,
+    /// 内核态 CPU 时间（回收子进程时 `wait4` 得到的 `ru_stime`）
+    pub rusage_system: Option<std::time::Duration>,
+    /// 结束时的进程（线程）数（`pids.current`）
+    pub pids_current: Option<u64>,
+    /// 采样到的最大进程（线程）数，同 `SandboxStatus::max_pids`
+    pub pids_peak: Option<u64>,
+    /// `cpu.stat` 的全部内容（CFS 限流次数和时间），只在通过 `ControllerSpec` 加入 cpu
+    /// controller 时存在
+    pub cpu_stat: Option<std::collections::BTreeMap<String, u64>>,
 }
 
 impl Sandbox {
     /// 新建沙箱
     ///
-    /// 参数含义见 [Sandbox](struct.Sandbox.html)
+    /// 参数含义见 [Sandbox](struct.Sandbox.html)，更多挂载选项见
+    /// [SandboxBuilder](struct.SandboxBuilder.html)
     pub fn new<T, U, V>(
         rootfs_directory: T,
         work_directory: U,
         sandbox_directory: V,
-    ) -> Result<Sandbox, Box<dyn Error>>
+    ) -> Result<Sandbox, SandboxError>
     where
         T: AsRef<std::path::Path>,
         U: AsRef<std::path::Path>,
         V: AsRef<std::path::Path>,
     {
-        let rootfs_directory = std::path::PathBuf::from(rootfs_directory.as_ref());
-        let work_directory = std::path::PathBuf::from(work_directory.as_ref());
-        let sandbox_directory = std::path::PathBuf::from(sandbox_directory.as_ref());
-
-        let log_and_panic = |err: &str| -> Result<(), Box<dyn Error>> {
-            log::error!("{}", err);
-            return Err(String::from(err).into());
-        };
+        SandboxBuilder::new(rootfs_directory, work_directory, sandbox_directory).mount()
+    }
+    /// 用 OCI 镜像的各层（最上层在前）作为 rootfs 新建沙箱，见 `SandboxBuilder::from_oci_layers()`
+    pub fn from_oci_layers<I, P, U, V>(
+        layers: I,
+        work_directory: U,
+        sandbox_directory: V,
+    ) -> Result<Sandbox, SandboxError>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<std::path::Path>,
+        U: AsRef<std::path::Path>,
+        V: AsRef<std::path::Path>,
+    {
+        SandboxBuilder::from_oci_layers(layers, work_directory, sandbox_directory)?.mount()
+    }
+    /// 在系统临时目录下自动创建 work 目录和挂载点并新建沙箱，drop 时一并删除
+    ///
+    /// 需要指定其他位置时使用 `SandboxBuilder::ephemeral()`
+    pub fn ephemeral<T: AsRef<std::path::Path>>(
+        rootfs_directory: T,
+    ) -> Result<Sandbox, SandboxError> {
+        SandboxBuilder::ephemeral(rootfs_directory).mount()
+    }
+    /// 用已经打开的目录 fd 新建沙箱，见 `SandboxBuilder::from_fds()`
+    pub fn from_fds(
+        rootfs: std::os::unix::io::OwnedFd,
+        work: std::os::unix::io::OwnedFd,
+        sandbox: std::os::unix::io::OwnedFd,
+    ) -> Result<Sandbox, SandboxError> {
+        SandboxBuilder::from_fds(rootfs, work, sandbox).mount()
+    }
+    /// 新建沙箱，并在 work 目录上挂载一个大小为 `tmpfs_size` bytes 的 tmpfs
+    ///
+    /// overlay 的 upper 和 work 目录都放在这个 tmpfs 里，写入更快且大小有上限，
+    /// 移除沙箱时卸载 tmpfs 即可清理所有写入的文件。
+    /// 其余参数含义见 [Sandbox](struct.Sandbox.html)
+    pub fn new_with_tmpfs<T, U, V>(
+        rootfs_directory: T,
+        work_directory: U,
+        sandbox_directory: V,
+        tmpfs_size: usize,
+    ) -> Result<Sandbox, SandboxError>
+    where
+        T: AsRef<std::path::Path>,
+        U: AsRef<std::path::Path>,
+        V: AsRef<std::path::Path>,
+    {
+        SandboxBuilder::new(rootfs_directory, work_directory, sandbox_directory)
+            .tmpfs(tmpfs_size)
+            .mount()
+    }
+    /// 检查环境并按 `builder` 挂载沙箱
+    fn mount(builder: SandboxBuilder) -> Result<Sandbox, SandboxError> {
+        let SandboxBuilder {
+            rootfs_directory,
+            work_directory,
+            sandbox_directory,
+            lower_directories,
+            tmpfs_size,
+            extra_mounts,
+            extra_controllers,
+            cgroup_parent,
+            command_prefix,
+            memory_limit_mode,
+            directory_fds,
+            stale_mount,
+            rootfs_digest,
+            concurrency_limit,
+            ..
+        } = builder;
+        // 排队等待的时间不计入挂载时间
+        let permit = concurrency_limit.as_ref().map(ConcurrencyLimit::acquire);
+        let mount_start = std::time::Instant::now();
 
-        let check_directory = |directory: &std::path::PathBuf| -> Result<(), Box<dyn Error>> {
-            if directory.exists() == false {
-                log_and_panic(&format!("{:?} Not Found!", directory))?;
+        let check_directory = |directory: &std::path::PathBuf| -> Result<(), SandboxError> {
+            if !directory.exists() {
+                log::error!("{:?} Not Found!", directory);
+                return Err(SandboxError::DirectoryNotFound(directory.clone()));
             }
             Ok(())
         };
 
+        // Check cgroup controllers
+        let controllers = CgroupControllers::probe()?;
+
+        for spec in &extra_controllers {
+            if !cgroup::controller_mounted(spec.name()) {
+                log::error!("cgroup controller {} is not mounted", spec.name());
+                return Err(SandboxError::MissingController(spec.name().to_string()));
+            }
+        }
+
+        if let Some(parent) = &cgroup_parent {
+            cgroup::check_parent(parent, &controllers, &extra_controllers)?;
+        }
+
         // Check swapaccount
-        if std::path::Path::new("/sys/fs/cgroup/memory/memory.memsw.usage_in_bytes").exists()
-            == false
+        if memory_limit_mode == MemoryLimitMode::Memsw
+            && !std::path::Path::new("/sys/fs/cgroup/memory/memory.memsw.usage_in_bytes").exists()
         {
-            log_and_panic("Need \"cgroup_enable=memory swapaccount=1\" kernel parameter")?;
+            log::error!("{}", SandboxError::SwapAccountDisabled);
+            return Err(SandboxError::SwapAccountDisabled);
         }
 
         check_directory(&rootfs_directory)?;
         check_directory(&work_directory)?;
         check_directory(&sandbox_directory)?;
+        for directory in &lower_directories {
+            check_directory(directory)?;
+        }
+        let mut directories = vec![&rootfs_directory, &work_directory, &sandbox_directory];
+        directories.extend(&lower_directories);
+        check_overlapping(&directories)?;
+        // upper 和 work 目录都在 work 目录中（使用 tmpfs 时在其上挂载的 tmpfs 中）
+        if tmpfs_size.is_none()
+            && nix::unistd::access(&work_directory, nix::unistd::AccessFlags::W_OK).is_err()
+        {
+            log::error!("{:?} is not writable", work_directory);
+            return Err(SandboxError::ReadOnlyDirectory(work_directory));
+        }
+        if let Some(expected) = rootfs_digest {
+            let actual = digest::rootfs_digest(&rootfs_directory)?;
+            if !actual.eq_ignore_ascii_case(&expected) {
+                log::error!(
+                    "Rootfs {:?} digest mismatch, expected {} but got {}",
+                    rootfs_directory,
+                    expected,
+                    actual
+                );
+                return Err(SandboxError::RootfsIntegrity(expected, actual));
+            }
+        }
+        // 先检查当前进程中的其他沙箱，以免 `StaleMount::Reclaim` 卸载还在使用的沙箱
+        let mountpoint = MountpointGuard::acquire(&sandbox_directory)?;
+        if tmpfs_size.is_some() {
+            check_stale_mount(&work_directory, stale_mount)?;
+        }
+        check_stale_mount(&sandbox_directory, stale_mount)?;
+
+        // Mount tmpfs
+        let (upper_directory, overlay_work_directory) = match tmpfs_size {
+            Some(size) => {
+                let target = work_directory.clone();
+                with_mount_timeout(&work_directory, move || {
+                    libmount::Tmpfs::new(&target)
+                        .size_bytes(size)
+                        .mount()
+                        .map_err(|err| err.to_string())
+                })?
+                .map_err(SandboxError::Mount)?;
+                let upper_directory = work_directory.join("upper");
+                let overlay_work_directory = work_directory.join("work");
+                let created = std::fs::create_dir(&upper_directory)
+                    .and_then(|_| std::fs::create_dir(&overlay_work_directory));
+                if let Err(err) = created {
+                    umount_or_log(&work_directory);
+                    return Err(err.into());
+                }
+                (upper_directory, overlay_work_directory)
+            }
+            None => (work_directory.clone(), sandbox_directory.clone()),
+        };
 
         // Mount Directory
-        let lower_dirs = [&rootfs_directory];
-        libmount::Overlay::writable(
-            lower_dirs.iter().map(|x| x.as_ref()),
-            &work_directory,
-            &sandbox_directory,
+        let mounted = mount_overlay(
+            &overlay_lower_directories(&rootfs_directory, &lower_directories),
+            &upper_directory,
+            &overlay_work_directory,
             &sandbox_directory,
         )
-        .mount()?;
+        .and_then(|_| {
+            extra_mounts
+                .mount(&sandbox_directory)
+                .inspect_err(|_| umount_or_log(&sandbox_directory))
+        });
+        let mounts = match mounted {
+            Ok(mounts) => mounts,
+            Err(err) => {
+                if tmpfs_size.is_some() {
+                    umount_or_log(&work_directory);
+                }
+                return Err(err);
+            }
+        };
 
         Ok(Sandbox {
             sandbox_directory,
             work_directory,
             rootfs_directory,
+            lower_directories,
+            upper_directory,
+            overlay_work_directory,
+            snapshot_directory: None,
+            mount_time: mount_start.elapsed(),
+            tmpfs: tmpfs_size.is_some(),
+            shared_rootfs: None,
+            controllers,
+            fixed_cgroup: None,
+            total_cpu_time: Default::default(),
+            running: Default::default(),
+            cpu_budget: None,
+            extra_mounts,
+            extra_controllers,
+            cgroup_parent,
+            command_prefix,
+            memory_limit_mode,
+            mounts,
+            owned_directory: None,
+            directory_fds,
+            mountpoint: Some(mountpoint),
+            permit,
             mounted: true,
         })
     }
-    /// 通过 SandboxConfig 在沙箱里执行命令
-    pub fn run(&self, config: SandboxConfig) -> Result<SandboxStatus, Box<dyn Error>> {
-        use cgroups_fs::CgroupsCommandExt;
-        use std::time::Duration;
-        use wait_timeout::ChildExt;
-
-        // Init
-        let cgroup = SandboxCgroup::new(&uuid::Uuid::new_v4().to_string()).unwrap();
-        let time_limit = Duration::from_millis(config.time_limit + 500);
-        let mut status = SandboxStatusKind::Success;
-        let mut used_time = time_limit;
-
-        // Set cgroup limit
-        cgroup.clear()?;
-        cgroup.set_memory_limit(config.memory_limit * 2)?;
-        cgroup.set_pids_limit(config.pids_limit)?;
-
-        let mut return_code = Some(0);
-        match nix::unistd::fork() {
-            Err(_) => log::error!("Fork error!"),
-            Ok(nix::unistd::ForkResult::Child) => {
-                log::trace!("forked!");
-                nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWPID).unwrap();
-                // Create Child
-                let mut child_exec = std::process::Command::new("bash")
-                    .args(&["-c", &config.command])
-                    .current_dir(&self.sandbox_directory)
-                    .cgroups(&[
-                        &cgroup.memory,
-                        &cgroup.pids,
-                        &cgroup.freezer,
-                        &cgroup.cpuacct,
-                    ])
-                    .chroot(self.sandbox_directory.to_str().unwrap().to_string())
-                    .stdin(config.stdin)
-                    .stdout(config.stdout)
-                    .stderr(config.stderr)
-                    .spawn()
-                    .unwrap();
-
-                let return_code = match child_exec.wait_timeout(time_limit * 2).unwrap() {
-                    Some(status) => status.code(),
-                    _ => {
-                        child_exec.kill().unwrap();
-                        child_exec.wait().unwrap().code()
-                    }
-                };
-                log::debug!("forked: {:?}", return_code);
-                std::process::exit(return_code.unwrap_or_else(|| -1));
-            }
-            Ok(nix::unistd::ForkResult::Parent { child, .. }) => {
-                use nix::sys::wait::WaitStatus::Exited;
-                let mut timeout = time_limit;
-                let delay = Duration::from_millis(100);
-                let zero_time = Duration::from_millis(0);
-
-                // Wait for child task start
-                std::thread::sleep(delay);
-
-                // Look up until timeout or no task in cgroup
-                while timeout > zero_time {
-                    if cgroup.is_empty()? {
-                        break;
-                    }
-                    std::thread::sleep(delay);
-                    timeout -= delay;
-                    log::trace!("less time {:?}", timeout);
-                }
+    /// 为沙箱预先创建一个 cgroup 并设置好内存和 Pid 限制
+    ///
+    /// 之后内存和 Pid 限制与 `limits` 相同的运行会复用这个 cgroup，每次运行只需重置统计，
+    /// 适合用同一组限制运行大量测试点的情况。限制不同、设置了 `io_limit`、
+    /// 使用 `OomBehavior::Pause` 或者该 cgroup 正被另一次运行使用时，仍会新建 cgroup。
+    pub fn with_fixed_limits(mut self, limits: SandboxLimits) -> Result<Sandbox, SandboxError> {
+        let cgroup = SandboxCgroup::new(
+            &uuid::Uuid::new_v4().to_string(),
+            self.cgroup_parent.as_deref(),
+            &self.extra_controllers,
+        )?;
+        let settings = CgroupSettings {
+            memory_limit: limits.memory_limit,
+            pids_limit: limits.pids_limit,
+            memory_limit_mode: self.memory_limit_mode,
+            legacy_memory_multiplier: LEGACY_MEMORY_MULTIPLIER,
+            oom_killer: true,
+            io_limit: None,
+        };
+        cgroup.configure(&settings)?;
+        self.fixed_cgroup = Some(FixedCgroup::new(settings, cgroup));
 
-                nix::sys::signal::kill(child, nix::sys::signal::Signal::SIGKILL).unwrap();
-                return_code = match nix::sys::wait::waitpid(child, None)? {
-                    Exited(_pid, status) => Some(status),
-                    _ => None,
-                };
-                log::trace!("main: {:?}", return_code);
+        Ok(self)
+    }
+    /// 设置所有运行累计 CPU 时间的上限
+    ///
+    /// 累计值达到上限后，之后的运行会直接返回 `SandboxError::CpuBudgetExhausted`，
+    /// 适合一组测试点共用时间限制的情况。每次运行仍受各自的时间限制
+    pub fn with_cpu_budget(mut self, budget: std::time::Duration) -> Sandbox {
+        self.cpu_budget = Some(budget);
+        self
+    }
+    /// 返回所有运行累计消耗的 CPU 时间
+    pub fn total_cpu_time(&self) -> std::time::Duration {
+        std::time::Duration::from_nanos(
+            self.total_cpu_time
+                .load(std::sync::atomic::Ordering::Acquire),
+        )
+    }
+    /// 将累计的 CPU 时间清零，例如开始运行下一组测试点时
+    pub fn reset_total_cpu_time(&self) {
+        self.total_cpu_time
+            .store(0, std::sync::atomic::Ordering::Release);
+    }
+    /// 保存当前 upper 目录的内容，例如编译完成后
+    ///
+    /// 之后可以用 `restore_snapshot()` 将沙箱恢复到这个状态，再次调用会覆盖之前的快照。
+    /// 快照保存在临时目录中，沙箱 drop 时删除
+    pub fn snapshot(&mut self) -> Result<(), SandboxError> {
+        if let Some(directory) = self.snapshot_directory.take() {
+            std::fs::remove_dir_all(directory)?;
+        }
+        let snapshot_directory =
+            std::env::temp_dir().join(format!("nova-sandbox-snapshot-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&snapshot_directory)?;
+        self.snapshot_directory = Some(snapshot_directory.clone());
+        copy_directory(&self.upper_directory, &snapshot_directory)?;
+        log::info!(
+            "Snapshot {:?} to {:?}",
+            &self.upper_directory,
+            &snapshot_directory
+        );
 
-                if timeout == zero_time {
-                    used_time = std::cmp::max(time_limit + delay, cgroup.get_cpu_time()?);
-                } else {
-                    used_time = cgroup.get_cpu_time()?;
-                }
+        Ok(())
+    }
+    /// 将沙箱的可写层恢复到 `snapshot()` 时的状态
+    ///
+    /// 需要重新挂载 overlay，没有快照时返回错误
+    pub fn restore_snapshot(&mut self) -> Result<(), SandboxError> {
+        let snapshot_directory = match &self.snapshot_directory {
+            Some(directory) => directory.clone(),
+            None => {
+                return Err(SandboxError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "no snapshot to restore",
+                )))
             }
         };
-
-        cgroup
-            .kill_all_tasks(std::time::Duration::from_millis(1000))
-            .unwrap_or_else(|err| {
-                log::warn!("failed to kill all task in cgroup: {}", err);
-            });
-
-        // Get return code
-        let return_code = match return_code {
-            // Rust Crashes
-            // TODO: Does rust crash should terminal process?
-            Some(101) => {
-                log::error!("Failed to run command");
-                return Err(String::from("Failed to run command").into());
-            }
-            Some(code) => code,
-            None => -1,
-        };
-        if return_code != 0 {
-            status = SandboxStatusKind::RuntimeError;
+        log::info!(
+            "Restore {:?} from {:?}",
+            &self.upper_directory,
+            &snapshot_directory
+        );
+        self.remount_overlay(&[], Some(&snapshot_directory))
+    }
+    /// 清空沙箱的可写层并重置统计，使沙箱可以直接交给下一个任务使用
+    ///
+    /// 会清除：upper 目录中的所有文件（包括编译产物，需要保留时请用 `snapshot()`），
+    /// 预先创建的 cgroup 的统计，以及累计的 CPU 时间。
+    /// 不会清除：快照、tmpfs 与共享 rootfs 的挂载、预先创建的 cgroup 和 CPU 时间上限。
+    ///
+    /// overlayfs 不允许在挂载时修改 upper 目录，所以只会重新挂载 overlay 本身。
+    /// 与 `reset_with(&ResetStrategy::Wipe)` 相同
+    pub fn reset(&mut self) -> Result<(), SandboxError> {
+        self.reset_with(&ResetStrategy::Wipe)
+    }
+    /// 按 `strategy` 处理沙箱的可写层，其余与 `reset()` 相同
+    ///
+    /// 各个策略的开销见 [ResetStrategy](enum.ResetStrategy.html)
+    pub fn reset_with(&mut self, strategy: &ResetStrategy) -> Result<(), SandboxError> {
+        log::info!(
+            "Reset sandbox on {:?} ({:?})",
+            &self.sandbox_directory,
+            strategy
+        );
+        match strategy {
+            ResetStrategy::Wipe => self.remount_overlay(&[], None)?,
+            ResetStrategy::Keep(paths) => {
+                let keep = paths
+                    .iter()
+                    .map(|path| path.strip_prefix("/").unwrap_or(path))
+                    .collect::<Vec<_>>();
+                self.remount_overlay(&keep, None)?
+            }
+            ResetStrategy::Snapshot => self.restore_snapshot()?,
         }
+        if let Some(lease) = self.fixed_cgroup.as_ref().and_then(|fixed| fixed.lease()) {
+            RunCgroup::Fixed(lease).clear()?;
+        }
+        self.reset_total_cpu_time();
 
-        // Calc Memory
-        let max_memory = cgroup.get_max_memory()?;
-        if max_memory > config.memory_limit {
-            status = SandboxStatusKind::MemoryLimitExceeded;
+        Ok(())
+    }
+    /// 卸载 overlay，将 upper 目录替换为 `contents` 的内容（None 时清空，保留 `keep`），再重新挂载
+    fn remount_overlay(
+        &mut self,
+        keep: &[&std::path::Path],
+        contents: Option<&std::path::Path>,
+    ) -> Result<(), SandboxError> {
+        if !self.mounted {
+            return Err(SandboxError::Mount(String::from("sandbox is not mounted")));
         }
 
-        // Calc time
-        if used_time > std::time::Duration::from_millis(config.time_limit) {
-            status = SandboxStatusKind::TimeLimitExceeded;
+        builder::umount_all(&mut self.mounts)?;
+        umount(&self.sandbox_directory)?;
+        self.mounted = false;
+        clear_directory(&self.upper_directory, keep)?;
+        if let Some(contents) = contents {
+            copy_directory(contents, &self.upper_directory)?;
         }
-        let used_time = used_time.as_millis();
+        mount_overlay(
+            &overlay_lower_directories(&self.rootfs_directory, &self.lower_directories),
+            &self.upper_directory,
+            &self.overlay_work_directory,
+            &self.sandbox_directory,
+        )?;
+        self.mounted = true;
+        self.mounts = self.extra_mounts.mount(&self.sandbox_directory)?;
 
-        log::debug!(
-            "status: {:?}, used_time: {}, return_code: {}, max_memory: {}",
-            status,
-            used_time,
-            return_code,
-            max_memory
-        );
-        Ok(SandboxStatus {
-            status,
-            max_memory,
-            used_time,
-            return_code,
-        })
+        Ok(())
+    }
+    /// 返回 rootfs 目录（overlay 的 lower 目录）
+    pub fn rootfs_directory(&self) -> &std::path::Path {
+        &self.rootfs_directory
+    }
+    /// 返回 work 目录
+    pub fn work_directory(&self) -> &std::path::Path {
+        &self.work_directory
+    }
+    /// 返回 overlay 的 upper 目录，沙箱中写入的文件都在这里
+    pub fn upper_directory(&self) -> &std::path::Path {
+        &self.upper_directory
+    }
+    /// 返回 overlay 的 work 目录
+    pub fn overlay_work_directory(&self) -> &std::path::Path {
+        &self.overlay_work_directory
+    }
+    /// 返回新建沙箱（检查环境和挂载）所用的时间，不计入每次运行的 `Overhead`
+    pub fn mount_time(&self) -> std::time::Duration {
+        self.mount_time
+    }
+    /// 返回主机上可用的 cgroup controller
+    pub fn controllers(&self) -> &CgroupControllers {
+        &self.controllers
+    }
+    /// 沙箱是否已挂载，`remove()` 成功后为 false
+    pub fn is_mounted(&self) -> bool {
+        self.mounted
+    }
+    /// 是否有运行正在进行，即有 `spawn()` 返回的 `RunHandle` 还没有 `join()` 或 drop
+    ///
+    /// `run()` 在返回前一直算作正在进行，准备命令（`setup_command`）运行期间也算
+    pub fn is_running(&self) -> bool {
+        self.running.load(std::sync::atomic::Ordering::Acquire) > 0
     }
-    /// 移除沙箱
-    fn remove(&mut self) {
-        use std::ffi::OsStr;
-        if self.mounted == false {
-            log::warn!("Try to remove an unmounted sandbox");
-            return;
+    /// 为一次运行准备 cgroup 并设置好限制，新建的 cgroup 以 `run_id` 命名
+    ///
+    /// 同时返回需要限速的块设备
+    fn prepare_cgroup(
+        &self,
+        config: &SandboxConfig,
+        run_id: &str,
+    ) -> Result<(RunCgroup, Option<(u64, u64)>), SandboxError> {
+        let io_device = match config.io_limit {
+            Some(_) if self.controllers.has("blkio") => backing_device(&self.work_directory),
+            Some(_) => {
+                log::warn!("blkio controller is not mounted, io_limit is ignored");
+                None
+            }
+            None => None,
+        };
+        if config.legacy_memory_multiplier
+            && !LEGACY_MEMORY_WARNED.swap(true, std::sync::atomic::Ordering::AcqRel)
+        {
+            log::warn!(
+                "legacy_memory_multiplier is deprecated: the cgroup memory limit is 4x memory_limit, \
+                 set it to false to limit memory to memory_limit"
+            );
         }
-        log::info!("Remove sandbox on {:?}", &self);
-        nix::mount::umount(OsStr::new(&self.sandbox_directory))
-            .unwrap_or_else(|err| log::error!("Failed to umount :{}", err));
+        let settings = CgroupSettings {
+            memory_limit: config.memory_limit,
+            pids_limit: config.pids_limit,
+            memory_limit_mode: self.memory_limit_mode,
+            legacy_memory_multiplier: config.legacy_memory_multiplier,
+            oom_killer: config.oom_behavior == OomBehavior::Kill,
+            io_limit: io_device.zip(config.io_limit),
+        };
+        let fixed_cgroup = match &self.fixed_cgroup {
+            Some(fixed) if config.cgroup.is_none() && fixed.settings == settings => fixed.lease(),
+            _ => None,
+        };
+        let cgroup = match fixed_cgroup {
+            Some(lease) => {
+                log::trace!("[{}] Reuse fixed cgroup", run_id);
+                RunCgroup::Fixed(lease)
+            }
+            None => {
+                let mut controllers = self.extra_controllers.clone();
+                if io_device.is_some() {
+                    controllers.push(ControllerSpec::new("blkio"));
+                }
+                let (cgroup, external) = match &config.cgroup {
+                    Some(external) => {
+                        log::trace!("[{}] Use external cgroup {:?}", run_id, external.name());
+                        (SandboxCgroup::open(external, &controllers)?, true)
+                    }
+                    None => (
+                        SandboxCgroup::new(run_id, self.cgroup_parent.as_deref(), &controllers)?,
+                        false,
+                    ),
+                };
+
+                // Set cgroup limit
+                cgroup.configure(&settings)?;
+                if external {
+                    RunCgroup::External(cgroup)
+                } else {
+                    RunCgroup::Owned(cgroup)
+                }
+            }
+        };
+        cgroup.clear()?;
+
+        Ok((cgroup, io_device))
+    }
+    /// 检查沙箱能否执行 `config`，但不真正执行命令
+    ///
+    /// 会创建 cgroup、设置所有限制，并检查沙箱内能否找到 bash（argv 模式下为要执行的程序），
+    /// 用于在不运行选手程序的情况下检查 rootfs 和限制是否配置正确
+    pub fn validate_exec(&self, mut config: SandboxConfig) -> Result<(), SandboxError> {
+        use nix::unistd::{access, AccessFlags};
+        config.command = config.command.with_prefix(&self.command_prefix);
+        config.validate()?;
+        if !self.mounted {
+            return Err(SandboxError::Mount(String::from("sandbox is not mounted")));
+        }
+
+        let _cgroup = self.prepare_cgroup(&config, &uuid::Uuid::new_v4().to_string())?;
+
+        let executable = |path: &std::path::Path| {
+            let path = path.strip_prefix("/").unwrap_or(path);
+            access(&self.sandbox_directory.join(path), AccessFlags::X_OK).is_ok()
+        };
+        match &config.command {
+            SandboxCommand::Shell(_) => {
+                let found_bash = ["bin/bash", "usr/bin/bash"]
+                    .iter()
+                    .any(|path| executable(std::path::Path::new(path)));
+                if !found_bash {
+                    log::error!("bash is not found in {:?}", self.sandbox_directory);
+                    return Err(SandboxError::InvalidRootfs(String::from(
+                        "bash is not found in sandbox",
+                    )));
+                }
+            }
+            SandboxCommand::Argv { program, .. } => {
+                if !executable(program) {
+                    log::error!("{:?} is not executable in sandbox", program);
+                    return Err(SandboxError::InvalidRootfs(format!(
+                        "{:?} is not executable in sandbox",
+                        program
+                    )));
+                }
+            }
+        }
+
+        log::debug!("Sandbox {:?} is ready to exec", self.sandbox_directory);
+        Ok(())
+    }
+    /// 通过 SandboxConfig 在沙箱里执行命令
+    pub fn run(&self, config: SandboxConfig) -> Result<SandboxStatus, SandboxError> {
+        self.spawn(config)?.join()
+    }
+    /// 用同一个程序依次运行多组输入，返回每次运行的状态和 stdout
+    ///
+    /// 每次运行的配置由 `config` 生成，其 `stdin_mode` 和 `stdout_stream` 会被替换为
+    /// 对应的输入和用于收集输出的 channel。没有快照时先用 `snapshot()` 保存当前状态
+    /// （例如编译完成后）；之后每次运行前（已有快照时包括第一次）用 `restore_snapshot()`
+    /// 恢复，使每组输入都从相同的状态开始。`stop` 对某次运行的状态返回 true 时不再运行剩余的输入，
+    /// 例如 `|status| status.status != SandboxStatusKind::Success`
+    pub fn run_batch<F, S>(
+        &mut self,
+        mut config: F,
+        inputs: Vec<Vec<u8>>,
+        mut stop: S,
+    ) -> Result<Vec<(SandboxStatus, Vec<u8>)>, SandboxError>
+    where
+        F: FnMut() -> SandboxConfig,
+        S: FnMut(&SandboxStatus) -> bool,
+    {
+        // 刚保存的快照与当前状态相同，第一次运行前不用恢复
+        let snapshot_taken = self.snapshot_directory.is_none();
+        if snapshot_taken {
+            self.snapshot()?;
+        }
+        let mut results = Vec::with_capacity(inputs.len());
+        for (i, input) in inputs.into_iter().enumerate() {
+            if i > 0 || !snapshot_taken {
+                self.restore_snapshot()?;
+            }
+            let (tx, rx) = std::sync::mpsc::channel();
+            let mut config = config();
+            config.stdin_mode = StdinMode::Bytes(input);
+            config.stdout_stream = Some(tx);
+            let status = self.run(config)?;
+            let output = rx.iter().flatten().collect();
+            let stopped = stop(&status);
+            results.push((status, output));
+            if stopped {
+                break;
+            }
+        }
+
+        Ok(results)
+    }
+    /// 运行交互题，将程序的 stdin/stdout 与交互器的 stdout/stdin 相连
+    ///
+    /// `interactor_stdin` 和 `interactor_stdout` 通常来自由调用者启动的交互器的
+    /// `ChildStdin` 和 `ChildStdout`，会替换 config 中的 stdin 和 stdout。
+    /// 沙箱不保留管道的任何一端，所以任意一方退出时另一方都能读到 EOF；
+    /// 双方互相等待造成的死锁会在达到时间限制后被判为超时
+    pub fn exec_interactive<I, O>(
+        &self,
+        mut config: SandboxConfig,
+        interactor_stdin: I,
+        interactor_stdout: O,
+    ) -> Result<SandboxStatus, SandboxError>
+    where
+        I: Into<Stdio>,
+        O: Into<Stdio>,
+    {
+        config.stdin = interactor_stdout.into();
+        config.stdin_mode = StdinMode::Stdio;
+        config.stdout = interactor_stdin.into();
+        self.run(config)
+    }
+    /// 通过 SandboxConfig 在沙箱里启动命令，不等待其结束
+    ///
+    /// 限制在后台线程中执行，可以通过返回的 `RunHandle` 查询运行中的资源占用
+    ///
+    /// 设置了 `setup_command` 时会先等待其运行完成
+    pub fn spawn(&self, mut config: SandboxConfig) -> Result<RunHandle<'_>, SandboxError> {
+        config.command = config.command.with_prefix(&self.command_prefix);
+        config.validate()?;
+        if let Some(budget) = self.cpu_budget {
+            let total_cpu_time = self.total_cpu_time();
+            if total_cpu_time >= budget {
+                log::error!("CPU budget {:?} exhausted", budget);
+                return Err(SandboxError::CpuBudgetExhausted(total_cpu_time));
+            }
+        }
+
+        let mut setup_time = std::time::Duration::from_millis(0);
+        if let Some(setup_command) = &config.setup_command {
+            let setup_start = std::time::Instant::now();
+            // 准备命令的资源占用不计入结果和累计 CPU 时间
+            let setup_config = SandboxConfig::new(
+                SETUP_TIME_LIMIT,
+                SETUP_MEMORY_LIMIT,
+                SETUP_PIDS_LIMIT,
+                setup_command,
+                Stdio::null(),
+                Stdio::null(),
+                Stdio::null(),
+            );
+            let status = self
+                .spawn_command(setup_config, Default::default(), Default::default())?
+                .join()?;
+            setup_time = setup_start.elapsed();
+            if status.status != SandboxStatusKind::Success {
+                log::error!("Setup command failed: {:?}", status);
+                return Err(SandboxError::SetupFailed(Box::new(status)));
+            }
+        }
+
+        self.spawn_command(config, self.total_cpu_time.clone(), setup_time)
+    }
+    /// 在沙箱里启动命令，CPU 时间累计到 `total_cpu_time`
+    ///
+    /// `setup_time` 为准备命令所用的时间，记录在结果的 `Overhead` 中
+    fn spawn_command(
+        &self,
+        config: SandboxConfig,
+        total_cpu_time: std::sync::Arc<std::sync::atomic::AtomicU64>,
+        setup_time: std::time::Duration,
+    ) -> Result<RunHandle<'_>, SandboxError> {
+        use cgroups_fs::CgroupsCommandExt;
+        use std::time::{Duration, Instant};
+
+        // Init
+        let spawn_start = Instant::now();
+        let deadline = config.max_run_duration.map(|max| Instant::now() + max);
+        let run_id = uuid::Uuid::new_v4().to_string();
+        let (cgroup, io_device) = self.prepare_cgroup(&config, &run_id)?;
+        let wall_time_limit =
+            Duration::from_millis(config.wall_time_limit.unwrap_or(config.time_limit + 500));
+        let output_limit = config.output_limit;
+        let (output, stdout) = match config.stdout_stream {
+            Some(sender) => {
+                let (read, write) = if config.stdout_tty {
+                    output::pty()?
+                } else {
+                    output::pipe()?
+                };
+                (Some((read, sender)), Stdio::from(write))
+            }
+            None => (None, config.stdout),
+        };
+
+        let (ready, mut ready_write) = output::pipe()?;
+        // (写入端, 要写入的数据)，只在需要沙箱提供 stdin 管道时存在
+        // 使用伪终端时 stdin 由终端提供
+        let stdin_mode = match config.tty {
+            Some(_) => StdinMode::Stdio,
+            None => config.stdin_mode,
+        };
+        let (stdin, stdin_pipe) = match stdin_mode {
+            StdinMode::Stdio => (config.stdin, None),
+            StdinMode::Closed => (Stdio::null(), None),
+            StdinMode::Bytes(bytes) => {
+                let (read, write) = output::pipe()?;
+                (Stdio::from(read), Some((write, Some(bytes))))
+            }
+            StdinMode::Open => {
+                let (read, write) = output::pipe()?;
+                (Stdio::from(read), Some((write, None)))
+            }
+        };
+        let (tty, stdin, stdout, stderr) = match config.tty {
+            Some(size) => {
+                let (master, slave) = output::terminal(size)?;
+                (
+                    Some(master),
+                    Stdio::from(slave.try_clone()?),
+                    Stdio::from(slave.try_clone()?),
+                    Stdio::from(slave),
+                )
+            }
+            None => (None, stdin, stdout, config.stderr),
+        };
+
+        let sandbox_process = nix::unistd::getpid();
+        match nix::unistd::fork()? {
+            nix::unistd::ForkResult::Child => {
+                log::trace!("[{}] forked!", run_id);
+                // 沙箱所在的进程已经退出时打开失败，此时只等待程序结束
+                let sandbox_process = backend::pidfd_open(sandbox_process);
+                nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWPID).unwrap();
+                // 关闭读取端，读取线程停止读取后程序的写入会马上失败
+                drop(output);
+                drop(ready);
+                drop(stdin_pipe);
+                let controlling_terminal = tty.is_some();
+                drop(tty);
+                // 先 fork 出的进程成为新 PID namespace 中的 1 号进程，负责回收孤儿进程
+                let init = match nix::unistd::fork() {
+                    Ok(nix::unistd::ForkResult::Child) => run_init(),
+                    Ok(nix::unistd::ForkResult::Parent { child }) => child,
+                    Err(err) => {
+                        log::error!("[{}] Failed to fork init: {}", run_id, err);
+                        let err = err
+                            .as_errno()
+                            .map_or_else(|| std::io::Error::other(err), std::io::Error::from);
+                        backend::report_failure(&mut ready_write, &err);
+                        std::process::exit(1);
+                    }
+                };
+                // Create Child
+                let mut command = match &config.command {
+                    SandboxCommand::Shell(shell) => {
+                        let mut command = std::process::Command::new("bash");
+                        command.args(["-c", shell]);
+                        command
+                    }
+                    SandboxCommand::Argv { program, argv } => {
+                        let mut command = std::process::Command::new(program);
+                        if let Some((arg0, args)) = argv.split_first() {
+                            command.arg0(arg0).args(args);
+                        }
+                        command
+                    }
+                };
+                command
+                    .current_dir(&self.sandbox_directory)
+                    .cgroups(&cgroup.all());
+                SandboxCommandExt::chroot(&mut command, &self.sandbox_directory);
+                if controlling_terminal {
+                    command.controlling_terminal();
+                }
+                if let Some(nice) = config.nice {
+                    command.nice(nice);
+                }
+                if let Some(file_size_limit) = config.file_size_limit {
+                    command.file_size_limit(file_size_limit);
+                }
+                if let Some(child_setup) = config.child_setup {
+                    command.child_setup(child_setup);
+                }
+                if config.drop_capabilities {
+                    command.drop_capabilities();
+                }
+                if config.uid.is_some() || config.gid.is_some() {
+                    command.drop_privileges(
+                        config.uid,
+                        config.gid,
+                        config.supplementary_groups.clone(),
+                    );
+                }
+                if config.no_new_privs {
+                    command.no_new_privs();
+                }
+                // 指令计数器在启动程序前打开，由程序及其 fork 出的进程继承
+                let counter = if config.count_instructions || config.instruction_limit.is_some() {
+                    match perf::InstructionCounter::open()
+                        .and_then(|counter| counter.enable().map(|_| counter))
+                    {
+                        Ok(counter) => Some(counter),
+                        Err(err) => {
+                            log::warn!("[{}] Failed to count instructions: {}", run_id, err);
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                let child_exec = command.stdin(stdin).stdout(stdout).stderr(stderr).spawn();
+                // 通知父进程程序已经进入 cgroup（或启动失败的原因），沙箱自身的问题
+                // 只通过这个管道和日志报告，不会写入程序的 stderr
+                backend::report_ready(&mut ready_write, &child_exec);
+                let mut child_exec = match child_exec {
+                    Ok(child_exec) => child_exec,
+                    Err(err) => {
+                        log::error!("[{}] Failed to spawn command: {}", run_id, err);
+                        std::process::exit(1);
+                    }
+                };
+
+                let instruction_limit = config.instruction_limit;
+                let budget = counter
+                    .as_ref()
+                    .and_then(|counter| Some((counter, instruction_limit?)));
+                let waited = wait_program(&run_id, &mut child_exec, sandbox_process, budget, init);
+                let exit_status = match waited {
+                    Ok((status, exceeded)) => {
+                        if let Some(counter) = &counter {
+                            match counter.read() {
+                                Ok(count) => {
+                                    backend::report_instructions(&mut ready_write, count, exceeded)
+                                }
+                                Err(err) => {
+                                    log::warn!("[{}] Failed to read instructions: {}", run_id, err)
+                                }
+                            }
+                        }
+                        status
+                    }
+                    Err(err) => {
+                        log::error!("[{}] Failed to wait for command: {}", run_id, err);
+                        backend::report_failure(&mut ready_write, &err);
+                        nix::sys::signal::kill(init, nix::sys::signal::Signal::SIGKILL).ok();
+                        std::process::exit(1);
+                    }
+                };
+                log::debug!("[{}] forked: {:?}", run_id, exit_status);
+                // 1 号进程退出后内核会杀死 namespace 中留在后台的进程
+                nix::sys::signal::kill(init, nix::sys::signal::Signal::SIGKILL).ok();
+                nix::sys::wait::waitpid(init, None).ok();
+                // 以相同的信号结束自身，使父进程能看到程序的结束方式
+                if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&exit_status) {
+                    reraise_signal(signal);
+                }
+                std::process::exit(exit_status.code().unwrap_or(-1));
+            }
+            nix::unistd::ForkResult::Parent { child, .. } => {
+                drop(stdin);
+                let stdin = match stdin_pipe {
+                    Some((pipe, Some(bytes))) => {
+                        output::feed(&run_id, pipe, bytes);
+                        None
+                    }
+                    Some((pipe, None)) => Some(pipe),
+                    None => None,
+                };
+                RunHandle::new(
+                    run::Supervisor {
+                        // 父进程中的写入端 `stdout` 在这里关闭
+                        output: output.map(|(pipe, sender)| {
+                            drop(stdout);
+                            output::OutputReader::start(&run_id, pipe, output_limit, Some(sender))
+                        }),
+                        run_id,
+                        backend: backend::CgroupBackend {
+                            child,
+                            cgroup: std::sync::Arc::new(cgroup),
+                            ready: {
+                                drop(ready_write);
+                                ready
+                            },
+                            pidfd: backend::pidfd_open(child),
+                            child_exited: Default::default(),
+                            use_freezer: config.use_freezer_on_kill,
+                            rusage: Default::default(),
+                        },
+                        cancelled: Default::default(),
+                        pause: Default::default(),
+                        time_limit: config.time_limit,
+                        wall_time_limit,
+                        memory_limit: config.memory_limit,
+                        legacy_memory_multiplier: config.legacy_memory_multiplier,
+                        memory_accounting: config.memory_accounting,
+                        timeout_signal: config.timeout_signal,
+                        oom_behavior: config.oom_behavior,
+                        verdict_policy: config.verdict_policy,
+                        thread_limit: config.thread_limit,
+                        fail_on_leftover_tasks: config.fail_on_leftover_tasks,
+                        collect_detailed_stats: config.collect_detailed_stats,
+                        output_drain_grace: config.output_drain_grace,
+                        cpu_time_source: config.cpu_time_source,
+                        resource_stream: config.resource_stream,
+                        io_device,
+                        deadline,
+                        total_cpu_time,
+                        spawn_start,
+                        setup_time,
+                    },
+                    self.running.clone(),
+                )
+                .map(|handle| handle.with_stdin(stdin).with_tty(tty))
+            }
+        }
+    }
+    /// 卸载沙箱
+    ///
+    /// 可以重复调用，已卸载时直接返回 `Unmount::Clean`。
+    /// 挂载点忙（EBUSY）时会退而使用 `MNT_DETACH` 延迟卸载，并返回 `Unmount::Detached`；
+    /// 卸载失败时返回错误，沙箱仍视为已挂载，可以稍后重试
+    pub fn remove(&mut self) -> Result<Unmount, SandboxError> {
+        let mut result = Unmount::Clean;
+        if self.mounted {
+            log::info!("Remove sandbox on {:?}", &self);
+            result = result.max(builder::umount_all(&mut self.mounts)?);
+            result = result.max(umount(&self.sandbox_directory)?);
+            self.mounted = false;
+            self.shared_rootfs = None;
+            self.mountpoint = None;
+        }
+        if self.tmpfs {
+            result = result.max(umount(&self.work_directory)?);
+            self.tmpfs = false;
+        }
+        self.permit = None;
+        Ok(result)
+    }
+    /// 放弃沙箱但保留挂载，用于事后检查沙箱中的文件（core dump、输出等）
+    ///
+    /// 返回的 `LeakedSandbox` drop 时不会卸载任何东西，需要调用 `cleanup()` 或手动卸载。
+    /// 快照仍会被删除
+    pub fn leak(mut self) -> LeakedSandbox {
+        log::warn!("Leak sandbox on {:?}", &self.sandbox_directory);
+        let leaked = LeakedSandbox {
+            sandbox_directory: self.sandbox_directory.clone(),
+            work_directory: self.work_directory.clone(),
+            upper_directory: self.upper_directory.clone(),
+            mounts: std::mem::take(&mut self.mounts),
+            owned_directory: self.owned_directory.take(),
+            directory_fds: self.directory_fds.take(),
+            mountpoint: self.mountpoint.take(),
+            permit: self.permit.take(),
+            mounted: self.mounted,
+            tmpfs: self.tmpfs,
+            shared_rootfs: self.shared_rootfs.take(),
+        };
         self.mounted = false;
+        self.tmpfs = false;
+        leaked
+    }
+}
+
+/// 通过 `Sandbox::leak()` 保留下来的挂载
+///
+/// drop 时不会卸载，需要调用 `cleanup()`
+#[derive(Debug)]
+#[must_use = "the sandbox stays mounted until cleanup() is called"]
+pub struct LeakedSandbox {
+    /// Sandbox 的挂载点
+    pub sandbox_directory: std::path::PathBuf,
+    /// Sandbox 的 work 目录
+    pub work_directory: std::path::PathBuf,
+    /// overlay 的 upper 目录，沙箱中写入的文件都在这里
+    pub upper_directory: std::path::PathBuf,
+    /// overlay 之上额外的挂载点
+    mounts: Vec<std::path::PathBuf>,
+    /// 清理后删除的目录
+    owned_directory: Option<std::path::PathBuf>,
+    /// 清理前 `work_directory` 可能依赖这些 fd
+    directory_fds: Option<std::sync::Arc<builder::DirectoryFds>>,
+    /// 清理前挂载点仍被占用
+    mountpoint: Option<MountpointGuard>,
+    /// 清理前仍占用 `ConcurrencyLimit` 的名额
+    permit: Option<ConcurrencyPermit>,
+    mounted: bool,
+    tmpfs: bool,
+    /// 共享的 rootfs 在清理前不能卸载
+    shared_rootfs: Option<std::sync::Arc<rootfs::SharedMount>>,
+}
+
+impl LeakedSandbox {
+    /// 卸载保留下来的沙箱，规则与 `Sandbox::remove()` 相同
+    pub fn cleanup(mut self) -> Result<Unmount, SandboxError> {
+        let mut result = Unmount::Clean;
+        if self.mounted {
+            result = result.max(builder::umount_all(&mut self.mounts)?);
+            result = result.max(umount(&self.sandbox_directory)?);
+            self.mounted = false;
+            self.shared_rootfs = None;
+            self.mountpoint = None;
+        }
+        if self.tmpfs {
+            result = result.max(umount(&self.work_directory)?);
+            self.tmpfs = false;
+        }
+        if let Some(directory) = self.owned_directory.take() {
+            builder::remove_owned_directory(&directory);
+        }
+        drop(self.directory_fds.take());
+        self.permit = None;
+        Ok(result)
+    }
+}
+
+/// 检查目录两两不同且互不嵌套，比较前先规范化路径
+///
+/// 同时按 inode（设备号和 inode 号）比较，bind mount 或别名得到的同一个目录即使路径不同，
+/// 也会被当作相同的目录
+fn check_overlapping(directories: &[&std::path::PathBuf]) -> Result<(), SandboxError> {
+    use std::os::unix::fs::MetadataExt;
+    let canonical = directories
+        .iter()
+        .map(|directory| directory.canonicalize())
+        .collect::<Result<Vec<_>, _>>()?;
+    let inode = |path: &std::path::Path| {
+        std::fs::metadata(path).map(|metadata| (metadata.dev(), metadata.ino()))
+    };
+    // 每个目录自身及其所有上级目录的 inode
+    let ancestors = canonical
+        .iter()
+        .map(|directory| directory.ancestors().map(inode).collect())
+        .collect::<Result<Vec<Vec<_>>, _>>()?;
+    for (i, a) in canonical.iter().enumerate() {
+        for (j, b) in canonical.iter().enumerate() {
+            if i != j && (a.starts_with(b) || ancestors[i].contains(&ancestors[j][0])) {
+                let err =
+                    SandboxError::OverlappingPaths(directories[i].clone(), directories[j].clone());
+                log::error!("{}", err);
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// overlay 的 lower 目录列表，上层在前，rootfs 在最下层
+fn overlay_lower_directories(
+    rootfs_directory: &std::path::Path,
+    lower_directories: &[std::path::PathBuf],
+) -> Vec<std::path::PathBuf> {
+    lower_directories
+        .iter()
+        .rev()
+        .cloned()
+        .chain(std::iter::once(rootfs_directory.to_path_buf()))
+        .collect()
+}
+
+/// 以 `lower_dirs`（上层在前）为 lower 目录挂载 overlay
+fn mount_overlay(
+    lower_dirs: &[std::path::PathBuf],
+    upper_directory: &std::path::Path,
+    overlay_work_directory: &std::path::Path,
+    sandbox_directory: &std::path::Path,
+) -> Result<(), SandboxError> {
+    let lower_dirs = lower_dirs.to_vec();
+    let upper_directory = upper_directory.to_path_buf();
+    let overlay_work_directory = overlay_work_directory.to_path_buf();
+    let target = sandbox_directory.to_path_buf();
+    with_mount_timeout(sandbox_directory, move || {
+        libmount::Overlay::writable(
+            lower_dirs.iter().map(|x| x.as_ref()),
+            &upper_directory,
+            &overlay_work_directory,
+            &target,
+        )
+        .mount()
+        .map_err(|err| err.to_string())
+    })?
+    .map_err(SandboxError::Mount)
+}
+
+/// 挂载和卸载的超时时间
+const MOUNT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// 在单独的线程中执行对 `directory` 的挂载或卸载 `f`，超过 `MOUNT_TIMEOUT` 时返回
+/// `SandboxError::MountTimeout`
+///
+/// 文件系统卡住时无法取消 `f`，执行它的线程会一直留在后台
+pub(crate) fn with_mount_timeout<F, T>(directory: &std::path::Path, f: F) -> Result<T, SandboxError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name(String::from("nova-sandbox-mount"))
+        .spawn(move || sender.send(f()).ok())?;
+    receiver.recv_timeout(MOUNT_TIMEOUT).map_err(|_| {
+        log::error!("Timeout while (u)mounting {:?}", directory);
+        SandboxError::MountTimeout(directory.to_path_buf())
+    })
+}
+
+/// 删除 `directory` 中除 `keep`（相对于 `directory` 的路径）以外的所有内容，不跟随符号链接
+///
+/// `keep` 中路径的上级目录本身会被保留，但其中的其他内容仍会被删除
+fn clear_directory(
+    directory: &std::path::Path,
+    keep: &[&std::path::Path],
+) -> Result<(), SandboxError> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let path = entry.path();
+        let nested = keep
+            .iter()
+            .filter_map(|kept| kept.strip_prefix(&name).ok())
+            .collect::<Vec<_>>();
+        if nested.iter().any(|rest| rest.as_os_str().is_empty()) {
+            continue;
+        }
+        // `DirEntry::file_type()` 不跟随符号链接
+        if entry.file_type()?.is_dir() {
+            if nested.is_empty() {
+                std::fs::remove_dir_all(&path)?;
+            } else {
+                clear_directory(&path, &nested)?;
+            }
+        } else {
+            std::fs::remove_file(&path)?;
+        }
     }
+
+    Ok(())
+}
+
+/// 将 `from` 中的内容复制到 `to`
+///
+/// 使用 `cp -a`，以保留 overlay 用来表示删除的 whiteout 文件和 xattr
+fn copy_directory(from: &std::path::Path, to: &std::path::Path) -> Result<(), SandboxError> {
+    let status = std::process::Command::new("cp")
+        .arg("-a")
+        .arg("--")
+        .arg(from.join("."))
+        .arg(to)
+        .status()?;
+    if !status.success() {
+        log::error!("Failed to copy {:?} to {:?}: {}", from, to, status);
+        return Err(SandboxError::Io(std::io::Error::other(format!(
+            "cp exited with {}",
+            status
+        ))));
+    }
+
+    Ok(())
+}
+
+/// `Sandbox::reset_with()` 处理可写层的方式
+///
+/// 三种策略都需要重新挂载 overlay，区别在于对 upper 目录的操作
+///
+/// 开销主要来自清空 upper 目录，与其中的文件数量成正比。在 ext4 上，
+/// upper 目录中有 1 MiB 的 `/main` 和 N 个临时小文件时，每次重置的耗时（取中位数）：
+///
+/// | N     | `Wipe`  | `Keep([/main])` | `Snapshot`（快照中只有 `/main`） |
+/// |-------|---------|-----------------|----------------------------------|
+/// | 100   | ~8 ms   | ~8 ms           | ~10 ms                           |
+/// | 1000  | ~58 ms  | ~57 ms          | ~66 ms                           |
+/// | 10000 | ~630 ms | ~540 ms         | ~600 ms                          |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResetStrategy {
+    /// 删除 upper 目录中的所有文件
+    Wipe,
+    /// 删除 upper 目录中除这些路径（沙箱中的路径，如 `/main`）以外的所有文件
+    ///
+    /// 适合编译一次、运行多个测试点的情况：保留编译产物，只删除程序写入的临时文件。
+    /// 开销与 `Wipe` 基本相同，不需要像 `Snapshot` 那样复制文件。
+    /// 保留的是这些路径在 upper 目录中的状态：目录会连同其中的内容一起保留，
+    /// 没有被写入过（仍来自 rootfs）的路径不受影响
+    Keep(Vec<std::path::PathBuf>),
+    /// 清空 upper 目录后复制 `snapshot()` 保存的内容，没有快照时返回错误
+    ///
+    /// 在 `Wipe` 的基础上每次还要复制快照中的所有文件（通过 `cp -a`），
+    /// 额外的开销与快照的大小成正比，但可以恢复快照之后被删除或修改的文件
+    Snapshot,
+}
+
+/// `Sandbox::remove()` 卸载的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Unmount {
+    /// 正常卸载
+    Clean,
+    /// 挂载点忙，使用了延迟卸载，挂载点会在不再被使用后被内核移除
+    Detached,
+}
+
+/// `SandboxConfig::legacy_memory_multiplier` 的默认值
+const LEGACY_MEMORY_MULTIPLIER: bool = true;
+
+/// 是否已经记录过 `legacy_memory_multiplier` 的弃用警告，每个进程只记录一次
+static LEGACY_MEMORY_WARNED: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+/// 当前进程中正在被沙箱使用的挂载点（规范化后的路径）
+static MOUNTPOINTS: std::sync::Mutex<Vec<std::path::PathBuf>> = std::sync::Mutex::new(Vec::new());
+
+/// 对一个挂载点的占用，drop 时释放
+#[derive(Debug)]
+struct MountpointGuard(std::path::PathBuf);
+
+impl MountpointGuard {
+    /// 占用 `directory`，已被当前进程中的另一个沙箱占用时返回 `SandboxError::MountpointInUse`
+    fn acquire(directory: &std::path::Path) -> Result<MountpointGuard, SandboxError> {
+        let canonical = directory.canonicalize()?;
+        let mut mountpoints = MOUNTPOINTS.lock().unwrap_or_else(|err| err.into_inner());
+        if mountpoints.contains(&canonical) {
+            log::error!("{:?} is used by another sandbox", directory);
+            return Err(SandboxError::MountpointInUse(directory.to_path_buf()));
+        }
+        mountpoints.push(canonical.clone());
+        Ok(MountpointGuard(canonical))
+    }
+}
+
+impl Drop for MountpointGuard {
+    fn drop(&mut self) {
+        let mut mountpoints = MOUNTPOINTS.lock().unwrap_or_else(|err| err.into_inner());
+        mountpoints.retain(|directory| directory != &self.0);
+    }
+}
+
+/// 挂载点上已经有挂载（例如之前崩溃的评测留下的）时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StaleMount {
+    /// 返回 `SandboxError::AlreadyMounted`
+    #[default]
+    Refuse,
+    /// 先卸载遗留的挂载，再挂载沙箱
+    Reclaim,
+}
+
+/// 检查 `directory` 上是否已有挂载，按 `mode` 处理
+fn check_stale_mount(directory: &std::path::Path, mode: StaleMount) -> Result<(), SandboxError> {
+    // 遗留的挂载可能层层叠加
+    while is_mountpoint(directory)? {
+        match mode {
+            StaleMount::Refuse => {
+                log::error!("{:?} is already a mountpoint", directory);
+                return Err(SandboxError::AlreadyMounted(directory.to_path_buf()));
+            }
+            StaleMount::Reclaim => {
+                log::warn!("Reclaim stale mount on {:?}", directory);
+                umount(directory)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 根据 `/proc/self/mountinfo` 判断 `directory` 是不是挂载点
+fn is_mountpoint(directory: &std::path::Path) -> Result<bool, SandboxError> {
+    let directory = directory.canonicalize()?;
+    let mountinfo = std::fs::read("/proc/self/mountinfo")?;
+    Ok(mountinfo
+        .split(|byte| *byte == b'\n')
+        .filter_map(|line| line.split(|byte| *byte == b' ').nth(4))
+        .any(|mountpoint| {
+            use std::os::unix::ffi::OsStrExt;
+            std::ffi::OsStr::from_bytes(&unescape_mountinfo(mountpoint)) == directory.as_os_str()
+        }))
+}
+
+/// 还原 mountinfo 中用 `\ooo` 转义的空白和反斜杠
+fn unescape_mountinfo(field: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let escaped = field.get(i + 1..i + 4).filter(|digits| {
+            field[i] == b'\\' && digits.iter().all(|digit| (b'0'..=b'7').contains(digit))
+        });
+        match escaped {
+            Some(digits) => {
+                result.push(
+                    digits
+                        .iter()
+                        .fold(0u8, |value, digit| value * 8 + (digit - b'0')),
+                );
+                i += 4;
+            }
+            None => {
+                result.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+/// 卸载 `directory`，EBUSY 时使用延迟卸载
+///
+/// 超时时同样使用延迟卸载
+pub(crate) fn umount(directory: &std::path::Path) -> Result<Unmount, SandboxError> {
+    use nix::mount::{umount2, MntFlags};
+    let target = directory.to_path_buf();
+    let umounted = with_mount_timeout(directory, move || nix::mount::umount(&target));
+    match umounted {
+        Ok(Ok(())) => Ok(Unmount::Clean),
+        Ok(Err(nix::Error::Sys(nix::errno::Errno::EBUSY))) | Err(SandboxError::MountTimeout(_)) => {
+            log::warn!("{:?} is busy or stuck, fall back to lazy umount", directory);
+            umount2(directory, MntFlags::MNT_DETACH).map_err(|err| {
+                log::error!("Failed to lazy umount {:?}: {}", directory, err);
+                SandboxError::Mount(format!("{:?}: {}", directory, err))
+            })?;
+            Ok(Unmount::Detached)
+        }
+        Ok(Err(err)) => {
+            log::error!("Failed to umount {:?}: {}", directory, err);
+            Err(SandboxError::Mount(format!("{:?}: {}", directory, err)))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// 卸载 `directory`，失败时只记录日志
+pub(crate) fn umount_or_log(directory: &std::path::Path) {
+    umount(directory).ok();
+}
+
+/// PID namespace 中的 1 号进程，只负责回收被过继给它的孤儿进程，直到被杀死
+///
+/// 忽略 SIGCHLD 时内核会自动回收子进程，不会留下僵尸进程占用 Pid 限制。
+/// 创建它的进程退出时它也会被杀死
+fn run_init() -> ! {
+    use nix::sys::signal::{signal, SigHandler, Signal};
+    let parent = nix::unistd::getppid();
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        signal(Signal::SIGCHLD, SigHandler::SigIgn).ok();
+    }
+    // 设置 PR_SET_PDEATHSIG 之前父进程可能已经退出
+    if nix::unistd::getppid() != parent {
+        std::process::exit(0);
+    }
+    // 不持有程序的 stdin/stdout 管道和启动通知管道，以免对端等不到 EOF
+    let fds: Vec<i32> = std::fs::read_dir("/proc/self/fd")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .filter(|fd| *fd > 2)
+                .collect()
+        })
+        .unwrap_or_default();
+    for fd in fds {
+        nix::unistd::close(fd).ok();
+    }
+    loop {
+        nix::unistd::pause();
+    }
+}
+
+/// 在 fork 出的中间进程中等待程序结束
+///
+/// 时间限制只由父进程监视，超时后父进程杀死整个 cgroup，这里不再有自己的超时，
+/// 以免两个超时互相竞争。只有沙箱所在的进程（`sandbox_process` 的 pidfd）意外退出、
+/// 没有人再负责杀死程序时，才在这里杀死程序。内核不支持 pidfd 时只等待程序结束。
+///
+/// 设置了指令数上限 `budget` 时每 10ms 检查一次计数器，超过时杀死 PID namespace 的
+/// 1 号进程 `init`（内核随之杀死 namespace 中的所有进程），返回值中的 bool 为 true
+fn wait_program(
+    run_id: &str,
+    child: &mut std::process::Child,
+    sandbox_process: Option<std::os::unix::io::OwnedFd>,
+    budget: Option<(&perf::InstructionCounter, u64)>,
+    init: nix::unistd::Pid,
+) -> std::io::Result<(std::process::ExitStatus, bool)> {
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::os::unix::io::AsRawFd;
+    let program = backend::pidfd_open(nix::unistd::Pid::from_raw(child.id() as i32));
+    // 只有能同时等待程序结束时才监视沙箱所在的进程
+    let mut fds = match (&program, &sandbox_process) {
+        (Some(program), Some(sandbox_process)) => vec![
+            PollFd::new(program.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(sandbox_process.as_raw_fd(), PollFlags::POLLIN),
+        ],
+        _ => Vec::new(),
+    };
+    if fds.is_empty() && budget.is_none() {
+        // `wait()` 在 EINTR 时自动重试
+        return Ok((child.wait()?, false));
+    }
+    let timeout = if budget.is_some() { 10 } else { -1 };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+        match poll(&mut fds, timeout) {
+            Ok(_) => {}
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(err) => return Err(std::io::Error::other(err)),
+        }
+        let readable = |fd: &PollFd| fd.revents().is_some_and(|revents| !revents.is_empty());
+        if fds.len() == 2 && readable(&fds[1]) && !readable(&fds[0]) {
+            log::warn!("[{}] Sandbox process exited, kill the command", run_id);
+            // 程序恰好在此时退出、还没有被回收时 kill 同样成功
+            child.kill()?;
+            return Ok((child.wait()?, false));
+        }
+        if let Some((counter, limit)) = budget {
+            if counter.read()? > limit {
+                log::info!("[{}] Instruction limit exceeded", run_id);
+                nix::sys::signal::kill(init, nix::sys::signal::Signal::SIGKILL).ok();
+                child.kill().ok();
+                return Ok((child.wait()?, true));
+            }
+        }
+    }
+}
+
+/// 用 `signal` 结束当前进程
+///
+/// 只在 fork 出的子进程中使用，先关闭 core dump 以免在主机上留下文件
+fn reraise_signal(signal: i32) {
+    use nix::sys::signal::{raise, sigprocmask, SigHandler, SigSet, SigmaskHow, Signal};
+    use std::convert::TryFrom;
+    if let Ok(signal) = Signal::try_from(signal) {
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        let mut mask = SigSet::empty();
+        mask.add(signal);
+        unsafe {
+            libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+            nix::sys::signal::signal(signal, SigHandler::SigDfl).ok();
+        }
+        sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&mask), None).ok();
+        raise(signal).ok();
+    }
+}
+
+/// 获取目录所在块设备的 `major:minor`
+///
+/// 目录不在块设备上（如 tmpfs）时返回 None
+fn backing_device(directory: &std::path::Path) -> Option<(u64, u64)> {
+    use nix::sys::stat::{major, minor, stat};
+    let dev = match stat(directory) {
+        Ok(file_stat) => file_stat.st_dev,
+        Err(err) => {
+            log::warn!(
+                "Failed to stat {:?}: {}, io_limit is ignored",
+                directory,
+                err
+            );
+            return None;
+        }
+    };
+    if major(dev) == 0 {
+        log::warn!(
+            "{:?} is not on a block device, io_limit is ignored",
+            directory
+        );
+        return None;
+    }
+    Some((major(dev), minor(dev)))
 }
 
 impl Drop for Sandbox {
     fn drop(&mut self) {
         log::debug!("DROP {:?}", self);
-        self.remove();
+        let removed = self.remove().is_ok();
+        if let Some(directory) = &self.snapshot_directory {
+            std::fs::remove_dir_all(directory)
+                .unwrap_or_else(|err| log::error!("Failed to remove snapshot: {}", err));
+        }
+        // 卸载失败时目录里可能还有挂载，不能删除
+        if let (true, Some(directory)) = (removed, &self.owned_directory) {
+            builder::remove_owned_directory(directory);
+        }
     }
 }
 
 pub trait SandboxCommandExt {
-    fn chroot(&mut self, dir: String) -> &mut Self;
-    fn chdir(&mut self, dir: String) -> &mut Self;
+    fn chroot<P: AsRef<std::path::Path>>(&mut self, dir: P) -> &mut Self;
+    fn chdir<P: AsRef<std::path::Path>>(&mut self, dir: P) -> &mut Self;
+    fn drop_privileges(
+        &mut self,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        groups: Vec<u32>,
+    ) -> &mut Self;
+    fn nice(&mut self, nice: i32) -> &mut Self;
+    fn drop_capabilities(&mut self) -> &mut Self;
+    fn no_new_privs(&mut self) -> &mut Self;
+    fn file_size_limit(&mut self, limit: u64) -> &mut Self;
+    fn child_setup(&mut self, setup: ChildSetup) -> &mut Self;
+    fn controlling_terminal(&mut self) -> &mut Self;
 }
 
 impl SandboxCommandExt for std::process::Command {
     /// 用于 Command 执行前 Chroot 进入沙箱  
     /// 应该在所有需要修改/读取 sysfs/procfs 的函数之后使用
-    fn chroot(&mut self, dir: String) -> &mut Self {
-        use std::ffi::OsStr;
-        log::debug!("Chroot to {}", dir);
+    fn chroot<P: AsRef<std::path::Path>>(&mut self, dir: P) -> &mut Self {
+        let dir = dir.as_ref().to_path_buf();
+        log::debug!("Chroot to {:?}", dir);
         unsafe {
             self.pre_exec(move || {
-                nix::unistd::chroot(OsStr::new(&dir)).unwrap();
+                nix::unistd::chroot(&dir).unwrap();
                 Ok(())
             })
         }
     }
     /// 用于在 Chroot 之后确定目录  
     /// 应在 `SandboxCommandExt::chroot()` 后使用
-    fn chdir(&mut self, dir: String) -> &mut Self {
-        use std::ffi::OsStr;
+    fn chdir<P: AsRef<std::path::Path>>(&mut self, dir: P) -> &mut Self {
+        let dir = dir.as_ref().to_path_buf();
+        unsafe {
+            self.pre_exec(move || {
+                nix::unistd::chdir(&dir).unwrap();
+                Ok(())
+            })
+        }
+    }
+    /// 用于在 Chroot 之后切换用户、用户组和附加用户组
+    ///
+    /// 依次调用 `setgroups`、`setgid`、`setuid`：前两者需要 root 权限，必须在 `setuid` 之前，
+    /// 而 Chroot 同样需要 root 权限，所以应在 `SandboxCommandExt::chroot()` 后使用。
+    /// `groups` 为空时清除所有附加用户组
+    fn drop_privileges(
+        &mut self,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        groups: Vec<u32>,
+    ) -> &mut Self {
+        use nix::unistd::{setgid, setgroups, setuid, Gid, Uid};
+        log::debug!("Drop privileges to {:?}:{:?} ({:?})", uid, gid, groups);
+        let groups: Vec<Gid> = groups.into_iter().map(Gid::from_raw).collect();
+        unsafe {
+            self.pre_exec(move || {
+                setgroups(&groups).map_err(nix_to_io)?;
+                if let Some(gid) = gid {
+                    setgid(Gid::from_raw(gid)).map_err(nix_to_io)?;
+                }
+                if let Some(uid) = uid {
+                    setuid(Uid::from_raw(uid)).map_err(nix_to_io)?;
+                }
+                Ok(())
+            })
+        }
+    }
+    /// 用于设置命令的 nice 值
+    /// 设置负数需要 root 权限，应在 `SandboxCommandExt::drop_privileges()` 前使用
+    fn nice(&mut self, nice: i32) -> &mut Self {
+        unsafe {
+            self.pre_exec(move || {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        }
+    }
+    /// 用于让命令 exec 后不再拥有任何 capability
+    ///
+    /// 清空 bounding、ambient 和 inheritable 集合，exec 时内核不会再授予任何 capability，
+    /// 即使命令仍以 root 运行。当前的 effective 集合不变，所以之后的
+    /// `SandboxCommandExt::drop_privileges()` 仍能切换用户；
+    /// 清空 bounding 集合需要 `CAP_SETPCAP`，应在 `SandboxCommandExt::drop_privileges()` 前使用
+    fn drop_capabilities(&mut self) -> &mut Self {
+        unsafe {
+            self.pre_exec(|| {
+                let mut cap = 0;
+                // 超过内核支持的最大编号时返回 EINVAL
+                while libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) == 0 {
+                    cap += 1;
+                }
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EINVAL) {
+                    return Err(err);
+                }
+                // 旧内核不支持 ambient 集合
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_CLEAR_ALL,
+                    0,
+                    0,
+                    0,
+                );
+                let mut header = CapUserHeader {
+                    version: LINUX_CAPABILITY_VERSION_3,
+                    pid: 0,
+                };
+                let mut data = [CapUserData::default(); 2];
+                if libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                for data in data.iter_mut() {
+                    data.inheritable = 0;
+                }
+                if libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        }
+    }
+    /// 用于设置 `PR_SET_NO_NEW_PRIVS`，命令及其子进程无法通过 setuid/setgid 程序或
+    /// 文件 capability 获得更高的权限
+    fn no_new_privs(&mut self) -> &mut Self {
+        unsafe {
+            self.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        }
+    }
+    /// 用于设置 `RLIMIT_FSIZE`，写入超过 `limit` bytes 的文件时程序收到 SIGXFSZ
+    ///
+    /// 硬限制同样为 `limit`，放弃 capability 后程序无法调高
+    fn file_size_limit(&mut self, limit: u64) -> &mut Self {
+        let rlimit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
         unsafe {
             self.pre_exec(move || {
-                nix::unistd::chdir(OsStr::new(&dir)).unwrap();
+                if libc::setrlimit(libc::RLIMIT_FSIZE, &rlimit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        }
+    }
+    /// 用于在 exec 前执行 `setup`，执行的时机由注册的顺序决定，见 `ChildSetup`
+    fn child_setup(&mut self, setup: ChildSetup) -> &mut Self {
+        // pre_exec 需要 FnMut + Sync，闭包实际只会被调用一次
+        let setup = std::sync::Mutex::new(Some(setup.0));
+        unsafe {
+            self.pre_exec(move || match setup.lock() {
+                Ok(mut setup) => setup.take().map_or(Ok(()), |setup| setup()),
+                Err(_) => Ok(()),
+            })
+        }
+    }
+    /// 用于新建会话，并将 stdin 所在的终端设为控制终端
+    ///
+    /// 应在 stdin 已经是伪终端的 slave 时使用
+    fn controlling_terminal(&mut self) -> &mut Self {
+        unsafe {
+            self.pre_exec(|| {
+                nix::unistd::setsid().map_err(|_| std::io::Error::last_os_error())?;
+                if libc::ioctl(0, libc::TIOCSCTTY, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
                 Ok(())
             })
         }
     }
 }
+
+/// `capget`/`capset` 使用的 64 位 capability 接口版本
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// `struct __user_cap_header_struct`
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// `struct __user_cap_data_struct`，64 位接口中有两个，分别是低 32 位和高 32 位
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// 将 nix 的错误转换为 `std::io::Error`，用于 `pre_exec` 中
+fn nix_to_io(err: nix::Error) -> std::io::Error {
+    match err {
+        nix::Error::Sys(errno) => std::io::Error::from_raw_os_error(errno as i32),
+        err => std::io::Error::other(err),
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// 同时挂载的沙箱数量上限，超过时新的沙箱排队等待
+///
+/// 可以在多个线程间 clone 共用，通过 `SandboxBuilder::concurrency_limit()` 使用，
+/// 也可以单独调用 `acquire()` 限制其他操作。主机的挂载表和 cgroup 数量有限，
+/// 过多的沙箱同时运行会在挂载或创建 cgroup 时以难以理解的方式失败，排队可以避免这种情况
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// 剩余的名额
+    available: Mutex<usize>,
+    released: Condvar,
+    max: usize,
+}
+
+/// `ConcurrencyLimit` 的一个名额，drop 时归还
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    inner: Arc<Inner>,
+}
+
+impl ConcurrencyLimit {
+    /// 最多允许 `max` 个名额同时被占用，`max` 为 0 时按 1 处理
+    pub fn new(max: usize) -> ConcurrencyLimit {
+        let max = std::cmp::max(max, 1);
+        ConcurrencyLimit {
+            inner: Arc::new(Inner {
+                available: Mutex::new(max),
+                released: Condvar::new(),
+                max,
+            }),
+        }
+    }
+    /// 名额上限
+    pub fn max(&self) -> usize {
+        self.inner.max
+    }
+    /// 当前剩余的名额
+    pub fn available(&self) -> usize {
+        *self.lock()
+    }
+    /// 获取一个名额，没有剩余时阻塞直到有名额被归还
+    pub fn acquire(&self) -> ConcurrencyPermit {
+        let mut available = self.lock();
+        while *available == 0 {
+            available = self
+                .inner
+                .released
+                .wait(available)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+        *available -= 1;
+        self.permit()
+    }
+    /// 在 `timeout` 内获取一个名额，超时返回 None
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<ConcurrencyPermit> {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut available = self.lock();
+        while *available == 0 {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining == Duration::from_millis(0) {
+                return None;
+            }
+            available = self
+                .inner
+                .released
+                .wait_timeout(available, remaining)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+        *available -= 1;
+        Some(self.permit())
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, usize> {
+        self.inner
+            .available
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+    fn permit(&self) -> ConcurrencyPermit {
+        ConcurrencyPermit {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Default for ConcurrencyLimit {
+    /// 上限为主机的 CPU 数，更多的沙箱同时运行时会互相影响计时
+    fn default() -> ConcurrencyLimit {
+        ConcurrencyLimit::new(std::thread::available_parallelism().map_or(1, |cpus| cpus.get()))
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        *self
+            .inner
+            .available
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) += 1;
+        self.inner.released.notify_one();
+    }
+}
@@ -0,0 +1,278 @@
+use crate::TtySize;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// 每次从管道读取的最大字节数
+const CHUNK_SIZE: usize = 64 * 1024;
+/// 等待管道数据时检查是否结束的间隔（以 ms 为单位）
+const POLL_INTERVAL: i32 = 50;
+
+/// 在后台线程中读取程序的 stdout，转发给调用者并统计字节数
+///
+/// 转发使用无界的 channel，读取不会因为接收方处理慢而阻塞程序的输出
+#[derive(Debug)]
+pub(crate) struct OutputReader {
+    /// 输出超过了限制
+    exceeded: Arc<AtomicBool>,
+    /// 等待 EOF 超时，读完管道中剩余的数据后退出
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// 新建 `O_CLOEXEC` 的管道，返回（读取端，写入端）
+pub(crate) fn pipe() -> Result<(File, File), nix::Error> {
+    let (read, write) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+    // pipe2 返回的 fd 由 File 接管
+    Ok(unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) })
+}
+
+/// 新建一对伪终端，返回（master，slave），两端都设置了 `O_CLOEXEC`
+///
+/// slave 设置为 raw 模式，程序写入的 `\n` 不会被转换为 `\r\n`
+pub(crate) fn pty() -> Result<(File, File), nix::Error> {
+    use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+
+    let (master, slave) = openpty(None)?;
+    let mut termios = tcgetattr(slave.as_raw_fd())?;
+    cfmakeraw(&mut termios);
+    tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &termios)?;
+    Ok((master, slave))
+}
+
+/// 新建一对窗口大小为 `size` 的伪终端，返回（master，slave），见 `SandboxConfig::tty`
+///
+/// 与 `pty()` 不同，slave 保持默认的终端设置（回显、行编辑、`\n` 转换为 `\r\n` 等）
+pub(crate) fn terminal(size: TtySize) -> Result<(File, File), nix::Error> {
+    openpty(Some(&nix::pty::Winsize {
+        ws_row: size.rows,
+        ws_col: size.columns,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }))
+}
+
+fn openpty(size: Option<&nix::pty::Winsize>) -> Result<(File, File), nix::Error> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+    let pty = nix::pty::openpty(size, None)?;
+    // openpty 返回的 fd 由 File 接管，出错时也会被关闭
+    let (master, slave) = unsafe { (File::from_raw_fd(pty.master), File::from_raw_fd(pty.slave)) };
+    for fd in &[&master, &slave] {
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    Ok((master, slave))
+}
+
+/// 在后台线程中将 `bytes` 写入程序的 stdin 管道，写完后关闭
+///
+/// 程序结束后写入会失败（EPIPE），剩余的数据被丢弃
+pub(crate) fn feed(run_id: &str, mut pipe: File, bytes: Vec<u8>) {
+    let run_id = run_id.to_string();
+    let spawned = std::thread::Builder::new()
+        .name(String::from("nova-sandbox-stdin"))
+        .spawn(move || {
+            if let Err(err) = std::io::Write::write_all(&mut pipe, &bytes) {
+                log::debug!("[{}] Stopped writing stdin: {}", run_id, err);
+            }
+        });
+    if let Err(err) = spawned {
+        log::error!("Failed to spawn stdin writer thread: {}", err);
+    }
+}
+
+impl OutputReader {
+    /// 从 `pipe` 读取输出，总字节数超过 `limit` 时停止读取并关闭管道和 `sender`
+    pub(crate) fn start(
+        run_id: &str,
+        pipe: File,
+        limit: Option<u64>,
+        sender: Option<Sender<Vec<u8>>>,
+    ) -> OutputReader {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_exceeded, thread_stop, run_id) =
+            (exceeded.clone(), stop.clone(), run_id.to_string());
+        let thread = std::thread::Builder::new()
+            .name(String::from("nova-sandbox-output"))
+            .spawn(move || {
+                read_output(&run_id, pipe, limit, sender, &thread_exceeded, &thread_stop)
+            })
+            .expect("failed to spawn output reader thread");
+
+        OutputReader {
+            exceeded,
+            stop,
+            thread: Some(thread),
+        }
+    }
+    /// 输出是否已经超过了限制
+    pub(crate) fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Acquire)
+    }
+    /// 等待读取线程读到 EOF，返回输出是否超过了限制
+    ///
+    /// 其他进程可能继承了管道的写入端（例如同一进程中同时 fork 出的另一个沙箱），
+    /// 最多等待 `grace`，之后读完管道中已有的数据就停止
+    pub(crate) fn finish(mut self, grace: Duration) -> bool {
+        if let Some(thread) = self.thread.take() {
+            let deadline = Instant::now() + grace;
+            while !thread.is_finished() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            self.stop.store(true, Ordering::Release);
+            if thread.join().is_err() {
+                log::error!("Output reader thread panicked");
+            }
+        }
+        self.exceeded.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for OutputReader {
+    /// 没有调用 `finish()` 时（例如运行出错）也让读取线程退出
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+fn read_output(
+    run_id: &str,
+    mut pipe: File,
+    limit: Option<u64>,
+    mut sender: Option<Sender<Vec<u8>>>,
+    exceeded: &AtomicBool,
+    stop: &AtomicBool,
+) {
+    let mut total = 0u64;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let mut fds = [PollFd::new(pipe.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, POLL_INTERVAL) {
+            Ok(0) => {
+                if stop.load(Ordering::Acquire) {
+                    log::warn!(
+                        "[{}] Output pipe is still open after the run, stop reading",
+                        run_id
+                    );
+                    return;
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(err) => {
+                log::warn!("[{}] Failed to poll output: {}", run_id, err);
+                return;
+            }
+        }
+        let size = match pipe.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(size) => size,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            // 伪终端的 slave 全部关闭后读取 master 返回 EIO，相当于 EOF
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => return,
+            Err(err) => {
+                log::warn!("[{}] Failed to read output: {}", run_id, err);
+                return;
+            }
+        };
+        total += size as u64;
+        let size = match limit {
+            Some(limit) if total > limit => (size as u64 - (total - limit)) as usize,
+            _ => size,
+        };
+        // 接收方已经关闭时继续读取和统计，只是不再转发
+        if let Some(tx) = &sender {
+            if size > 0 && tx.send(buffer[..size].to_vec()).is_err() {
+                sender = None;
+            }
+        }
+        if limit.is_some_and(|limit| total > limit) {
+            log::info!("[{}] Output limit exceeded", run_id);
+            exceeded.store(true, Ordering::Release);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const GRACE: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn forward() {
+        let (read, mut write) = pipe().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reader = OutputReader::start("test", read, Some(10), Some(tx));
+        write.write_all(b"hello").unwrap();
+        drop(write);
+        assert!(!reader.finish(GRACE));
+        assert_eq!(rx.iter().flatten().collect::<Vec<_>>(), b"hello");
+    }
+
+    #[test]
+    fn limit_exceeded() {
+        let (read, mut write) = pipe().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reader = OutputReader::start("test", read, Some(3), Some(tx));
+        write.write_all(b"hello").unwrap();
+        while !reader.exceeded() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // 超过限制后管道被关闭
+        assert!(write.write_all(b"world").is_err());
+        assert!(reader.finish(GRACE));
+        // 只转发限制以内的部分
+        assert_eq!(rx.iter().flatten().collect::<Vec<_>>(), b"hel");
+    }
+
+    #[test]
+    fn pty_forward() {
+        let (master, mut slave) = pty().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reader = OutputReader::start("test", master, None, Some(tx));
+        slave.write_all(b"hello\nworld\n").unwrap();
+        drop(slave);
+        // 读到 EIO 后结束，换行没有被转换
+        assert_eq!(
+            rx.iter().flatten().collect::<Vec<_>>(),
+            b"hello\nworld\n".to_vec()
+        );
+        assert!(!reader.finish(GRACE));
+    }
+
+    #[test]
+    fn finish_without_eof() {
+        let (read, _write) = pipe().unwrap();
+        let reader = OutputReader::start("test", read, None, None);
+        let start = Instant::now();
+        assert!(!reader.finish(GRACE));
+        assert!(start.elapsed() >= GRACE);
+    }
+
+    #[test]
+    fn drain_to_eof() {
+        let (read, mut write) = pipe().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reader = OutputReader::start("test", read, None, Some(tx));
+        // 写入端在 finish() 之后、超过一次 poll 间隔才写完最后的数据
+        let writer = std::thread::spawn(move || {
+            write.write_all(&[b'a'; 100_000]).unwrap();
+            std::thread::sleep(Duration::from_millis(POLL_INTERVAL as u64 * 2));
+            write.write_all(&[b'b'; 100_000]).unwrap();
+        });
+        assert!(!reader.finish(Duration::from_secs(5)));
+        writer.join().unwrap();
+        assert_eq!(rx.iter().flatten().count(), 200_000);
+    }
+}
@@ -0,0 +1,147 @@
+use std::os::unix::io::OwnedFd;
+
+/// 程序执行的用户态指令数（`perf_event_open` 的 `PERF_COUNT_HW_INSTRUCTIONS`）
+///
+/// 在 fork 出的中间进程中打开，启动程序前开始计数。`total` 由之后 fork 出的所有子进程继承，
+/// 包括程序 fork 出的进程；`own` 只统计中间进程自身，两者相减即为程序的指令数。
+/// 只在开启 `perf` feature 时可用，否则 `open()` 总是失败
+#[derive(Debug)]
+pub(crate) struct InstructionCounter {
+    total: OwnedFd,
+    own: OwnedFd,
+}
+
+impl InstructionCounter {
+    /// 在当前进程上打开计数器（暂不计数），主机没有硬件计数器（如大多数虚拟机）时失败
+    pub(crate) fn open() -> std::io::Result<InstructionCounter> {
+        Ok(InstructionCounter {
+            total: sys::open(true)?,
+            own: sys::open(false)?,
+        })
+    }
+    /// 开始计数，应在启动程序前调用
+    pub(crate) fn enable(&self) -> std::io::Result<()> {
+        sys::enable(&self.own)?;
+        sys::enable(&self.total)
+    }
+    /// 到目前为止程序执行的指令数，包括已经退出的子进程
+    pub(crate) fn read(&self) -> std::io::Result<u64> {
+        let own = sys::read(&self.own)?;
+        Ok(sys::read(&self.total)?.saturating_sub(own))
+    }
+}
+
+#[cfg(feature = "perf")]
+mod sys {
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    const PERF_TYPE_HARDWARE: u32 = 0;
+    const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+    const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+    /// `_IO('$', 0)`
+    const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+
+    /// `perf_event_attr` 的第一个版本（`PERF_ATTR_SIZE_VER0`，64 字节），之后的字段都不需要
+    #[repr(C)]
+    #[derive(Default)]
+    struct PerfEventAttr {
+        kind: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        /// 位域，见 `DISABLED` 等常量
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        bp_addr: u64,
+    }
+
+    const DISABLED: u64 = 1 << 0;
+    const INHERIT: u64 = 1 << 1;
+    const EXCLUDE_KERNEL: u64 = 1 << 5;
+    const EXCLUDE_HV: u64 = 1 << 6;
+
+    /// 打开当前进程的用户态指令计数器，`inherit` 为 true 时之后 fork 出的子进程也计入
+    pub(super) fn open(inherit: bool) -> std::io::Result<OwnedFd> {
+        let mut flags = DISABLED | EXCLUDE_KERNEL | EXCLUDE_HV;
+        if inherit {
+            flags |= INHERIT;
+        }
+        let attr = PerfEventAttr {
+            kind: PERF_TYPE_HARDWARE,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config: PERF_COUNT_HW_INSTRUCTIONS,
+            flags,
+            ..Default::default()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                0 as libc::pid_t,
+                -1 as libc::c_int,
+                -1 as libc::c_int,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // 系统调用返回的 fd 由 OwnedFd 接管
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+    }
+
+    pub(super) fn enable(fd: &OwnedFd) -> std::io::Result<()> {
+        if unsafe { libc::ioctl(fd.as_raw_fd(), PERF_EVENT_IOC_ENABLE, 0) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub(super) fn read(fd: &OwnedFd) -> std::io::Result<u64> {
+        let mut count = 0u64;
+        let size = unsafe {
+            libc::read(
+                fd.as_raw_fd(),
+                &mut count as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if size < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if size as usize != std::mem::size_of::<u64>() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "short read from perf counter",
+            ));
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(not(feature = "perf"))]
+mod sys {
+    use std::os::unix::io::OwnedFd;
+
+    fn unsupported() -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "instruction counting needs the perf feature",
+        )
+    }
+
+    pub(super) fn open(_inherit: bool) -> std::io::Result<OwnedFd> {
+        Err(unsupported())
+    }
+
+    pub(super) fn enable(_fd: &OwnedFd) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub(super) fn read(_fd: &OwnedFd) -> std::io::Result<u64> {
+        Err(unsupported())
+    }
+}
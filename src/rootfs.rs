@@ -0,0 +1,86 @@
+use crate::{Sandbox, SandboxError};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// 只读挂载一次、可以被多个沙箱共用的 rootfs
+///
+/// 每个沙箱仍有自己的 upper/work 目录，只是共用同一个 lower 目录。
+/// 最后一个使用它的沙箱（以及 `SharedRootfs` 本身）drop 后才会卸载
+#[derive(Debug, Clone)]
+pub struct SharedRootfs {
+    mount: Arc<SharedMount>,
+}
+
+/// 共享 rootfs 的只读挂载点，drop 时卸载
+#[derive(Debug)]
+pub(crate) struct SharedMount {
+    directory: PathBuf,
+}
+
+impl SharedRootfs {
+    /// 将 `rootfs_directory` 只读 bind mount 到 `mount_directory`
+    pub fn new<T, U>(rootfs_directory: T, mount_directory: U) -> Result<SharedRootfs, SandboxError>
+    where
+        T: AsRef<Path>,
+        U: AsRef<Path>,
+    {
+        let rootfs_directory = rootfs_directory.as_ref();
+        let mount_directory = mount_directory.as_ref();
+        for directory in [rootfs_directory, mount_directory].iter() {
+            if !directory.exists() {
+                log::error!("{:?} Not Found!", directory);
+                return Err(SandboxError::DirectoryNotFound(directory.to_path_buf()));
+            }
+        }
+
+        let (source, target) = (
+            rootfs_directory.to_path_buf(),
+            mount_directory.to_path_buf(),
+        );
+        crate::with_mount_timeout(mount_directory, move || {
+            libmount::BindMount::new(&source, &target)
+                .readonly(true)
+                .mount()
+                .map_err(|err| err.to_string())
+        })?
+        .map_err(SandboxError::Mount)?;
+        log::info!(
+            "Mount shared rootfs {:?} on {:?}",
+            rootfs_directory,
+            mount_directory
+        );
+
+        Ok(SharedRootfs {
+            mount: Arc::new(SharedMount {
+                directory: mount_directory.to_path_buf(),
+            }),
+        })
+    }
+    /// 只读挂载点
+    pub fn directory(&self) -> &Path {
+        &self.mount.directory
+    }
+    /// 以共享的 rootfs 为 lower 目录新建沙箱
+    ///
+    /// 参数含义见 [Sandbox](struct.Sandbox.html)
+    pub fn sandbox<T, U>(
+        &self,
+        work_directory: T,
+        sandbox_directory: U,
+    ) -> Result<Sandbox, SandboxError>
+    where
+        T: AsRef<Path>,
+        U: AsRef<Path>,
+    {
+        let mut sandbox = Sandbox::new(&self.mount.directory, work_directory, sandbox_directory)?;
+        sandbox.shared_rootfs = Some(self.mount.clone());
+        Ok(sandbox)
+    }
+}
+
+impl Drop for SharedMount {
+    fn drop(&mut self) {
+        log::info!("Umount shared rootfs on {:?}", &self.directory);
+        crate::umount_or_log(&self.directory);
+    }
+}
@@ -0,0 +1,1534 @@
+use crate::backend::{CgroupBackend, Ready, SandboxBackend};
+use crate::cgroup::RunCgroup;
+use crate::output::OutputReader;
+use crate::{
+    CgroupReport, CpuTimeSource, InternalError, MemoryAccounting, MemoryCounter, OomBehavior,
+    Overhead, SandboxError, SandboxStatus, SandboxStatusKind, Termination, TimeDimension,
+    VerdictPolicy,
+};
+use nix::sys::signal::Signal;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 正在运行的命令
+///
+/// 由 `Sandbox::spawn()` 返回，限制由后台线程负责，调用 `join()` 获取最终结果。
+/// 在 `join()` 前 drop 会杀死 cgroup 内的所有进程
+#[derive(Debug)]
+pub struct RunHandle<'a> {
+    run_id: String,
+    cgroup: Arc<RunCgroup>,
+    cancelled: Arc<AtomicBool>,
+    pause: Arc<PauseClock>,
+    supervisor: Option<std::thread::JoinHandle<Result<SandboxStatus, SandboxError>>>,
+    /// `StdinMode::Open` 时程序 stdin 的写入端
+    stdin: Option<std::fs::File>,
+    /// 设置了 `SandboxConfig::tty` 时伪终端的 master 端
+    tty: Option<std::fs::File>,
+    /// 沙箱正在进行的运行数，见 `Sandbox::is_running()`
+    running: Arc<AtomicUsize>,
+    /// 运行期间沙箱不能被卸载
+    _sandbox: std::marker::PhantomData<&'a crate::Sandbox>,
+}
+
+impl<'a> RunHandle<'a> {
+    pub(crate) fn new(
+        supervisor: Supervisor<CgroupBackend>,
+        running: Arc<AtomicUsize>,
+    ) -> Result<RunHandle<'a>, SandboxError> {
+        let run_id = supervisor.run_id.clone();
+        let cgroup = supervisor.backend.cgroup.clone();
+        let cancelled = supervisor.cancelled.clone();
+        let pause = supervisor.pause.clone();
+        let supervisor = std::thread::Builder::new()
+            .name(String::from("nova-sandbox-supervisor"))
+            .spawn(move || supervisor.supervise())?;
+        running.fetch_add(1, Ordering::AcqRel);
+        Ok(RunHandle {
+            run_id,
+            cgroup,
+            cancelled,
+            pause,
+            supervisor: Some(supervisor),
+            stdin: None,
+            tty: None,
+            running,
+            _sandbox: std::marker::PhantomData,
+        })
+    }
+    pub(crate) fn with_stdin(mut self, stdin: Option<std::fs::File>) -> RunHandle<'a> {
+        self.stdin = stdin;
+        self
+    }
+    /// 取出程序 stdin 的写入端，只在 `StdinMode::Open` 时存在
+    ///
+    /// drop 写入端后程序读到 EOF；没有取出时写入端在 `join()` 时关闭
+    pub fn take_stdin(&mut self) -> Option<std::fs::File> {
+        self.stdin.take()
+    }
+    pub(crate) fn with_tty(mut self, tty: Option<std::fs::File>) -> RunHandle<'a> {
+        self.tty = tty;
+        self
+    }
+    /// 取出伪终端的 master 端，只在设置了 `SandboxConfig::tty` 时存在
+    ///
+    /// 读取得到程序的输出，写入作为程序的输入；程序结束后读取返回 EIO
+    pub fn take_tty(&mut self) -> Option<std::fs::File> {
+        self.tty.take()
+    }
+    /// 这次运行的 ID，与日志中的 ID 相同
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+    /// 这次运行使用的 cgroup 在每个 controller 下的目录，用于排查问题
+    pub fn cgroup_paths(&self) -> Vec<std::path::PathBuf> {
+        self.cgroup.paths()
+    }
+    /// 获取到目前为止消耗的 CPU 时间
+    pub fn cpu_time(&self) -> Result<Duration, SandboxError> {
+        self.cgroup.get_cpu_time()
+    }
+    /// 获取当前的内存占用（以 bytes 为单位）
+    pub fn current_memory(&self) -> Result<u64, SandboxError> {
+        self.cgroup.get_current_memory()
+    }
+    /// 获取到目前为止的内存峰值（memsw，以 bytes 为单位）
+    pub fn max_memory(&self) -> Result<u64, SandboxError> {
+        self.cgroup.get_max_memory()
+    }
+    /// 通过 freezer 暂停程序，直到调用 `resume()`，已经暂停时什么也不做
+    ///
+    /// 暂停期间程序不消耗 CPU 时间，经过的时间也不计入 `wall_time_limit` 和 `wall_time`，
+    /// 但仍计入 `max_run_duration`。暂停期间 `join()` 不会返回，除非 `RunHandle` 被 drop
+    /// 或超过 `max_run_duration`，此时程序照常被杀死。程序在 1s 内没有全部暂停时恢复运行并返回
+    /// `SandboxError::FreezeTimeout`
+    pub fn pause(&self) -> Result<(), SandboxError> {
+        self.pause.pause(|| self.cgroup.freeze(FREEZE_TIMEOUT))
+    }
+    /// 恢复被 `pause()` 暂停的程序，没有暂停时什么也不做
+    pub fn resume(&self) -> Result<(), SandboxError> {
+        self.pause.resume(|| self.cgroup.thaw())
+    }
+    /// 返回一个可以发送到其他线程的 `RunMonitor`，用于在 `join()` 时查询资源占用
+    pub fn monitor(&self) -> RunMonitor {
+        RunMonitor {
+            cgroup: self.cgroup.clone(),
+            pause: self.pause.clone(),
+        }
+    }
+    /// 等待运行结束，返回运行状态
+    pub fn join(mut self) -> Result<SandboxStatus, SandboxError> {
+        self.stdin = None;
+        match self.supervisor.take().unwrap().join() {
+            Ok(result) => result,
+            Err(_) => Err(SandboxError::Internal(InternalError::SupervisorPanicked)),
+        }
+    }
+}
+
+impl Drop for RunHandle<'_> {
+    fn drop(&mut self) {
+        if let Some(supervisor) = self.supervisor.take() {
+            log::info!(
+                "[{}] RunHandle dropped before join, kill all tasks",
+                self.run_id
+            );
+            self.cancelled.store(true, Ordering::Release);
+            supervisor.join().ok();
+        }
+        self.running.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// 用于在其他线程查询运行中的资源占用
+#[derive(Debug, Clone)]
+pub struct RunMonitor {
+    cgroup: Arc<RunCgroup>,
+    pause: Arc<PauseClock>,
+}
+
+impl RunMonitor {
+    /// 暂停程序，同 `RunHandle::pause()`，可以在另一个线程 `join()` 时使用
+    pub fn pause(&self) -> Result<(), SandboxError> {
+        self.pause.pause(|| self.cgroup.freeze(FREEZE_TIMEOUT))
+    }
+    /// 恢复被暂停的程序，同 `RunHandle::resume()`
+    pub fn resume(&self) -> Result<(), SandboxError> {
+        self.pause.resume(|| self.cgroup.thaw())
+    }
+    /// 获取到目前为止消耗的 CPU 时间
+    pub fn cpu_time(&self) -> Result<Duration, SandboxError> {
+        self.cgroup.get_cpu_time()
+    }
+    /// 获取当前的内存占用（以 bytes 为单位）
+    pub fn current_memory(&self) -> Result<u64, SandboxError> {
+        self.cgroup.get_current_memory()
+    }
+    /// 获取到目前为止的内存峰值（memsw，以 bytes 为单位）
+    pub fn max_memory(&self) -> Result<u64, SandboxError> {
+        self.cgroup.get_max_memory()
+    }
+}
+
+/// 运行期间的一次资源占用采样，见 `SandboxConfig::resource_stream`
+///
+/// 读取失败的数值为 None
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceSnapshot {
+    /// 程序开始运行后经过的时间
+    pub elapsed: Duration,
+    /// 到目前为止消耗的 CPU 时间
+    pub cpu_time: Option<Duration>,
+    /// 到目前为止的内存峰值（以 bytes 为单位）
+    pub max_memory: Option<u64>,
+    /// 到目前为止的最大进程（线程）数
+    pub max_pids: Option<u64>,
+}
+
+/// `SandboxConfig::resource_stream` 中的一项
+#[derive(Debug, Clone)]
+pub enum ResourceEvent {
+    /// 运行期间的一次采样
+    Snapshot(ResourceSnapshot),
+    /// 运行结束，包含与 `join()` 相同的结果，是 channel 关闭前的最后一项
+    Finished(Box<SandboxStatus>),
+}
+
+/// 等待 `RunHandle::pause()` 暂停所有进程的最长时间
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 程序被 `RunHandle::pause()` 暂停的时间，监视线程计算实际运行时间时减去
+#[derive(Debug, Default)]
+pub(crate) struct PauseClock {
+    state: std::sync::Mutex<PauseState>,
+}
+
+#[derive(Debug, Default)]
+struct PauseState {
+    /// 正在进行的暂停开始的时间
+    since: Option<Instant>,
+    /// 已经结束的暂停的总时间
+    total: Duration,
+}
+
+impl PauseClock {
+    fn lock(&self) -> std::sync::MutexGuard<'_, PauseState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+    /// 没有暂停时调用 `freeze`，成功后开始计时
+    pub(crate) fn pause<F>(&self, freeze: F) -> Result<(), SandboxError>
+    where
+        F: FnOnce() -> Result<(), SandboxError>,
+    {
+        let mut state = self.lock();
+        if state.since.is_none() {
+            freeze()?;
+            state.since = Some(Instant::now());
+        }
+        Ok(())
+    }
+    /// 正在暂停时调用 `thaw`，成功后停止计时
+    pub(crate) fn resume<F>(&self, thaw: F) -> Result<(), SandboxError>
+    where
+        F: FnOnce() -> Result<(), SandboxError>,
+    {
+        let mut state = self.lock();
+        if let Some(since) = state.since {
+            thaw()?;
+            state.total += since.elapsed();
+            state.since = None;
+        }
+        Ok(())
+    }
+    /// 到目前为止暂停的总时间，包括正在进行的暂停
+    pub(crate) fn paused(&self) -> Duration {
+        let state = self.lock();
+        state.total
+            + state
+                .since
+                .map_or(Duration::from_millis(0), |since| since.elapsed())
+    }
+}
+
+/// 在后台线程中监视 fork 出的子进程，负责超时、清理和统计
+pub(crate) struct Supervisor<B: SandboxBackend> {
+    /// 这次运行的 ID，会出现在所有日志中
+    pub(crate) run_id: String,
+    pub(crate) backend: B,
+    pub(crate) cancelled: Arc<AtomicBool>,
+    pub(crate) pause: Arc<PauseClock>,
+    /// 时间限制（以 ms 为单位）
+    pub(crate) time_limit: u64,
+    /// 实际运行时间的上限，见 `SandboxConfig::wall_time_limit`
+    pub(crate) wall_time_limit: Duration,
+    /// 内存限制（以 bytes 为单位）
+    pub(crate) memory_limit: u64,
+    /// cgroup 的限制是 `memory_limit` 的 4 倍，否则达到 `memory_limit` 即为超限
+    pub(crate) legacy_memory_multiplier: bool,
+    pub(crate) memory_accounting: MemoryAccounting,
+    pub(crate) timeout_signal: nix::sys::signal::Signal,
+    pub(crate) oom_behavior: OomBehavior,
+    pub(crate) verdict_policy: VerdictPolicy,
+    pub(crate) thread_limit: Option<u32>,
+    /// 运行结束后无法清理 cgroup 内的进程时返回错误，而不只是记录日志
+    pub(crate) fail_on_leftover_tasks: bool,
+    pub(crate) collect_detailed_stats: bool,
+    pub(crate) cpu_time_source: CpuTimeSource,
+    /// 资源占用采样的接收方，运行结束时 drop
+    pub(crate) resource_stream: Option<std::sync::mpsc::Sender<ResourceEvent>>,
+    /// 读取 stdout 的线程，设置了 `stdout_stream` 时存在
+    pub(crate) output: Option<OutputReader>,
+    /// 运行结束后等待 stdout 读到 EOF 的最长时间
+    pub(crate) output_drain_grace: Duration,
+    pub(crate) io_device: Option<(u64, u64)>,
+    pub(crate) deadline: Option<Instant>,
+    /// 沙箱累计的 CPU 时间（以 ns 为单位），运行结束后加上这次的 CPU 时间
+    pub(crate) total_cpu_time: Arc<AtomicU64>,
+    /// 开始准备这次运行的时间
+    pub(crate) spawn_start: Instant,
+    /// 准备命令所用的时间
+    pub(crate) setup_time: Duration,
+}
+
+impl<B: SandboxBackend> Supervisor<B> {
+    pub(crate) fn supervise(mut self) -> Result<SandboxStatus, SandboxError> {
+        let output = self.output.take();
+        let mut resource_stream = self.resource_stream.take();
+        let backend = &self.backend;
+        let run_id = &self.run_id;
+        let deadline = self.deadline;
+        let past_deadline = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        let time_limit = Duration::from_millis(self.time_limit + 500);
+
+        // 按实际运行时间（不含暂停的时间）倒数，到 0 时无论 cgroup 的状态如何都判为超时
+        let mut timeout = self.wall_time_limit;
+        let delay = Duration::from_millis(100);
+        let zero_time = Duration::from_millis(0);
+        // 确认程序已经进入 cgroup 后才开始检查，否则可能把还没启动的程序当作已结束
+        match backend.wait_ready(READY_TIMEOUT)? {
+            Ready::Started => {}
+            Ready::Failed(err) => {
+                log::error!("[{}] Failed to start command: {}", run_id, err);
+                // 子进程报告后马上退出
+                if backend.wait_child(Duration::from_millis(1000))?.is_none() {
+                    backend.kill_child()?;
+                }
+                return Err(SandboxError::SpawnFailed(err));
+            }
+            Ready::Unknown => log::warn!("[{}] Command did not report ready", run_id),
+        }
+        let start = Instant::now();
+        let prepare = start.duration_since(self.spawn_start);
+
+        let read_peak = || {
+            best_effort(
+                run_id,
+                "max memory",
+                match self.memory_accounting {
+                    MemoryAccounting::MemswPeak => backend.max_memory(),
+                    MemoryAccounting::Rss => backend.max_rss().map(|rss| (rss, MemoryCounter::Rss)),
+                },
+            )
+        };
+        // 没有 cpuacct 时不读取 CPU 时间，以实际运行时间代替
+        let has_cpu_time = backend.has_cpu_time();
+        if !has_cpu_time {
+            log::warn!(
+                "[{}] cpuacct is not available, use wall time as used time",
+                run_id
+            );
+        }
+        let read_cpu_time = || {
+            if has_cpu_time {
+                best_effort(run_id, "cpu time", backend.cpu_time())
+            } else {
+                None
+            }
+        };
+        // 运行中每次检查都记录内存峰值，防止程序结束后计数器被清零或读取失败导致少算
+        let mut sampled_peak = None;
+
+        // Look up until timeout or no task in cgroup
+        let mut oom = false;
+        let mut threads_exceeded = false;
+        let mut output_exceeded = false;
+        while timeout > zero_time {
+            sampled_peak = higher_peak(sampled_peak, read_peak());
+            // 连续两次检查都为空才认为运行结束，防止程序 fork 时短暂出现的空 cgroup
+            if backend.is_empty()? && {
+                std::thread::sleep(Duration::from_millis(10));
+                backend.is_empty()?
+            } {
+                break;
+            }
+            let max_pids = best_effort(run_id, "pids", backend.max_pids());
+            if let Some(stream) = &resource_stream {
+                let snapshot = ResourceSnapshot {
+                    elapsed: start.elapsed(),
+                    cpu_time: read_cpu_time(),
+                    max_memory: sampled_peak.map(|(max_memory, _)| max_memory),
+                    max_pids,
+                };
+                // 接收方关闭后不再采样
+                if stream.send(ResourceEvent::Snapshot(snapshot)).is_err() {
+                    resource_stream = None;
+                }
+            }
+            if self.cancelled.load(Ordering::Acquire) {
+                timeout = zero_time;
+                break;
+            }
+            // 实际运行时间的上限较大时，CPU 时间超限的程序不用等到它
+            if self.cpu_time_source == CpuTimeSource::Cpuacct
+                && read_cpu_time().is_some_and(|cpu_time| cpu_time > time_limit)
+            {
+                log::info!("[{}] CPU time limit exceeded", run_id);
+                timeout = zero_time;
+                break;
+            }
+            if self.oom_behavior == OomBehavior::Pause && backend.under_oom()? {
+                log::info!("[{}] Memory limit reached, cgroup is paused", run_id);
+                oom = true;
+                break;
+            }
+            if let Some(thread_limit) = self.thread_limit {
+                let threads = backend.max_threads()?;
+                if threads > thread_limit {
+                    log::info!("[{}] Thread limit exceeded: {} threads", run_id, threads);
+                    threads_exceeded = true;
+                    break;
+                }
+            }
+            if output.as_ref().is_some_and(OutputReader::exceeded) {
+                output_exceeded = true;
+                break;
+            }
+            if past_deadline() {
+                log::error!("[{}] Run exceeded max_run_duration, abort", run_id);
+                backend.kill_child()?;
+                backend
+                    .kill_all_tasks(Duration::from_millis(1000))
+                    .unwrap_or_else(|err| {
+                        log::warn!("[{}] failed to kill all task in cgroup: {}", run_id, err);
+                    });
+                return Err(SandboxError::Internal(InternalError::Deadline));
+            }
+            // 程序结束时马上醒来，不用等满 delay
+            let wait_start = Instant::now();
+            let paused = self.pause.paused();
+            backend.wait_exit(std::cmp::min(delay, timeout))?;
+            let paused = self.pause.paused() - paused;
+            timeout = timeout.saturating_sub(wait_start.elapsed().saturating_sub(paused));
+            log::trace!("[{}] less time {:?}", run_id, timeout);
+        }
+
+        // 超时后先发送 timeout_signal，给程序一点时间自行结束
+        let cancelled = self.cancelled.load(Ordering::Acquire);
+        let timed_out = timeout == zero_time && !cancelled;
+        let mut kill_signal = None;
+        if timed_out {
+            kill_signal = Some(Signal::SIGKILL);
+            if self.timeout_signal != Signal::SIGKILL {
+                backend.signal_all_tasks(self.timeout_signal)?;
+                let mut grace = Duration::from_millis(500);
+                while grace > zero_time && !past_deadline() && !backend.is_empty()? {
+                    std::thread::sleep(delay);
+                    grace -= delay;
+                }
+                if backend.is_empty()? {
+                    kill_signal = Some(self.timeout_signal);
+                }
+            }
+        }
+
+        let violated = oom || threads_exceeded || output_exceeded;
+        if violated {
+            kill_signal = Some(Signal::SIGKILL);
+        }
+
+        // cgroup 已空时子进程马上就会退出，稍等一下；超时或违规时则直接检查
+        let exiting =
+            (timeout > zero_time && !violated) || kill_signal.is_some_and(|s| s != Signal::SIGKILL);
+        let grace = if exiting { delay } else { zero_time };
+        let termination = match backend.wait_child(grace)? {
+            Some(termination) => termination,
+            None => {
+                backend.kill_child()?;
+                Termination::Killed
+            }
+        };
+        log::trace!("[{}] main: {:?}", run_id, termination);
+        let wall_elapsed = start.elapsed().saturating_sub(self.pause.paused());
+        let wall_time = wall_elapsed.as_millis();
+        let teardown_start = Instant::now();
+
+        // 资源统计读取失败时只丢弃对应的数值，不影响结果
+        let cgroup_cpu_time = read_cpu_time();
+        let rusage = backend.rusage();
+        let rusage_cpu_time = rusage.map(|(user, system)| user + system);
+        if let (Some(cgroup), Some(rusage)) = (cgroup_cpu_time, rusage_cpu_time) {
+            if diverges(cgroup, rusage) {
+                log::warn!(
+                    "[{}] CPU time from cpuacct ({:?}) differs from rusage ({:?})",
+                    run_id,
+                    cgroup,
+                    rusage
+                );
+            }
+        }
+        let (cpu_time, has_cpu_time) = match self.cpu_time_source {
+            CpuTimeSource::Cpuacct => (cgroup_cpu_time, has_cpu_time),
+            CpuTimeSource::Rusage => {
+                if rusage_cpu_time.is_none() {
+                    log::warn!("[{}] rusage is not available, use cpuacct", run_id);
+                }
+                (
+                    rusage_cpu_time.or(cgroup_cpu_time),
+                    has_cpu_time || rusage_cpu_time.is_some(),
+                )
+            }
+        };
+        if let Some(cpu_time) = cpu_time {
+            self.total_cpu_time
+                .fetch_add(cpu_time.as_nanos() as u64, Ordering::AcqRel);
+        }
+        // 超时的运行同样报告实测的值，超时与否由 `time_exceeded` 判断
+        let used_time = if has_cpu_time {
+            cpu_time
+        } else {
+            Some(wall_elapsed)
+        };
+
+        let detailed_stats = if self.collect_detailed_stats {
+            best_effort(run_id, "cgroup report", backend.report()).map(|report| CgroupReport {
+                rusage_user: rusage.map(|(user, _)| user),
+                rusage_system: rusage.map(|(_, system)| system),
+                ..report
+            })
+        } else {
+            None
+        };
+
+        let kill_timeout = match deadline {
+            Some(deadline) => std::cmp::min(
+                Duration::from_millis(1000),
+                deadline.saturating_duration_since(Instant::now()),
+            ),
+            None => Duration::from_millis(1000),
+        };
+        if let Err(err) = backend.kill_all_tasks(kill_timeout) {
+            if self.fail_on_leftover_tasks {
+                log::error!("[{}] Task(s) left in cgroup after the run: {}", run_id, err);
+                return Err(err);
+            }
+            log::warn!("[{}] failed to kill all task in cgroup: {}", run_id, err);
+        }
+        // 程序结束前最后写入的部分也可能超过限制
+        if let Some(output) = output {
+            output_exceeded |= output.finish(self.output_drain_grace);
+        }
+        if past_deadline() {
+            log::error!(
+                "[{}] Run exceeded max_run_duration while cleaning up",
+                run_id
+            );
+            return Err(SandboxError::Internal(InternalError::Deadline));
+        }
+
+        // 子进程等待程序结束失败时，它的退出码不是程序的退出码
+        let instructions = match backend.instructions() {
+            Err(SandboxError::Internal(err)) => {
+                log::error!("[{}] {}", run_id, err);
+                return Err(SandboxError::Internal(err));
+            }
+            result => best_effort(run_id, "instructions", result).flatten(),
+        };
+
+        // Get return code
+        let return_code = match termination {
+            Termination::Exited(code) => code,
+            _ => -1,
+        };
+        // fork 失败通常是程序出错的原因
+        let pids_denied = return_code != 0
+            && best_effort(run_id, "pids events", backend.pids_denied())
+                .is_some_and(|denied| denied > 0);
+
+        // Calc Memory
+        let (max_memory, memory_counter) = match higher_peak(read_peak(), sampled_peak) {
+            Some((max_memory, counter)) => (Some(max_memory), Some(counter)),
+            None => (None, None),
+        };
+
+        // Calc time
+        let exceeded = |time: Option<Duration>| {
+            time.is_some_and(|time| time > Duration::from_millis(self.time_limit))
+        };
+        let time_exceeded = if exceeded(cpu_time) {
+            Some(TimeDimension::Cpu)
+        } else if timed_out || exceeded(used_time) {
+            Some(TimeDimension::Wall)
+        } else {
+            None
+        };
+        let status = verdict(
+            &Conditions {
+                termination,
+                time_exceeded,
+                memory_exceeded: oom
+                    || max_memory.is_some_and(|max_memory| {
+                        // cgroup 的限制就是 memory_limit 时，峰值不会超过它，达到即说明被限制了
+                        max_memory > self.memory_limit
+                            || (!self.legacy_memory_multiplier && max_memory >= self.memory_limit)
+                    }),
+                pids_denied,
+                threads_exceeded,
+                output_exceeded,
+                instructions_exceeded: instructions.is_some_and(|(_, exceeded)| exceeded),
+            },
+            self.verdict_policy,
+        );
+        let used_time_ns = used_time.map(|used_time| used_time.as_nanos());
+        let used_time = used_time.map(|used_time| used_time.as_millis());
+
+        let max_pids = best_effort(run_id, "pids", backend.max_pids());
+        let swap_used = best_effort(run_id, "swap usage", backend.swap_used()).flatten();
+        let io_stats = match self.io_device {
+            Some(device) => best_effort(run_id, "io stats", backend.io_stats(device)).flatten(),
+            None => None,
+        };
+
+        log::debug!(
+            "[{}] status: {:?}, used_time: {:?}, return_code: {}, max_memory: {:?}",
+            run_id,
+            status,
+            used_time,
+            return_code,
+            max_memory
+        );
+        let status = SandboxStatus {
+            status,
+            max_memory,
+            memory_counter,
+            swap_used,
+            used_time,
+            used_time_ns,
+            used_time_source: if has_cpu_time {
+                TimeDimension::Cpu
+            } else {
+                TimeDimension::Wall
+            },
+            wall_time,
+            return_code,
+            termination,
+            io_stats,
+            max_pids,
+            kill_signal,
+            instructions: instructions.map(|(count, _)| count),
+            detailed_stats,
+            overhead: Overhead {
+                setup_command: self.setup_time,
+                prepare,
+                teardown: teardown_start.elapsed(),
+            },
+            run_id: self.run_id.clone(),
+        };
+        if let Some(stream) = resource_stream {
+            stream
+                .send(ResourceEvent::Finished(Box::new(status.clone())))
+                .ok();
+        }
+        Ok(status)
+    }
+}
+
+/// 运行中检测到的情况，由 `verdict()` 组合成运行状态
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Conditions {
+    pub(crate) termination: Termination,
+    /// 超过了时间限制的种类
+    pub(crate) time_exceeded: Option<TimeDimension>,
+    pub(crate) memory_exceeded: bool,
+    /// 运行中有 fork 因达到 `pids_limit` 而失败
+    pub(crate) pids_denied: bool,
+    pub(crate) threads_exceeded: bool,
+    pub(crate) output_exceeded: bool,
+    /// 子进程因指令数超过 `instruction_limit` 杀死了程序
+    pub(crate) instructions_exceeded: bool,
+}
+
+/// 按 `policy` 组合运行中检测到的情况，得到最终的运行状态
+///
+/// 优先级为 output > thread > instruction > tle > mle > pids > re > success，
+/// 其中 tle、mle、re 和 success 由 `classify()` 决定
+pub(crate) fn verdict(conditions: &Conditions, policy: VerdictPolicy) -> SandboxStatusKind {
+    if conditions.output_exceeded {
+        return SandboxStatusKind::OutputLimitExceeded;
+    }
+    if conditions.threads_exceeded {
+        return SandboxStatusKind::ThreadLimitExceeded;
+    }
+    if conditions.instructions_exceeded {
+        return SandboxStatusKind::InstructionLimitExceeded;
+    }
+    match classify(
+        conditions.termination,
+        conditions.memory_exceeded,
+        conditions.time_exceeded,
+        policy,
+    ) {
+        SandboxStatusKind::RuntimeError if conditions.pids_denied => {
+            SandboxStatusKind::PidsLimitExceeded
+        }
+        kind => kind,
+    }
+}
+
+/// 由程序的结束方式、是否超过内存限制和是否超时得到运行状态，沙箱的判定规则以此为准
+///
+/// `memory_exceeded` 为 cgroup 的内存统计达到了限制（包括被 OOM killer 杀死），
+/// `time_exceeded` 为超时的种类，没有超时时为 None。按顺序：
+///
+/// - 超时时为 `TimeLimitExceeded`，与结束方式无关（超时后程序被沙箱杀死，为 `Termination::Killed`）
+/// - 超过内存限制时为 `MemoryLimitExceeded`。`VerdictPolicy::ReOverMle` 时，
+///   如果程序自行出错（返回值不为 0，或被 SIGKILL 以外的信号终止，如 SIGSEGV），改为 `RuntimeError`；
+///   被 SIGKILL 终止（通常是 OOM killer）或正常退出时仍为 `MemoryLimitExceeded`
+/// - 返回值为 0 时为 `Success`
+/// - 其余情况（返回值不为 0、被任意信号终止）为 `RuntimeError`
+///
+/// 输出、线程数和 Pid 限制由沙箱另外判断，不在这里处理
+pub fn classify(
+    termination: Termination,
+    memory_exceeded: bool,
+    time_exceeded: Option<TimeDimension>,
+    policy: VerdictPolicy,
+) -> SandboxStatusKind {
+    let failed_by_itself = match termination {
+        Termination::Exited(code) => code != 0,
+        Termination::Signaled(signal) => signal != Signal::SIGKILL,
+        Termination::Killed => false,
+    };
+    if let Some(kind) = time_exceeded {
+        SandboxStatusKind::TimeLimitExceeded { kind }
+    } else if memory_exceeded && !(policy == VerdictPolicy::ReOverMle && failed_by_itself) {
+        SandboxStatusKind::MemoryLimitExceeded
+    } else if termination == Termination::Exited(0) {
+        SandboxStatusKind::Success
+    } else {
+        SandboxStatusKind::RuntimeError
+    }
+}
+
+/// 返回两次内存峰值读取中较大的一个
+fn higher_peak(
+    a: Option<(u64, MemoryCounter)>,
+    b: Option<(u64, MemoryCounter)>,
+) -> Option<(u64, MemoryCounter)> {
+    std::cmp::max_by_key(a, b, |peak| peak.map(|(value, _)| value))
+}
+
+/// 等待程序进入 cgroup 的最长时间
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// cpuacct 与 rusage 的 CPU 时间相差超过 20ms 且超过 10%
+///
+/// 两者的精度和统计范围不同（cpuacct 包括后台进程，rusage 包括沙箱在子进程中的开销），
+/// 小的差别是正常的
+fn diverges(cgroup: Duration, rusage: Duration) -> bool {
+    let difference = cgroup.abs_diff(rusage);
+    difference > Duration::from_millis(20) && difference > std::cmp::max(cgroup, rusage) / 10
+}
+
+/// 读取失败时记录日志并返回 None
+fn best_effort<T>(run_id: &str, name: &str, result: Result<T, SandboxError>) -> Option<T> {
+    result
+        .map_err(|err| log::warn!("[{}] Failed to read {}: {}", run_id, name, err))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::{MockBackend, MockLog, MockProgram};
+    use crate::TaskState;
+
+    fn supervise(program: MockProgram) -> (Result<SandboxStatus, SandboxError>, MockLog) {
+        supervise_with(program, |_| {})
+    }
+
+    fn supervise_with<F>(
+        program: MockProgram,
+        f: F,
+    ) -> (Result<SandboxStatus, SandboxError>, MockLog)
+    where
+        F: FnOnce(&mut Supervisor<MockBackend>),
+    {
+        let (backend, log) = MockBackend::new(program);
+        let mut supervisor = Supervisor {
+            run_id: String::from("test"),
+            backend,
+            cancelled: Default::default(),
+            pause: Default::default(),
+            time_limit: 100,
+            wall_time_limit: Duration::from_millis(600),
+            memory_limit: 1024 * 1024,
+            legacy_memory_multiplier: true,
+            memory_accounting: MemoryAccounting::MemswPeak,
+            timeout_signal: Signal::SIGKILL,
+            oom_behavior: OomBehavior::Kill,
+            verdict_policy: VerdictPolicy::MleOverRe,
+            thread_limit: None,
+            fail_on_leftover_tasks: false,
+            collect_detailed_stats: false,
+            cpu_time_source: CpuTimeSource::Cpuacct,
+            resource_stream: None,
+            output: None,
+            output_drain_grace: Duration::from_millis(200),
+            io_device: None,
+            deadline: None,
+            total_cpu_time: Default::default(),
+            spawn_start: Instant::now(),
+            setup_time: Duration::from_millis(0),
+        };
+        f(&mut supervisor);
+        let result = supervisor.supervise();
+        let log = std::mem::take(&mut *log.lock().unwrap());
+        (result, log)
+    }
+
+    #[test]
+    fn success() {
+        let (result, log) = supervise(MockProgram::default());
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::Success);
+        assert_eq!(status.termination, Termination::Exited(0));
+        assert_eq!(status.run_id, "test");
+        assert_eq!(status.kill_signal, None);
+        assert!(!log.child_killed);
+    }
+
+    #[test]
+    fn wall_time_limit() {
+        let program = || MockProgram {
+            exit_after: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let (result, log) = supervise_with(program(), |supervisor| {
+            supervisor.wall_time_limit = Duration::from_millis(100)
+        });
+        let status = result.unwrap();
+        assert_eq!(
+            status.status,
+            SandboxStatusKind::TimeLimitExceeded {
+                kind: TimeDimension::Wall
+            }
+        );
+        assert!(status.wall_time < 300);
+        // 报告实测的 CPU 时间，而不是时间限制
+        assert_eq!(status.used_time, Some(10));
+        assert!(log.tasks_killed);
+        // 实际运行时间的上限比时间限制更宽时，只等待不占用 CPU 的程序不超时
+        let (result, _) = supervise_with(program(), |supervisor| {
+            supervisor.time_limit = 10;
+            supervisor.wall_time_limit = Duration::from_millis(1000)
+        });
+        assert_eq!(result.unwrap().status, SandboxStatusKind::Success);
+    }
+
+    #[test]
+    fn used_time_ns() {
+        let (result, _) = supervise(MockProgram {
+            exit_after: Some(Duration::from_millis(50)),
+            cpu_time: Duration::from_micros(12_345),
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.used_time_ns, Some(12_345_000));
+        assert_eq!(status.used_time, Some(12));
+    }
+
+    #[test]
+    fn detailed_stats() {
+        let program = MockProgram {
+            pids: 3,
+            ..Default::default()
+        };
+        let (result, _) = supervise(program.clone());
+        assert!(result.unwrap().detailed_stats.is_none());
+        let (result, _) = supervise_with(program, |supervisor| {
+            supervisor.collect_detailed_stats = true
+        });
+        assert_eq!(result.unwrap().detailed_stats.unwrap().pids_peak, Some(3));
+    }
+
+    #[test]
+    fn wakes_on_exit() {
+        let (result, _) = supervise(MockProgram {
+            exit_after: Some(Duration::from_millis(30)),
+            ..Default::default()
+        });
+        // 不用等满一次检查的间隔（100ms）
+        assert!(result.unwrap().wall_time < 100);
+    }
+
+    #[test]
+    fn swap_used() {
+        let (result, _) = supervise(MockProgram {
+            swap_used: true,
+            ..Default::default()
+        });
+        assert_eq!(result.unwrap().swap_used, Some(true));
+        let (result, _) = supervise(MockProgram {
+            memsw_unavailable: true,
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.swap_used, None);
+        assert_eq!(status.memory_counter, Some(MemoryCounter::Usage));
+    }
+
+    #[test]
+    fn classify_mapping() {
+        use SandboxStatusKind::*;
+        use Signal::*;
+        let policy = VerdictPolicy::MleOverRe;
+        let tle = TimeLimitExceeded {
+            kind: TimeDimension::Cpu,
+        };
+        let cases = [
+            (Termination::Exited(0), false, None, Success),
+            (Termination::Exited(1), false, None, RuntimeError),
+            (Termination::Signaled(SIGSEGV), false, None, RuntimeError),
+            // 不是沙箱发出的 SIGKILL
+            (Termination::Signaled(SIGKILL), false, None, RuntimeError),
+            (Termination::Killed, false, Some(TimeDimension::Cpu), tle),
+            (Termination::Killed, true, Some(TimeDimension::Cpu), tle),
+            (Termination::Exited(0), false, Some(TimeDimension::Cpu), tle),
+            (
+                Termination::Signaled(SIGKILL),
+                true,
+                None,
+                MemoryLimitExceeded,
+            ),
+            (
+                Termination::Signaled(SIGSEGV),
+                true,
+                None,
+                MemoryLimitExceeded,
+            ),
+            (Termination::Exited(0), true, None, MemoryLimitExceeded),
+        ];
+        for (termination, memory_exceeded, time_exceeded, expected) in cases.iter() {
+            assert_eq!(
+                classify(*termination, *memory_exceeded, *time_exceeded, policy),
+                *expected,
+                "{:?}, memory_exceeded: {}, time_exceeded: {:?}",
+                termination,
+                memory_exceeded,
+                time_exceeded
+            );
+        }
+    }
+
+    #[test]
+    fn verdict_policy() {
+        let conditions = |termination| Conditions {
+            termination,
+            time_exceeded: None,
+            memory_exceeded: true,
+            pids_denied: false,
+            threads_exceeded: false,
+            output_exceeded: false,
+            instructions_exceeded: false,
+        };
+        let segfault = conditions(Termination::Signaled(Signal::SIGSEGV));
+        assert_eq!(
+            verdict(&segfault, VerdictPolicy::MleOverRe),
+            SandboxStatusKind::MemoryLimitExceeded
+        );
+        assert_eq!(
+            verdict(&segfault, VerdictPolicy::ReOverMle),
+            SandboxStatusKind::RuntimeError
+        );
+        // 被 OOM killer 杀死
+        let oom_killed = conditions(Termination::Signaled(Signal::SIGKILL));
+        assert_eq!(
+            verdict(&oom_killed, VerdictPolicy::ReOverMle),
+            SandboxStatusKind::MemoryLimitExceeded
+        );
+        let exited = conditions(Termination::Exited(0));
+        assert_eq!(
+            verdict(&exited, VerdictPolicy::ReOverMle),
+            SandboxStatusKind::MemoryLimitExceeded
+        );
+        // 超时始终优先
+        let timed_out = Conditions {
+            time_exceeded: Some(TimeDimension::Cpu),
+            ..segfault
+        };
+        assert_eq!(
+            verdict(&timed_out, VerdictPolicy::ReOverMle),
+            SandboxStatusKind::TimeLimitExceeded {
+                kind: TimeDimension::Cpu
+            }
+        );
+    }
+
+    #[test]
+    fn no_cpuacct() {
+        let (result, _) = supervise_with(
+            MockProgram {
+                exit_after: Some(Duration::from_millis(50)),
+                no_cpuacct: true,
+                ..Default::default()
+            },
+            |supervisor| supervisor.time_limit = 1000,
+        );
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::Success);
+        assert_eq!(status.used_time_source, TimeDimension::Wall);
+        assert!(status.used_time.unwrap() >= 50);
+
+        // 只能按实际运行时间判断超时
+        let (result, _) = supervise(MockProgram {
+            exit_after: Some(Duration::from_millis(300)),
+            no_cpuacct: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            result.unwrap().status,
+            SandboxStatusKind::TimeLimitExceeded {
+                kind: TimeDimension::Wall
+            }
+        );
+    }
+
+    #[test]
+    fn total_cpu_time() {
+        let total_cpu_time = Arc::new(AtomicU64::new(5));
+        let (result, _) = supervise_with(MockProgram::default(), |supervisor| {
+            supervisor.total_cpu_time = total_cpu_time.clone();
+        });
+        let cpu_time = Duration::from_millis(result.unwrap().used_time.unwrap() as u64);
+        assert!(total_cpu_time.load(Ordering::Acquire) >= cpu_time.as_nanos() as u64 + 5);
+    }
+
+    #[test]
+    fn runtime_error() {
+        let (result, _) = supervise(MockProgram {
+            termination: Termination::Exited(1),
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::RuntimeError);
+        assert_eq!(status.return_code, 1);
+    }
+
+    #[test]
+    fn rust_panic() {
+        // panic 的 Rust 程序以 101 退出，与其他非 0 退出码相同
+        let (result, _) = supervise(MockProgram {
+            termination: Termination::Exited(101),
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::RuntimeError);
+        assert_eq!(status.return_code, 101);
+    }
+
+    #[test]
+    fn wait_failed() {
+        let (result, _) = supervise(MockProgram {
+            termination: Termination::Exited(1),
+            wait_error: Some(nix::libc::ECHILD),
+            ..Default::default()
+        });
+        match result {
+            Err(SandboxError::Internal(InternalError::WaitFailed(err))) => {
+                assert_eq!(err.raw_os_error(), Some(nix::libc::ECHILD))
+            }
+            other => panic!("Wrong result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn instruction_limit() {
+        let (result, _) = supervise(MockProgram {
+            instructions: Some((12345, false)),
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::Success);
+        assert_eq!(status.instructions, Some(12345));
+
+        // 子进程杀死程序后，程序看起来是被 SIGKILL 杀死的
+        let (result, _) = supervise(MockProgram {
+            termination: Termination::Signaled(Signal::SIGKILL),
+            instructions: Some((23456, true)),
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::InstructionLimitExceeded);
+        assert_eq!(status.instructions, Some(23456));
+    }
+
+    #[test]
+    fn spawn_failed() {
+        let (result, log) = supervise(MockProgram {
+            exit_after: None,
+            spawn_error: Some(nix::libc::ENOENT),
+            ..Default::default()
+        });
+        match result {
+            Err(SandboxError::SpawnFailed(err)) => {
+                assert_eq!(err.raw_os_error(), Some(nix::libc::ENOENT))
+            }
+            other => panic!("Wrong result: {:?}", other),
+        }
+        // 子进程没有退出时被杀死回收
+        assert!(log.child_killed);
+    }
+
+    #[test]
+    fn memory_limit_exceeded() {
+        let (result, _) = supervise(MockProgram {
+            termination: Termination::Signaled(Signal::SIGKILL),
+            max_memory: 4 * 1024 * 1024,
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::MemoryLimitExceeded);
+
+        // RSS 统计下同一个程序没有超限
+        let (result, _) = supervise_with(
+            MockProgram {
+                max_memory: 2 * 1024 * 1024 - 1,
+                ..Default::default()
+            },
+            |supervisor| supervisor.memory_accounting = MemoryAccounting::Rss,
+        );
+        assert_eq!(result.unwrap().status, SandboxStatusKind::Success);
+    }
+
+    #[test]
+    fn memory_limit_reached() {
+        let program = || MockProgram {
+            termination: Termination::Signaled(Signal::SIGKILL),
+            max_memory: 1024 * 1024,
+            ..Default::default()
+        };
+        // 旧的限制下峰值不超过 memory_limit 就没有超限
+        let (result, _) = supervise(program());
+        assert_eq!(result.unwrap().status, SandboxStatusKind::RuntimeError);
+        let (result, _) = supervise_with(program(), |supervisor| {
+            supervisor.legacy_memory_multiplier = false
+        });
+        assert_eq!(
+            result.unwrap().status,
+            SandboxStatusKind::MemoryLimitExceeded
+        );
+    }
+
+    #[test]
+    fn stat_read_failed() {
+        let (result, _) = supervise(MockProgram {
+            termination: Termination::Exited(1),
+            fail_reads: true,
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::RuntimeError);
+        assert_eq!(status.used_time, None);
+        assert_eq!(status.max_memory, None);
+        assert_eq!(status.max_pids, None);
+
+        // 超时的程序即使读不到 CPU 时间也能判为超时
+        let (result, _) = supervise(MockProgram {
+            exit_after: None,
+            fail_reads: true,
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(
+            status.status,
+            SandboxStatusKind::TimeLimitExceeded {
+                kind: TimeDimension::Wall
+            }
+        );
+        assert_eq!(status.used_time, None);
+    }
+
+    #[test]
+    fn oom_pause() {
+        let (result, log) = supervise_with(
+            MockProgram {
+                exit_after: None,
+                oom_after: Some(Duration::from_millis(0)),
+                ..Default::default()
+            },
+            |supervisor| supervisor.oom_behavior = OomBehavior::Pause,
+        );
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::MemoryLimitExceeded);
+        assert_eq!(status.termination, Termination::Killed);
+        assert_eq!(status.kill_signal, Some(Signal::SIGKILL));
+        assert!(status.wall_time < 100 + 500);
+        assert!(log.child_killed);
+    }
+
+    #[test]
+    fn peak_reset() {
+        let (result, _) = supervise(MockProgram {
+            exit_after: Some(Duration::from_millis(300)),
+            max_memory: 4 * 1024 * 1024,
+            peak_reset_on_exit: true,
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::MemoryLimitExceeded);
+        assert_eq!(status.max_memory, Some(4 * 1024 * 1024));
+    }
+
+    #[test]
+    fn peak_sampled() {
+        // 峰值在运行中逐渐增长，结束后被清零，只有运行中的采样能看到接近最终值的峰值
+        let (result, _) = supervise(MockProgram {
+            exit_after: Some(Duration::from_millis(500)),
+            max_memory: 4 * 1024 * 1024,
+            memory_growth: true,
+            peak_reset_on_exit: true,
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::MemoryLimitExceeded);
+        assert!(status.max_memory.unwrap() > 3 * 1024 * 1024);
+    }
+
+    #[test]
+    fn memsw_unavailable() {
+        let (result, _) = supervise(MockProgram {
+            max_memory: 4 * 1024 * 1024,
+            memsw_unavailable: true,
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::MemoryLimitExceeded);
+        assert_eq!(status.memory_counter, Some(MemoryCounter::Usage));
+
+        let (result, _) = supervise(MockProgram::default());
+        assert_eq!(result.unwrap().memory_counter, Some(MemoryCounter::Memsw));
+    }
+
+    #[test]
+    fn thread_limit_exceeded() {
+        let (result, log) = supervise_with(
+            MockProgram {
+                exit_after: None,
+                threads: 4,
+                ..Default::default()
+            },
+            |supervisor| supervisor.thread_limit = Some(1),
+        );
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::ThreadLimitExceeded);
+        assert_eq!(status.kill_signal, Some(Signal::SIGKILL));
+        assert!(log.child_killed);
+
+        let (result, _) = supervise_with(
+            MockProgram {
+                threads: 4,
+                ..Default::default()
+            },
+            |supervisor| supervisor.thread_limit = Some(4),
+        );
+        assert_eq!(result.unwrap().status, SandboxStatusKind::Success);
+    }
+
+    #[test]
+    fn pids_limit_exceeded() {
+        let (result, _) = supervise(MockProgram {
+            termination: Termination::Exited(1),
+            pids_denied: 3,
+            ..Default::default()
+        });
+        assert_eq!(result.unwrap().status, SandboxStatusKind::PidsLimitExceeded);
+
+        // 程序仍然正常结束时不影响结果
+        let (result, _) = supervise(MockProgram {
+            pids_denied: 3,
+            ..Default::default()
+        });
+        assert_eq!(result.unwrap().status, SandboxStatusKind::Success);
+    }
+
+    #[test]
+    fn time_limit_exceeded() {
+        let (result, log) = supervise(MockProgram {
+            exit_after: None,
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(
+            status.status,
+            SandboxStatusKind::TimeLimitExceeded {
+                kind: TimeDimension::Wall
+            }
+        );
+        assert_eq!(status.termination, Termination::Killed);
+        assert_eq!(status.kill_signal, Some(Signal::SIGKILL));
+        // 只等待不占用 CPU 的程序报告实测的 CPU 时间
+        assert_eq!(status.used_time, Some(10));
+        assert!(log.child_killed);
+        assert!(log.signals.is_empty());
+    }
+
+    #[test]
+    fn cpu_time_limit_exceeded() {
+        let (result, _) = supervise(MockProgram {
+            exit_after: Some(Duration::from_millis(300)),
+            cpu_time: Duration::from_millis(500),
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(
+            status.status,
+            SandboxStatusKind::TimeLimitExceeded {
+                kind: TimeDimension::Cpu
+            }
+        );
+        assert!(status.wall_time >= 300);
+    }
+
+    #[test]
+    fn cpu_time_source() {
+        // cpuacct 多算了时间（300ms），rusage 只有 40ms
+        let program = || MockProgram {
+            exit_after: Some(Duration::from_millis(300)),
+            cpu_time: Duration::from_millis(500),
+            rusage: Some((Duration::from_millis(30), Duration::from_millis(10))),
+            ..Default::default()
+        };
+        let (result, _) = supervise(program());
+        assert!(result.unwrap().status.is_time_limit_exceeded());
+        let (result, _) = supervise_with(program(), |supervisor| {
+            supervisor.cpu_time_source = CpuTimeSource::Rusage;
+            supervisor.collect_detailed_stats = true
+        });
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::Success);
+        assert_eq!(status.used_time, Some(40));
+        let report = status.detailed_stats.unwrap();
+        assert_eq!(report.rusage_user, Some(Duration::from_millis(30)));
+        assert_eq!(report.rusage_system, Some(Duration::from_millis(10)));
+        // 没有 rusage 时使用 cpuacct
+        let (result, _) = supervise_with(
+            MockProgram {
+                rusage: None,
+                ..program()
+            },
+            |supervisor| supervisor.cpu_time_source = CpuTimeSource::Rusage,
+        );
+        assert!(result.unwrap().status.is_time_limit_exceeded());
+        // 没有 cpuacct 时仍然报告 CPU 时间而不是实际运行时间
+        let (result, _) = supervise_with(
+            MockProgram {
+                no_cpuacct: true,
+                ..program()
+            },
+            |supervisor| supervisor.cpu_time_source = CpuTimeSource::Rusage,
+        );
+        let status = result.unwrap();
+        assert_eq!(status.used_time, Some(40));
+        assert_eq!(status.used_time_source, TimeDimension::Cpu);
+    }
+
+    #[test]
+    fn diverging_cpu_time() {
+        let ms = Duration::from_millis;
+        assert!(!diverges(ms(10), ms(25)));
+        assert!(!diverges(ms(1000), ms(1050)));
+        assert!(diverges(ms(1000), ms(1200)));
+        assert!(diverges(ms(300), ms(40)));
+    }
+
+    #[test]
+    fn transient_empty() {
+        let (result, _) = supervise(MockProgram {
+            exit_after: None,
+            transient_empty_at: Some(Duration::from_millis(200)),
+            ..Default::default()
+        });
+        let status = result.unwrap();
+        assert_eq!(
+            status.status,
+            SandboxStatusKind::TimeLimitExceeded {
+                kind: TimeDimension::Wall
+            }
+        );
+    }
+
+    #[test]
+    fn timeout_signal() {
+        let (result, log) = supervise_with(
+            MockProgram {
+                exit_after: None,
+                handles: Some(Signal::SIGTERM),
+                ..Default::default()
+            },
+            |supervisor| supervisor.timeout_signal = Signal::SIGTERM,
+        );
+        let status = result.unwrap();
+        assert_eq!(
+            status.status,
+            SandboxStatusKind::TimeLimitExceeded {
+                kind: TimeDimension::Wall
+            }
+        );
+        assert_eq!(status.termination, Termination::Signaled(Signal::SIGTERM));
+        assert_eq!(status.kill_signal, Some(Signal::SIGTERM));
+        assert!(!log.child_killed);
+    }
+
+    #[test]
+    fn cancelled() {
+        let (result, log) = supervise_with(
+            MockProgram {
+                exit_after: None,
+                ..Default::default()
+            },
+            |supervisor| supervisor.cancelled.store(true, Ordering::Release),
+        );
+        let status = result.unwrap();
+        assert_eq!(status.termination, Termination::Killed);
+        assert_eq!(status.kill_signal, None);
+        // 取消不算超时，使用时间是实测的值
+        assert!(status.used_time.unwrap() < 100);
+        assert!(log.child_killed);
+    }
+
+    #[test]
+    fn paused() {
+        let (result, _) = supervise_with(
+            MockProgram {
+                exit_after: Some(Duration::from_millis(500)),
+                ..Default::default()
+            },
+            |supervisor| {
+                supervisor.wall_time_limit = Duration::from_millis(300);
+                let pause = supervisor.pause.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    pause.pause(|| Ok(())).unwrap();
+                    // 已经暂停时不会再次冻结
+                    pause.pause(|| panic!("frozen twice")).unwrap();
+                    std::thread::sleep(Duration::from_millis(400));
+                    pause.resume(|| Ok(())).unwrap();
+                });
+            },
+        );
+        // 暂停的 400ms 不计入实际运行时间
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::Success);
+        assert!(status.wall_time < 300);
+    }
+
+    #[test]
+    fn deadline() {
+        let (result, log) = supervise_with(
+            MockProgram {
+                exit_after: None,
+                ..Default::default()
+            },
+            |supervisor| {
+                supervisor.deadline = Some(Instant::now() + Duration::from_millis(200));
+            },
+        );
+        match result {
+            Err(SandboxError::Internal(InternalError::Deadline)) => {}
+            other => panic!("Wrong result: {:?}", other),
+        }
+        assert!(log.child_killed && log.tasks_killed);
+    }
+
+    #[test]
+    fn leftover_tasks() {
+        let program = || MockProgram {
+            survivors: vec![TaskState {
+                pid: 42,
+                state: Some('D'),
+            }],
+            ..Default::default()
+        };
+        // 默认只记录日志
+        let (result, _) = supervise(program());
+        assert_eq!(result.unwrap().status, SandboxStatusKind::Success);
+        let (result, _) = supervise_with(program(), |supervisor| {
+            supervisor.fail_on_leftover_tasks = true
+        });
+        match result {
+            Err(err @ SandboxError::KillTimeout(_)) => {
+                assert!(err.has_uninterruptible_tasks());
+                assert_eq!(
+                    err.to_string(),
+                    "Failed to kill all task(s), still alive: 42 (D)"
+                );
+            }
+            other => panic!("Wrong result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn resource_stream() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (result, _) = supervise_with(
+            MockProgram {
+                exit_after: Some(Duration::from_millis(300)),
+                memory_growth: true,
+                ..Default::default()
+            },
+            |supervisor| supervisor.resource_stream = Some(tx),
+        );
+        let status = result.unwrap();
+        assert_eq!(status.status, SandboxStatusKind::Success);
+        // 最后一项是运行结果，之后 channel 被关闭
+        let mut events: Vec<_> = rx.iter().collect();
+        match events.pop() {
+            Some(ResourceEvent::Finished(finished)) => assert_eq!(finished.run_id, status.run_id),
+            other => panic!("Wrong last event: {:?}", other),
+        }
+        let snapshots: Vec<_> = events
+            .into_iter()
+            .map(|event| match event {
+                ResourceEvent::Snapshot(snapshot) => snapshot,
+                other => panic!("Unexpected event: {:?}", other),
+            })
+            .collect();
+        assert!(snapshots.len() >= 2);
+        assert!(snapshots
+            .windows(2)
+            .all(|pair| pair[0].elapsed < pair[1].elapsed
+                && pair[0].max_memory <= pair[1].max_memory));
+    }
+
+    #[test]
+    fn deadline_after_time_limit() {
+        // 程序先超时，之后在等待它处理 timeout_signal 时超过 deadline，不能报告为超时
+        let (result, log) = supervise_with(
+            MockProgram {
+                exit_after: None,
+                ..Default::default()
+            },
+            |supervisor| {
+                supervisor.timeout_signal = Signal::SIGTERM;
+                supervisor.deadline = Some(Instant::now() + Duration::from_millis(800));
+            },
+        );
+        match result {
+            Err(SandboxError::Internal(InternalError::Deadline)) => {}
+            other => panic!("Wrong result: {:?}", other),
+        }
+        assert_eq!(log.signals, vec![Signal::SIGTERM]);
+        assert!(log.child_killed);
+    }
+}
@@ -0,0 +1,27 @@
+//! 编写沙箱测试时使用的断言
+//!
+//! 失败时 panic 并打印完整的 `SandboxStatus`，便于判断是程序还是沙箱的问题
+
+use crate::{SandboxStatus, SandboxStatusKind};
+
+/// 断言运行状态为 `expected`，`TimeLimitExceeded` 需要超时的种类也相同
+#[track_caller]
+pub fn assert_verdict(status: &SandboxStatus, expected: SandboxStatusKind) {
+    if status.status != expected {
+        panic!(
+            "expected verdict {}, got {}\nstatus: {:#?}",
+            expected, status.status, status
+        );
+    }
+}
+
+/// 断言运行状态为 `TimeLimitExceeded`，不论超时的种类
+#[track_caller]
+pub fn assert_time_limit_exceeded(status: &SandboxStatus) {
+    if !status.status.is_time_limit_exceeded() {
+        panic!(
+            "expected verdict Time Limit Exceeded, got {}\nstatus: {:#?}",
+            status.status, status
+        );
+    }
+}
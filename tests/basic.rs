@@ -1,48 +1,832 @@
 use nova_sandbox::*;
 
+#[macro_use]
 mod common;
 
 #[test]
 fn hello_world() {
+    require_environment!();
     pretty_env_logger::init();
     let status = common::run_sandbox("echo 'Hello, World!'");
     log::debug!("{:?}", status);
-    if let SandboxStatusKind::Success = status.status {
-        log::info!("Test success");
-    } else {
-        panic!("Wrong return type!");
-    }
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
 }
 
 #[test]
 fn time_limit() {
+    require_environment!();
     let status = common::run_sandbox("sleep 2");
     log::debug!("{:?}", status);
-    if let SandboxStatusKind::TimeLimitExceeded = status.status {
-        log::info!("Test success");
-    } else {
-        panic!("Wrong return type!");
-    }
+    testing::assert_verdict(
+        &status,
+        SandboxStatusKind::TimeLimitExceeded {
+            kind: TimeDimension::Wall,
+        },
+    );
+
+    let status = common::run_sandbox("while true; do :; done");
+    log::debug!("{:?}", status);
+    testing::assert_verdict(
+        &status,
+        SandboxStatusKind::TimeLimitExceeded {
+            kind: TimeDimension::Cpu,
+        },
+    );
 }
 
 #[test]
 fn memory_limit() {
+    require_environment!();
     let status = common::run_sandbox("for i in $(seq 1 10000000000); do echo $i; done;");
     log::debug!("{:?}", status);
-    if let SandboxStatusKind::MemoryLimitExceeded = status.status {
-        log::info!("Test success");
-    } else {
-        panic!("Wrong return type!");
-    }
+    testing::assert_verdict(&status, SandboxStatusKind::MemoryLimitExceeded);
 }
 
 #[test]
 fn run_time() {
+    require_environment!();
     let status = common::run_sandbox("exit -1");
     log::debug!("{:?}", status);
-    if let SandboxStatusKind::RuntimeError = status.status {
-        log::info!("Test success");
-    } else {
-        panic!("Wrong return type!");
+    testing::assert_verdict(&status, SandboxStatusKind::RuntimeError);
+}
+
+#[test]
+fn exit_101() {
+    require_environment!();
+    // panic 的 Rust 程序以 101 退出
+    let status = common::run_sandbox("exit 101");
+    testing::assert_verdict(&status, SandboxStatusKind::RuntimeError);
+    assert_eq!(status.return_code, 101);
+}
+
+#[test]
+fn io_limit() {
+    require_environment!();
+    let mut config = common::config("echo 'Hello, World!' > /hello");
+    config.io_limit = Some(IoLimit {
+        read_bps: Some(1024 * 1024),
+        write_bps: Some(1024 * 1024),
+    });
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+    assert!(status.io_stats.is_some());
+}
+
+#[test]
+fn validate_exec() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        sandbox
+            .validate_exec(common::config("echo 'Hello, World!'"))
+            .unwrap();
+    });
+}
+
+#[test]
+fn supplementary_groups() {
+    require_environment!();
+    let mut config = common::config("test \"$(id -u)\" = 1000 && test \"$(id -G)\" = '1000 2000'");
+    config.uid = Some(1000);
+    config.gid = Some(1000);
+    config.supplementary_groups = vec![2000];
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+}
+
+#[test]
+fn nice() {
+    require_environment!();
+    let mut config = common::config("test \"$(nice)\" = 5");
+    config.nice = Some(5);
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+}
+
+#[test]
+fn max_run_duration() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let mut config = common::config("sleep 2");
+        config.max_run_duration = Some(std::time::Duration::from_millis(500));
+        match sandbox.run(config) {
+            Err(SandboxError::Internal(InternalError::Deadline)) => log::info!("Test success"),
+            status => panic!("Wrong return type! {:?}", status),
+        }
+    });
+}
+
+#[test]
+fn tmpfs() {
+    require_environment!();
+    let status = common::with_sandbox_from(
+        |rootfs, work, target| Sandbox::new_with_tmpfs(rootfs, work, target, 1024 * 1024).unwrap(),
+        |sandbox| {
+            // 写入超过 tmpfs 大小的文件会失败
+            sandbox
+                .run(common::config("head -c 2097152 /usr/bin/bash > /big"))
+                .unwrap()
+        },
+    );
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::RuntimeError);
+}
+
+#[test]
+fn remove() {
+    require_environment!();
+    common::with_sandbox(|mut sandbox| {
+        assert_eq!(sandbox.upper_directory(), sandbox.work_directory());
+        assert_eq!(sandbox.remove().unwrap(), Unmount::Clean);
+        // 重复调用不会出错
+        assert_eq!(sandbox.remove().unwrap(), Unmount::Clean);
+    });
+}
+
+#[test]
+fn memory_accounting() {
+    require_environment!();
+    // 写文件产生的页缓存会计入 memsw，但不计入 RSS
+    let command = "yes | head -c 20971520 > /cache";
+    let status = common::run_sandbox(command);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::MemoryLimitExceeded);
+
+    let mut config = common::config(command);
+    config.memory_accounting = MemoryAccounting::Rss;
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+}
+
+#[test]
+fn interactive() {
+    require_environment!();
+    use std::process::{Command, Stdio};
+    let spawn_interactor = |script: &str| {
+        Command::new("bash")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap()
+    };
+
+    let mut interactor = spawn_interactor("echo 3; read x; test \"$x\" = 6");
+    let status = common::with_sandbox(|sandbox| {
+        sandbox
+            .exec_interactive(
+                common::config("read x; echo $((x * 2))"),
+                interactor.stdin.take().unwrap(),
+                interactor.stdout.take().unwrap(),
+            )
+            .unwrap()
+    });
+    log::debug!("{:?}", status);
+    assert!(interactor.wait().unwrap().success());
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+
+    // 双方都在等待对方时判为超时
+    let mut interactor = spawn_interactor("read x");
+    let status = common::with_sandbox(|sandbox| {
+        sandbox
+            .exec_interactive(
+                common::config("read x"),
+                interactor.stdin.take().unwrap(),
+                interactor.stdout.take().unwrap(),
+            )
+            .unwrap()
+    });
+    log::debug!("{:?}", status);
+    interactor.kill().unwrap();
+    interactor.wait().unwrap();
+    testing::assert_time_limit_exceeded(&status);
+}
+
+#[test]
+fn setup_command() {
+    require_environment!();
+    let mut config = common::config("test -x /data/run");
+    config.setup_command = Some(String::from(
+        "mkdir /data && echo true > /data/run && chmod +x /data/run",
+    ));
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    assert!(status.overhead.setup_command > std::time::Duration::from_millis(0));
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+
+    let mut config = common::config("true");
+    config.setup_command = Some(String::from("exit 1"));
+    common::with_sandbox(|sandbox| match sandbox.run(config) {
+        Err(SandboxError::SetupFailed(status)) => assert_eq!(status.return_code, 1),
+        other => panic!("Wrong result: {:?}", other),
+    });
+}
+
+#[test]
+fn thread_limit() {
+    require_environment!();
+    let mut config = common::config("sleep 0.5");
+    config.thread_limit = Some(1);
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+}
+
+#[test]
+fn leak() {
+    require_environment!();
+    let unmount = common::with_sandbox(|sandbox| {
+        let status = sandbox.run(common::config("echo 1 > /core")).unwrap();
+        log::debug!("{:?}", status);
+        let leaked = sandbox.leak();
+        // drop 后仍然可以看到沙箱中的文件
+        assert!(leaked.sandbox_directory.join("core").exists());
+        assert!(leaked.sandbox_directory.join("usr/bin/bash").exists());
+        leaked.cleanup().unwrap()
+    });
+    assert_eq!(unmount, Unmount::Clean);
+}
+
+#[test]
+fn overlapping_paths() {
+    require_environment!();
+    let rootfs = common::rootfs();
+    let directory = common::temp_path();
+    let nested = format!("{}/sandbox", directory);
+    std::fs::create_dir_all(&nested).unwrap();
+
+    for (work, target) in [(&directory, &directory), (&directory, &nested)].iter() {
+        match Sandbox::new(rootfs, work, target) {
+            Err(SandboxError::OverlappingPaths(_, _)) => {}
+            other => panic!("Wrong result: {:?}", other),
+        }
+    }
+    // 规范化后相同的路径也会被发现
+    let relative = format!("{}/..", nested);
+    match Sandbox::new(rootfs, &relative, &nested) {
+        Err(SandboxError::OverlappingPaths(_, _)) => {}
+        other => panic!("Wrong result: {:?}", other),
+    }
+
+    // rootfs 不能作为 work 目录
+    match Sandbox::new(rootfs, rootfs, &nested) {
+        Err(SandboxError::OverlappingPaths(_, _)) => {}
+        other => panic!("Wrong result: {:?}", other),
+    }
+
+    // 通过 bind mount 得到的 rootfs 别名也会被发现
+    let alias = format!("{}/alias", directory);
+    std::fs::create_dir(&alias).unwrap();
+    let flags = nix::mount::MsFlags::MS_BIND;
+    nix::mount::mount(
+        Some(rootfs),
+        alias.as_str(),
+        None::<&str>,
+        flags,
+        None::<&str>,
+    )
+    .unwrap();
+    let result = Sandbox::new(rootfs, &alias, &nested);
+    nix::mount::umount(alias.as_str()).unwrap();
+    match result {
+        Err(SandboxError::OverlappingPaths(_, _)) => {}
+        other => panic!("Wrong result: {:?}", other),
+    }
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn ephemeral() {
+    require_environment!();
+    let base = common::temp_path();
+    std::fs::create_dir(&base).unwrap();
+
+    let sandbox = SandboxBuilder::ephemeral(common::rootfs())
+        .base_directory(&base)
+        .mount()
+        .unwrap();
+    assert!(sandbox.work_directory().starts_with(&base));
+    assert!(sandbox.sandbox_directory.starts_with(&base));
+    drop(sandbox);
+    assert_eq!(std::fs::read_dir(&base).unwrap().count(), 0);
+
+    std::fs::remove_dir(base).unwrap();
+}
+
+#[test]
+fn stdout_stream() {
+    require_environment!();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut config = common::config("seq 1 3");
+    config.stdout_stream = Some(tx);
+    let status = common::run_sandbox_config(config);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+    assert_eq!(rx.iter().flatten().collect::<Vec<_>>(), b"1\n2\n3\n");
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut config = common::config("yes");
+    config.stdout_stream = Some(tx);
+    config.output_limit = Some(1000);
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::OutputLimitExceeded);
+    assert_eq!(rx.iter().flatten().count(), 1000);
+}
+
+#[test]
+fn stdout_stream_complete() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        // 结束前一次写出大量数据，`run()` 返回时应当全部读完
+        for _ in 0..5 {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let mut config = common::config("yes | head -c 4000000");
+            config.stdout_stream = Some(tx);
+            let status = sandbox.run(config).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+            assert_eq!(rx.try_iter().flatten().count(), 4_000_000);
+        }
+    });
+}
+
+#[test]
+fn stdout_tty() {
+    require_environment!();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut config = common::config("test -t 1 && echo tty");
+    config.stdout_stream = Some(tx);
+    config.stdout_tty = true;
+    let status = common::run_sandbox_config(config);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+    assert_eq!(rx.iter().flatten().collect::<Vec<_>>(), b"tty\n");
+}
+
+#[test]
+fn tty() {
+    require_environment!();
+    let mut config = common::config(
+        "test -t 0 && test -t 1 && test -t 2 && echo tty; \
+         shopt -s checkwinsize; /bin/true; echo $LINES $COLUMNS; read line; echo got $line",
+    );
+    config.tty = Some(TtySize {
+        rows: 30,
+        columns: 100,
+    });
+    common::with_sandbox(|sandbox| {
+        let mut handle = sandbox.spawn(config).unwrap();
+        let mut tty = handle.take_tty().unwrap();
+        std::io::Write::write_all(&mut tty, b"hello\n").unwrap();
+        let mut output = Vec::new();
+        let mut buffer = [0u8; 1024];
+        // 程序结束后读取返回 EIO
+        while let Ok(size) = std::io::Read::read(&mut tty, &mut buffer) {
+            if size == 0 {
+                break;
+            }
+            output.extend_from_slice(&buffer[..size]);
+        }
+        testing::assert_verdict(&handle.join().unwrap(), SandboxStatusKind::Success);
+        let output = String::from_utf8(output).unwrap();
+        // 终端回显输入，并将 \n 转换为 \r\n；回显可能出现在程序的输出之间
+        assert!(output.contains("tty\r\n"), "{:?}", output);
+        assert!(output.contains("30 100\r\n"), "{:?}", output);
+        assert!(output.contains("got hello\r\n"), "{:?}", output);
+    });
+
+    let mut config = common::config("true");
+    config.tty = Some(TtySize::default());
+    config.stdout_stream = Some(std::sync::mpsc::channel().0);
+    assert!(matches!(
+        config.validate(),
+        Err(SandboxError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn argv() {
+    require_environment!();
+    // 没有参数跟在命令之后时，bash -c 中的 $0 为 argv[0]
+    let command = SandboxCommand::Argv {
+        program: "/usr/bin/bash".into(),
+        argv: vec!["mysh".into(), "-c".into(), "test \"$0\" = mysh".into()],
+    };
+    let mut config = common::config("");
+    config.command = command.clone();
+    let mut validate = common::config("");
+    validate.command = command;
+    common::with_sandbox(|sandbox| {
+        sandbox.validate_exec(validate).unwrap();
+        let mut missing = common::config("");
+        missing.command = SandboxCommand::Argv {
+            program: "/usr/bin/missing".into(),
+            argv: Vec::new(),
+        };
+        match sandbox.validate_exec(missing) {
+            Err(SandboxError::InvalidRootfs(_)) => {}
+            other => panic!("Wrong result: {:?}", other),
+        }
+        // 程序启动失败时马上返回，不用等待程序进入 cgroup
+        let mut missing = common::config("");
+        missing.command = SandboxCommand::Argv {
+            program: "/usr/bin/missing".into(),
+            argv: Vec::new(),
+        };
+        let start = std::time::Instant::now();
+        match sandbox.run(missing) {
+            Err(SandboxError::SpawnFailed(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::NotFound)
+            }
+            other => panic!("Wrong result: {:?}", other),
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+        let status = sandbox.run(config).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    });
+}
+
+#[test]
+fn command_prefix() {
+    require_environment!();
+    let prefix = ["/usr/bin/env", "FOO=bar"];
+    let argv = SandboxCommand::Argv {
+        program: "/usr/bin/bash".into(),
+        argv: vec![
+            "mysh".into(),
+            "-c".into(),
+            "test \"$FOO $0\" = \"bar /usr/bin/bash\"".into(),
+        ],
+    };
+    assert_eq!(
+        argv.clone().with_prefix(&prefix.map(String::from)),
+        SandboxCommand::Argv {
+            program: "/usr/bin/env".into(),
+            argv: vec![
+                "/usr/bin/env".into(),
+                "FOO=bar".into(),
+                "/usr/bin/bash".into(),
+                "-c".into(),
+                "test \"$FOO $0\" = \"bar /usr/bin/bash\"".into()
+            ],
+        }
+    );
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            SandboxBuilder::new(rootfs, work, target)
+                .command_prefix(prefix)
+                .mount()
+                .unwrap()
+        },
+        |sandbox| {
+            // shell 模式的命令不需要额外转义
+            let status = sandbox
+                .run(common::config("test \"$FOO\" = 'bar'"))
+                .unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+            let mut config = common::config("");
+            config.command = argv;
+            let status = sandbox.run(config).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+        },
+    );
+}
+
+#[test]
+fn max_pids() {
+    require_environment!();
+    let status = common::run_sandbox("sleep 0.5 & sleep 0.5 & wait");
+    log::debug!("{:?}", status);
+    assert_eq!(status.max_pids, Some(3));
+
+    // 达到 pids_limit 后 fork 失败，峰值等于限制
+    let mut config = common::config("for i in $(seq 1 10); do sleep 0.5 & done; wait");
+    config.stderr = std::process::Stdio::null();
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    assert_eq!(status.max_pids, Some(5));
+}
+
+#[test]
+fn pids_limit() {
+    require_environment!();
+    // bash 在 fork 失败后会等待并重试，最终 exit 1
+    let mut config = common::config("for i in $(seq 1 10); do sleep 0.2 & done; wait; exit 1");
+    config.time_limit = 5000;
+    config.stderr = std::process::Stdio::null();
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::PidsLimitExceeded);
+
+    let status = common::run_sandbox("exit 1");
+    testing::assert_verdict(&status, SandboxStatusKind::RuntimeError);
+}
+
+#[test]
+fn stdin_mode() {
+    require_environment!();
+    let mut config = common::config("cat");
+    config.stdin_mode = StdinMode::Closed;
+    testing::assert_verdict(
+        &common::run_sandbox_config(config),
+        SandboxStatusKind::Success,
+    );
+
+    let mut config = common::config("test \"$(cat)\" = hello");
+    config.stdin_mode = StdinMode::Bytes(b"hello".to_vec());
+    testing::assert_verdict(
+        &common::run_sandbox_config(config),
+        SandboxStatusKind::Success,
+    );
+
+    common::with_sandbox(|sandbox| {
+        let mut config = common::config("read line && test \"$line\" = hi && test -z \"$(cat)\"");
+        config.stdin_mode = StdinMode::Open;
+        let mut handle = sandbox.spawn(config).unwrap();
+        let mut stdin = handle.take_stdin().unwrap();
+        std::io::Write::write_all(&mut stdin, b"hi\n").unwrap();
+        drop(stdin);
+        testing::assert_verdict(&handle.join().unwrap(), SandboxStatusKind::Success);
+    });
+}
+
+#[test]
+fn from_fds() {
+    require_environment!();
+    let open = |path: &str| std::os::unix::io::OwnedFd::from(std::fs::File::open(path).unwrap());
+    let work_directory = common::temp_path();
+    let sandbox_directory = common::temp_path();
+    std::fs::create_dir(&work_directory).unwrap();
+    std::fs::create_dir(&sandbox_directory).unwrap();
+
+    let sandbox = Sandbox::from_fds(
+        open(common::rootfs()),
+        open(&work_directory),
+        open(&sandbox_directory),
+    )
+    .unwrap();
+    let status = sandbox.run(common::config("echo 1 > /a")).unwrap();
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+    drop(sandbox);
+    assert_eq!(
+        std::fs::read_to_string(format!("{}/a", work_directory)).unwrap(),
+        "1\n"
+    );
+
+    // 打开后被替换的挂载点
+    let sandbox_fd = open(&sandbox_directory);
+    std::fs::remove_dir_all(&sandbox_directory).unwrap();
+    std::fs::create_dir(&sandbox_directory).unwrap();
+    match Sandbox::from_fds(open(common::rootfs()), open(&work_directory), sandbox_fd) {
+        Err(SandboxError::DirectoryChanged(_)) => {}
+        other => panic!("Wrong result: {:?}", other),
+    }
+
+    std::fs::remove_dir_all(work_directory).unwrap();
+    std::fs::remove_dir_all(sandbox_directory).unwrap();
+}
+
+#[test]
+fn command_length() {
+    require_environment!();
+    let long = format!("true #{}", "a".repeat(200 * 1024));
+    match common::config(&long).validate() {
+        Err(SandboxError::InvalidConfig(_)) => {}
+        other => panic!("Wrong result: {:?}", other),
+    }
+    let mut argv = common::config("");
+    argv.command = SandboxCommand::Argv {
+        program: "/usr/bin/true".into(),
+        argv: vec![String::from("true"); 5000],
+    };
+    match argv.validate() {
+        Err(SandboxError::InvalidConfig(_)) => {}
+        other => panic!("Wrong result: {:?}", other),
+    }
+    let empty_argv = SandboxCommand::Argv {
+        program: std::path::PathBuf::new(),
+        argv: vec![String::from("true")],
+    };
+    for command in [SandboxCommand::from(" \n\t"), empty_argv] {
+        let mut empty = common::config("");
+        empty.command = command;
+        match empty.validate() {
+            Err(SandboxError::InvalidConfig(_)) => {}
+            other => panic!("Wrong result: {:?}", other),
+        }
+    }
+
+    common::with_sandbox(|sandbox| {
+        let mut config = common::config(format!("true #{}", "a".repeat(2000)));
+        config.max_command_length = 1000;
+        match sandbox.run(config) {
+            Err(SandboxError::InvalidConfig(_)) => {}
+            other => panic!("Wrong result: {:?}", other),
+        }
+        let status = sandbox
+            .run(common::config(format!("true #{}", "a".repeat(64 * 1024))))
+            .unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    });
+}
+
+#[test]
+fn stale_mount() {
+    require_environment!();
+    use nix::mount::{mount, MsFlags};
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            // 模拟崩溃后遗留的挂载
+            mount(
+                Some("tmpfs"),
+                target,
+                Some("tmpfs"),
+                MsFlags::empty(),
+                None::<&str>,
+            )
+            .unwrap();
+            match Sandbox::new(rootfs, work, target) {
+                Err(SandboxError::AlreadyMounted(_)) => {}
+                other => panic!("Wrong result: {:?}", other),
+            }
+            SandboxBuilder::new(rootfs, work, target)
+                .stale_mount(StaleMount::Reclaim)
+                .mount()
+                .unwrap()
+        },
+        |sandbox| {
+            let status = sandbox.run(common::config("test -e /bin/bash")).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+        },
+    );
+}
+
+#[test]
+fn mountpoint_in_use() {
+    require_environment!();
+    common::with_sandbox_from(
+        |rootfs, work, target| Sandbox::new(rootfs, work, target).unwrap(),
+        |sandbox| {
+            let target = sandbox.sandbox_directory.clone();
+            let work = common::temp_path();
+            std::fs::create_dir(&work).unwrap();
+            // 即使允许回收遗留的挂载，也不会卸载还在使用的沙箱
+            let second = SandboxBuilder::new(common::rootfs(), &work, &target)
+                .stale_mount(StaleMount::Reclaim)
+                .mount();
+            match second {
+                Err(SandboxError::MountpointInUse(_)) => {}
+                other => panic!("Wrong result: {:?}", other),
+            }
+            let status = sandbox.run(common::config("true")).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+
+            drop(sandbox);
+            let second = Sandbox::new(common::rootfs(), &work, &target).unwrap();
+            drop(second);
+            std::fs::remove_dir_all(work).unwrap();
+        },
+    );
+}
+
+#[test]
+fn drop_capabilities() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let status = sandbox.run(common::config("mknod /null c 1 3")).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::RuntimeError);
+
+        let mut config = common::config("mknod /null c 1 3");
+        config.drop_capabilities = false;
+        let status = sandbox.run(config).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    });
+}
+
+#[test]
+fn no_new_privs() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let status = sandbox
+            .run(common::config("cat /bin/id > /id && chmod 4755 /id"))
+            .unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+
+        let run = |command: &str, no_new_privs: bool| {
+            let mut config = common::config(command);
+            config.uid = Some(1000);
+            config.gid = Some(1000);
+            config.no_new_privs = no_new_privs;
+            sandbox.run(config).unwrap()
+        };
+        testing::assert_verdict(
+            &run("test \"$(/id -u)\" = 1000", true),
+            SandboxStatusKind::Success,
+        );
+        testing::assert_verdict(
+            &run("test \"$(/id -u)\" = 0", false),
+            SandboxStatusKind::Success,
+        );
+    });
+}
+
+#[test]
+fn rootfs_digest() {
+    require_environment!();
+    let digest = nova_sandbox::rootfs_digest(common::rootfs()).unwrap();
+    assert_eq!(digest.len(), 64);
+    assert_eq!(
+        nova_sandbox::rootfs_digest(common::rootfs()).unwrap(),
+        digest
+    );
+
+    let status = common::with_sandbox_from(
+        |rootfs, work, target| {
+            SandboxBuilder::new(rootfs, work, target)
+                .rootfs_digest(digest.as_str())
+                .mount()
+                .unwrap()
+        },
+        |sandbox| sandbox.run(common::config("true")).unwrap(),
+    );
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            match SandboxBuilder::new(rootfs, work, target)
+                .rootfs_digest("0".repeat(64))
+                .mount()
+            {
+                Err(SandboxError::RootfsIntegrity(_, actual)) => assert_eq!(actual, digest),
+                other => panic!("Wrong result: {:?}", other),
+            }
+            Sandbox::new(rootfs, work, target).unwrap()
+        },
+        drop,
+    );
+
+    // 修改文件内容或权限都会改变摘要
+    let directory = common::temp_path();
+    std::fs::create_dir(&directory).unwrap();
+    let file = format!("{}/a", directory);
+    std::fs::write(&file, "1").unwrap();
+    let before = nova_sandbox::rootfs_digest(&directory).unwrap();
+    std::fs::write(&file, "2").unwrap();
+    let changed = nova_sandbox::rootfs_digest(&directory).unwrap();
+    assert_ne!(before, changed);
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o4755)).unwrap();
+    assert_ne!(nova_sandbox::rootfs_digest(&directory).unwrap(), changed);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn child_setup() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let mut config = common::config("ulimit -n > /nofile");
+        config.child_setup = Some(ChildSetup::new(|| {
+            let rlimit = libc::rlimit {
+                rlim_cur: 16,
+                rlim_max: 16,
+            };
+            if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }));
+        let status = sandbox.run(config).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+        assert_eq!(
+            std::fs::read_to_string(sandbox.upper_directory().join("nofile")).unwrap(),
+            "16\n"
+        );
+
+        let mut config = common::config("true");
+        config.child_setup = Some(ChildSetup::new(|| {
+            Err(std::io::Error::from_raw_os_error(libc::EPERM))
+        }));
+        match sandbox.run(config) {
+            Err(SandboxError::SpawnFailed(err)) => {
+                assert_eq!(err.raw_os_error(), Some(libc::EPERM))
+            }
+            other => panic!("Wrong result: {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn instruction_limit() {
+    require_environment!();
+    let mut config = common::config("i=0; while [ $i -lt 20000 ]; do i=$((i + 1)); done");
+    config.instruction_limit = Some(1_000_000);
+    let status = common::run_sandbox_config(config);
+    // 没有开启 perf feature 或主机没有可用的硬件计数器时只是不计数
+    match status.instructions {
+        Some(_) => testing::assert_verdict(&status, SandboxStatusKind::InstructionLimitExceeded),
+        None => testing::assert_verdict(&status, SandboxStatusKind::Success),
     }
 }
@@ -1,21 +1,57 @@
+#![allow(dead_code, unused_macros)]
+
 use nova_sandbox::*;
 use std::fs;
 use std::process::Stdio;
 
-pub fn run_sandbox<T: std::fmt::Display>(command: T) -> nova_sandbox::SandboxStatus {
-    let work_directory = format!("/tmp/{}", uuid::Uuid::new_v4().to_string());
-    let sandbox_directory = format!("/tmp/{}", uuid::Uuid::new_v4().to_string());
-    fs::create_dir(&work_directory).unwrap();
-    fs::create_dir(&sandbox_directory).unwrap();
+/// 没有设置 `NOVA_SANDBOX_TEST_ROOTFS` 时使用的 rootfs
+const DEFAULT_ROOTFS: &str = "/work/package/debs/linux-rootfs";
 
-    let sandbox = Sandbox::new(
-        "/work/package/debs/linux-rootfs",
-        &work_directory,
-        &sandbox_directory,
-    )
-    .unwrap();
+/// 测试使用的 rootfs，由环境变量 `NOVA_SANDBOX_TEST_ROOTFS` 指定
+///
+/// rootfs 中需要有 bash 和 coreutils 中的常用命令
+pub fn rootfs() -> &'static str {
+    static ROOTFS: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    ROOTFS.get_or_init(|| {
+        std::env::var("NOVA_SANDBOX_TEST_ROOTFS").unwrap_or_else(|_| String::from(DEFAULT_ROOTFS))
+    })
+}
 
-    let config = SandboxConfig::new(
+/// 缺少 root 权限或 rootfs 时返回 true，测试应直接跳过
+pub fn should_skip() -> bool {
+    if !nix::unistd::geteuid().is_root() {
+        eprintln!("skipped: integration tests need root");
+        return true;
+    }
+    if !std::path::Path::new(rootfs()).is_dir() {
+        eprintln!(
+            "skipped: rootfs {:?} not found, set NOVA_SANDBOX_TEST_ROOTFS",
+            rootfs()
+        );
+        return true;
+    }
+    false
+}
+
+/// 在测试开头使用，环境不满足时跳过测试，见 `should_skip()`
+macro_rules! require_environment {
+    () => {
+        if common::should_skip() {
+            return;
+        }
+    };
+}
+
+/// 系统临时目录下一个唯一的路径，不会创建
+pub fn temp_path() -> String {
+    std::env::temp_dir()
+        .join(uuid::Uuid::new_v4().to_string())
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn config<T: std::fmt::Display>(command: T) -> SandboxConfig {
+    SandboxConfig::new(
         1000,
         8 * 1024 * 1024,
         5,
@@ -23,12 +59,42 @@ pub fn run_sandbox<T: std::fmt::Display>(command: T) -> nova_sandbox::SandboxSta
         Stdio::inherit(),
         Stdio::inherit(),
         Stdio::inherit(),
-    );
-    let status = sandbox.run(config).unwrap();
+    )
+}
+
+pub fn run_sandbox<T: std::fmt::Display>(command: T) -> nova_sandbox::SandboxStatus {
+    run_sandbox_config(config(command))
+}
+
+pub fn run_sandbox_config(config: SandboxConfig) -> nova_sandbox::SandboxStatus {
+    with_sandbox(|sandbox| sandbox.run(config).unwrap())
+}
+
+/// 在新建的沙箱中执行 `f`，结束后清理目录
+pub fn with_sandbox<F, R>(f: F) -> R
+where
+    F: FnOnce(Sandbox) -> R,
+{
+    f(Sandbox::ephemeral(rootfs()).unwrap())
+}
+
+/// 用 `new` 新建沙箱并在其中执行 `f`，结束后清理目录
+pub fn with_sandbox_from<N, F, R>(new: N, f: F) -> R
+where
+    N: FnOnce(&str, &str, &str) -> Sandbox,
+    F: FnOnce(Sandbox) -> R,
+{
+    let work_directory = temp_path();
+    let sandbox_directory = temp_path();
+    fs::create_dir(&work_directory).unwrap();
+    fs::create_dir(&sandbox_directory).unwrap();
+
+    let sandbox = new(rootfs(), &work_directory, &sandbox_directory);
+
+    let result = f(sandbox);
 
-    drop(sandbox);
     fs::remove_dir_all(work_directory).unwrap();
     fs::remove_dir_all(sandbox_directory).unwrap();
 
-    status
+    result
 }
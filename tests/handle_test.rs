@@ -0,0 +1,351 @@
+use nova_sandbox::*;
+use std::time::{Duration, Instant};
+
+#[macro_use]
+mod common;
+
+#[test]
+fn query_while_running() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let handle = sandbox
+            .spawn(common::config("while true; do :; done"))
+            .unwrap();
+        let monitor = handle.monitor();
+        let cpu_time = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(500));
+            monitor.cpu_time().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert!(cpu_time > Duration::from_millis(0));
+        assert!(handle.current_memory().unwrap() > 0);
+        assert!(handle.max_memory().unwrap() >= handle.current_memory().unwrap());
+
+        for path in handle.cgroup_paths() {
+            assert!(path.join("tasks").exists());
+        }
+
+        let run_id = handle.run_id().to_string();
+        let status = handle.join().unwrap();
+        log::debug!("{:?}", status);
+        assert_eq!(status.run_id, run_id);
+        testing::assert_time_limit_exceeded(&status);
+    });
+}
+
+#[test]
+fn sandbox_state() {
+    require_environment!();
+    common::with_sandbox(|mut sandbox| {
+        assert!(sandbox.is_mounted());
+        assert!(!sandbox.is_running());
+        let handle = sandbox.spawn(common::config("sleep 0.2")).unwrap();
+        assert!(sandbox.is_running());
+        testing::assert_verdict(&handle.join().unwrap(), SandboxStatusKind::Success);
+        assert!(!sandbox.is_running());
+        // drop 而不 join 同样结束运行
+        drop(sandbox.spawn(common::config("sleep 1")).unwrap());
+        assert!(!sandbox.is_running());
+        sandbox.remove().unwrap();
+        assert!(!sandbox.is_mounted());
+    });
+}
+
+#[test]
+fn drop_kills_run() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let start = Instant::now();
+        let handle = sandbox.spawn(common::config("sleep 10")).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        drop(handle);
+        assert!(start.elapsed() < Duration::from_secs(3));
+    });
+}
+
+#[test]
+fn extra_controllers() {
+    require_environment!();
+    let builder = |rootfs: &str, work: &str, target: &str| {
+        SandboxBuilder::new(rootfs, work, target)
+            .controller(
+                ControllerSpec::new("cpuset")
+                    .value("cpuset.cpus", 0)
+                    .value("cpuset.mems", 0),
+            )
+            .controller(ControllerSpec::new("memory").value("memory.swappiness", 0))
+    };
+    common::with_sandbox_from(
+        |rootfs, work, target| builder(rootfs, work, target).mount().unwrap(),
+        |sandbox| {
+            let handle = sandbox.spawn(common::config("sleep 0.5")).unwrap();
+            let paths = handle.cgroup_paths();
+            let read = |controller: &str, file: &str| {
+                let path = paths
+                    .iter()
+                    .find(|path| path.starts_with(format!("/sys/fs/cgroup/{}", controller)))
+                    .unwrap();
+                std::fs::read_to_string(path.join(file)).unwrap()
+            };
+            assert_eq!(read("cpuset", "cpuset.cpus").trim(), "0");
+            assert_eq!(read("memory", "memory.swappiness").trim(), "0");
+            testing::assert_verdict(&handle.join().unwrap(), SandboxStatusKind::Success);
+        },
+    );
+
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            let result = SandboxBuilder::new(rootfs, work, target)
+                .controller(ControllerSpec::new("missing"))
+                .mount();
+            match result {
+                Err(SandboxError::MissingController(name)) => assert_eq!(name, "missing"),
+                other => panic!("Wrong result: {:?}", other),
+            }
+            Sandbox::new(rootfs, work, target).unwrap()
+        },
+        |_| {},
+    );
+}
+
+#[test]
+fn cgroup_config_error() {
+    require_environment!();
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            SandboxBuilder::new(rootfs, work, target)
+                .controller(ControllerSpec::new("memory").value("memory.swappiness", "invalid"))
+                .mount()
+                .unwrap()
+        },
+        |sandbox| match sandbox.run(common::config("true")) {
+            Err(SandboxError::CgroupConfig {
+                controller, key, ..
+            }) => {
+                assert_eq!(controller, "memory");
+                assert_eq!(key, "memory.swappiness");
+            }
+            other => panic!("Wrong result: {:?}", other),
+        },
+    );
+}
+
+#[test]
+fn cgroup_parent() {
+    require_environment!();
+    let parent = format!("nova-sandbox-test-{}", std::process::id());
+    let controllers = ["memory", "pids", "freezer", "cpuacct", "blkio"];
+    let directories = controllers
+        .iter()
+        .map(|controller| std::path::Path::new("/sys/fs/cgroup").join(controller))
+        .filter(|directory| directory.join("tasks").exists())
+        .map(|directory| directory.join(&parent))
+        .collect::<Vec<_>>();
+
+    // 父 cgroup 不存在
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            match SandboxBuilder::new(rootfs, work, target)
+                .cgroup_parent(&parent)
+                .mount()
+            {
+                Err(SandboxError::CgroupParent(path)) => assert!(path.ends_with(&parent)),
+                other => panic!("Wrong result: {:?}", other),
+            }
+            Sandbox::new(rootfs, work, target).unwrap()
+        },
+        |_| {},
+    );
+
+    for directory in &directories {
+        std::fs::create_dir(directory).unwrap();
+    }
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            SandboxBuilder::new(rootfs, work, target)
+                .cgroup_parent(format!("/{}", parent))
+                .mount()
+                .unwrap()
+        },
+        |sandbox| {
+            let handle = sandbox.spawn(common::config("sleep 0.1")).unwrap();
+            for path in handle.cgroup_paths() {
+                assert!(path.parent().unwrap().ends_with(&parent), "{:?}", path);
+            }
+            testing::assert_verdict(&handle.join().unwrap(), SandboxStatusKind::Success);
+        },
+    );
+    for directory in &directories {
+        std::fs::remove_dir(directory).unwrap();
+    }
+}
+
+#[test]
+fn external_cgroup() {
+    require_environment!();
+    let name = format!("nova-sandbox-external-{}", std::process::id());
+    let directories = ["memory", "pids", "freezer", "cpuacct"]
+        .iter()
+        .map(|controller| std::path::Path::new("/sys/fs/cgroup").join(controller))
+        .filter(|directory| directory.join("tasks").exists())
+        .map(|directory| directory.join(&name))
+        .collect::<Vec<_>>();
+    common::with_sandbox(|sandbox| {
+        let mut config = common::config("true");
+        config.cgroup = Some(ExternalCgroup::new(&name));
+        match sandbox.run(config) {
+            Err(SandboxError::DirectoryNotFound(path)) => assert!(path.ends_with(&name)),
+            other => panic!("Wrong result: {:?}", other),
+        }
+
+        for directory in &directories {
+            std::fs::create_dir(directory).unwrap();
+        }
+        let mut config = common::config("sleep 0.1");
+        config.cgroup = Some(ExternalCgroup::new(&name));
+        let handle = sandbox.spawn(config).unwrap();
+        for path in handle.cgroup_paths() {
+            assert!(path.ends_with(&name), "{:?}", path);
+        }
+        testing::assert_verdict(&handle.join().unwrap(), SandboxStatusKind::Success);
+        // 限制已经设置，cgroup 没有被删除
+        let pids = std::path::Path::new("/sys/fs/cgroup/pids").join(&name);
+        assert_eq!(
+            std::fs::read_to_string(pids.join("pids.max"))
+                .unwrap()
+                .trim(),
+            "5"
+        );
+    });
+    for directory in &directories {
+        std::fs::remove_dir(directory).unwrap();
+    }
+}
+
+#[test]
+fn external_cgroup_raise_memory_limit() {
+    require_environment!();
+    let name = format!("nova-sandbox-raise-{}", std::process::id());
+    let directories = ["memory", "pids", "freezer", "cpuacct"]
+        .iter()
+        .map(|controller| std::path::Path::new("/sys/fs/cgroup").join(controller))
+        .filter(|directory| directory.join("tasks").exists())
+        .map(|directory| directory.join(&name))
+        .collect::<Vec<_>>();
+    for directory in &directories {
+        std::fs::create_dir(directory).unwrap();
+    }
+    common::with_sandbox(|sandbox| {
+        // 放宽限制时 memsw 必须先于 memory.limit_in_bytes 写入，否则内核返回 EINVAL
+        for memory_limit in [8 * 1024 * 1024, 64 * 1024 * 1024] {
+            let mut config = common::config("true");
+            config.memory_limit = memory_limit;
+            config.cgroup = Some(ExternalCgroup::new(&name));
+            let status = sandbox.run(config).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+        }
+    });
+    for directory in &directories {
+        std::fs::remove_dir(directory).unwrap();
+    }
+}
+
+#[test]
+fn strict_rss() {
+    require_environment!();
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            SandboxBuilder::new(rootfs, work, target)
+                .memory_limit_mode(MemoryLimitMode::StrictRss)
+                .mount()
+                .unwrap()
+        },
+        |sandbox| {
+            let handle = sandbox.spawn(common::config("sleep 0.2")).unwrap();
+            let memory = handle
+                .cgroup_paths()
+                .into_iter()
+                .find(|path| path.starts_with("/sys/fs/cgroup/memory"))
+                .unwrap();
+            let swappiness = std::fs::read_to_string(memory.join("memory.swappiness")).unwrap();
+            assert_eq!(swappiness.trim(), "0");
+            testing::assert_verdict(&handle.join().unwrap(), SandboxStatusKind::Success);
+
+            // 在变量中保存 64 MiB，远超 cgroup 的限制（memory_limit 的 4 倍）
+            let status = sandbox
+                .run(common::config("x=$(yes | head -c 67108864); echo ${#x}"))
+                .unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::MemoryLimitExceeded);
+            // 即使 memsw 可用，也按不含 swap 的峰值判断
+            assert_eq!(status.memory_counter, Some(MemoryCounter::Usage));
+            assert_eq!(status.swap_used, None);
+        },
+    );
+}
+
+#[test]
+fn exact_memory_limit() {
+    require_environment!();
+    let memory_limit_in_bytes = |handle: &RunHandle| {
+        let memory = handle
+            .cgroup_paths()
+            .into_iter()
+            .find(|path| path.starts_with("/sys/fs/cgroup/memory"))
+            .unwrap();
+        std::fs::read_to_string(memory.join("memory.limit_in_bytes"))
+            .unwrap()
+            .trim()
+            .parse::<u64>()
+            .unwrap()
+    };
+    common::with_sandbox(|sandbox| {
+        let config = common::config("sleep 0.2");
+        let memory_limit = config.memory_limit;
+        let handle = sandbox.spawn(config).unwrap();
+        assert_eq!(memory_limit_in_bytes(&handle), memory_limit * 4);
+        handle.join().unwrap();
+
+        let mut config = common::config("sleep 0.2");
+        config.legacy_memory_multiplier = false;
+        let handle = sandbox.spawn(config).unwrap();
+        assert_eq!(memory_limit_in_bytes(&handle), memory_limit);
+        handle.join().unwrap();
+
+        // 程序在达到限制时被杀死，已用的内存不超过限制
+        let mut config = common::config("for i in $(seq 1 10000000000); do echo $i; done;");
+        config.legacy_memory_multiplier = false;
+        let status = sandbox.run(config).unwrap();
+        log::debug!("{:?}", status);
+        testing::assert_verdict(&status, SandboxStatusKind::MemoryLimitExceeded);
+        assert!(status.max_memory.unwrap() <= memory_limit);
+    });
+}
+
+#[test]
+fn pause_and_resume() {
+    require_environment!();
+    let mut config = common::config("i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done");
+    config.wall_time_limit = Some(1000);
+    common::with_sandbox(|sandbox| {
+        let handle = sandbox.spawn(config).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        handle.pause().unwrap();
+        let freezer = handle
+            .cgroup_paths()
+            .into_iter()
+            .find(|path| path.starts_with("/sys/fs/cgroup/freezer"))
+            .unwrap();
+        let state = std::fs::read_to_string(freezer.join("freezer.state")).unwrap();
+        assert_eq!(state.trim(), "FROZEN");
+        // 暂停期间不消耗 CPU 时间，也不会因为超过 wall_time_limit 被杀死
+        let cpu_time = handle.cpu_time().unwrap();
+        std::thread::sleep(Duration::from_secs(2));
+        assert_eq!(handle.cpu_time().unwrap(), cpu_time);
+        handle.monitor().resume().unwrap();
+        let status = handle.join().unwrap();
+        log::debug!("{:?}", status);
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+        assert!(status.wall_time < 1000);
+    });
+}
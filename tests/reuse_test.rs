@@ -0,0 +1,422 @@
+use nova_sandbox::*;
+
+#[macro_use]
+mod common;
+
+#[test]
+fn fixed_limits() {
+    require_environment!();
+    let limits = SandboxLimits {
+        time_limit: 1000,
+        memory_limit: 8 * 1024 * 1024,
+        pids_limit: 5,
+    };
+    common::with_sandbox(|sandbox| {
+        let sandbox = sandbox.with_fixed_limits(limits).unwrap();
+
+        let status = sandbox.run(common::config("sleep 2")).unwrap();
+        log::debug!("{:?}", status);
+        testing::assert_time_limit_exceeded(&status);
+
+        // 统计在两次运行之间被重置
+        let status = sandbox.run(common::config("true")).unwrap();
+        log::debug!("{:?}", status);
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+        assert!(status.used_time.unwrap() < 500);
+    });
+}
+
+#[test]
+fn cpu_budget() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let sandbox = sandbox.with_cpu_budget(std::time::Duration::from_millis(300));
+
+        let status = sandbox
+            .run(common::config("while true; do :; done"))
+            .unwrap();
+        log::debug!("{:?}", status);
+        assert!(sandbox.total_cpu_time() >= std::time::Duration::from_millis(300));
+
+        match sandbox.run(common::config("true")) {
+            Err(SandboxError::CpuBudgetExhausted(_)) => {}
+            other => panic!("Wrong result: {:?}", other),
+        }
+
+        sandbox.reset_total_cpu_time();
+        let status = sandbox.run(common::config("true")).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    });
+}
+
+#[test]
+fn snapshot() {
+    require_environment!();
+    let check = |sandbox: &Sandbox, command: &str| {
+        let status = sandbox.run(common::config(command)).unwrap();
+        log::debug!("{:?}", status);
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    };
+    common::with_sandbox(|mut sandbox| {
+        check(&sandbox, "echo 1 > /a; rm /usr/bin/wc");
+        sandbox.snapshot().unwrap();
+
+        check(&sandbox, "echo 2 > /b; rm /a /usr/bin/yes");
+        sandbox.restore_snapshot().unwrap();
+
+        check(
+            &sandbox,
+            "test -f /a && test ! -e /b && test -f /usr/bin/yes && test ! -e /usr/bin/wc",
+        );
+    });
+}
+
+#[test]
+fn reset() {
+    require_environment!();
+    let limits = SandboxLimits {
+        time_limit: 1000,
+        memory_limit: 8 * 1024 * 1024,
+        pids_limit: 5,
+    };
+    common::with_sandbox(|sandbox| {
+        let mut sandbox = sandbox.with_fixed_limits(limits).unwrap();
+
+        let status = sandbox.run(common::config("echo 1 > /a")).unwrap();
+        log::debug!("{:?}", status);
+        assert!(sandbox.total_cpu_time() > std::time::Duration::from_millis(0));
+
+        sandbox.reset().unwrap();
+        assert_eq!(
+            sandbox.total_cpu_time(),
+            std::time::Duration::from_millis(0)
+        );
+        let status = sandbox.run(common::config("test ! -e /a")).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    });
+}
+
+#[test]
+fn builder() {
+    require_environment!();
+    let lower = common::temp_path();
+    let data = common::temp_path();
+    std::fs::create_dir(&lower).unwrap();
+    std::fs::create_dir(&data).unwrap();
+    std::fs::write(format!("{}/lower", lower), "lower").unwrap();
+    std::fs::write(format!("{}/input", data), "input").unwrap();
+
+    let command = "echo x > /dev/null && cat /lower /data/input && ! touch /data/output";
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            SandboxBuilder::new(rootfs, work, target)
+                .lower(&lower)
+                .bind(&data, "/data", true)
+                .dev(true)
+                .mount()
+                .unwrap()
+        },
+        |mut sandbox| {
+            let status = sandbox.run(common::config(command)).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+            // reset 后额外的挂载仍然存在
+            sandbox.reset().unwrap();
+            let status = sandbox.run(common::config(command)).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+            assert_eq!(sandbox.remove().unwrap(), Unmount::Clean);
+        },
+    );
+
+    std::fs::remove_dir_all(lower).unwrap();
+    std::fs::remove_dir_all(data).unwrap();
+}
+
+#[test]
+fn oci_layers() {
+    require_environment!();
+    let layers = [common::temp_path(), common::temp_path()];
+    for (index, layer) in layers.iter().enumerate() {
+        std::fs::create_dir(layer).unwrap();
+        std::fs::write(format!("{}/layer", layer), index.to_string()).unwrap();
+    }
+    std::fs::write(format!("{}/base", layers[0]), "base").unwrap();
+
+    common::with_sandbox_from(
+        |_, work, target| {
+            // 最上层在前，rootfs 在最后
+            let mut all: Vec<_> = layers.iter().rev().map(std::path::PathBuf::from).collect();
+            all.push(std::path::PathBuf::from(common::rootfs()));
+            Sandbox::from_oci_layers(all, work, target).unwrap()
+        },
+        |sandbox| {
+            // 前面的层在上面
+            let command = "test \"$(cat /layer)\" = 1 && test \"$(cat /base)\" = base";
+            let status = sandbox.run(common::config(command)).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+        },
+    );
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            let empty: [&str; 0] = [];
+            match SandboxBuilder::from_oci_layers(empty, work, target) {
+                Err(SandboxError::InvalidConfig(_)) => {}
+                other => panic!("Wrong result: {:?}", other),
+            }
+            Sandbox::new(rootfs, work, target).unwrap()
+        },
+        |_| {},
+    );
+
+    for layer in &layers {
+        std::fs::remove_dir_all(layer).unwrap();
+    }
+}
+
+#[test]
+fn run_batch() {
+    require_environment!();
+    common::with_sandbox(|mut sandbox| {
+        sandbox
+            .run(common::config(
+                "echo 'read x; echo $((x * 2)); echo $x >> /log' > /prog",
+            ))
+            .unwrap();
+        let inputs = vec![b"1\n".to_vec(), b"2\n".to_vec(), b"3\n".to_vec()];
+        // 每组输入都从编译后的状态开始，/log 中只有本次的输入
+        let results = sandbox
+            .run_batch(
+                || common::config("bash /prog && test $(wc -l < /log) = 1"),
+                inputs.clone(),
+                |_| false,
+            )
+            .unwrap();
+        let outputs: Vec<_> = results.iter().map(|(_, output)| output.clone()).collect();
+        assert_eq!(
+            outputs,
+            vec![b"2\n".to_vec(), b"4\n".to_vec(), b"6\n".to_vec()]
+        );
+        for (status, _) in &results {
+            testing::assert_verdict(status, SandboxStatusKind::Success);
+        }
+
+        let results = sandbox
+            .run_batch(
+                || common::config("read x; test $x != 2"),
+                inputs,
+                |status| status.status != SandboxStatusKind::Success,
+            )
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        testing::assert_verdict(&results[1].0, SandboxStatusKind::RuntimeError);
+
+        // 快照之后的修改对所有输入都不可见，包括第一组
+        sandbox.run(common::config("echo changed > /log")).unwrap();
+        let results = sandbox
+            .run_batch(
+                || common::config("read x; test ! -e /log"),
+                vec![b"1\n".to_vec(), b"2\n".to_vec()],
+                |_| false,
+            )
+            .unwrap();
+        for (status, _) in &results {
+            testing::assert_verdict(status, SandboxStatusKind::Success);
+        }
+    });
+}
+
+#[test]
+fn fixed_limits_concurrent() {
+    require_environment!();
+    let limits = SandboxLimits {
+        time_limit: 1000,
+        memory_limit: 8 * 1024 * 1024,
+        pids_limit: 5,
+    };
+    common::with_sandbox(|sandbox| {
+        let sandbox = sandbox.with_fixed_limits(limits).unwrap();
+
+        // 第一次运行占用预先创建的 cgroup
+        let busy = sandbox
+            .spawn(common::config(
+                "a=$(yes | head -c 4000000); while true; do :; done",
+            ))
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+
+        // 同时进行的运行使用新建的 cgroup，不会重置第一次运行的统计
+        let status = sandbox.run(common::config("true")).unwrap();
+        log::debug!("{:?}", status);
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+        assert!(status.used_time.unwrap() < 200);
+        assert!(status.max_memory.unwrap() < 4_000_000);
+
+        let status = busy.join().unwrap();
+        log::debug!("{:?}", status);
+        testing::assert_time_limit_exceeded(&status);
+        assert!(status.used_time.unwrap() >= 900);
+        assert!(status.max_memory.unwrap() >= 4_000_000);
+
+        // 之后的运行复用 cgroup，统计已被重置
+        let status = sandbox.run(common::config("true")).unwrap();
+        assert!(status.used_time.unwrap() < 200);
+        assert!(status.max_memory.unwrap() < 4_000_000);
+    });
+}
+
+#[test]
+fn with_limits() {
+    require_environment!();
+    let limits = SandboxLimits {
+        time_limit: 500,
+        memory_limit: 8 * 1024 * 1024,
+        pids_limit: 5,
+    };
+    let config = |command: &str| {
+        SandboxConfig::with_limits(
+            limits,
+            command,
+            std::process::Stdio::inherit(),
+            std::process::Stdio::inherit(),
+            std::process::Stdio::inherit(),
+        )
+    };
+    assert_eq!(config("true").limits(), limits);
+    common::with_sandbox(|sandbox| {
+        let status = sandbox.run(config("true")).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+        let status = sandbox.run(config("sleep 2")).unwrap();
+        testing::assert_time_limit_exceeded(&status);
+    });
+}
+
+#[test]
+fn output_directory() {
+    require_environment!();
+    let output = common::temp_path();
+    std::fs::create_dir(&output).unwrap();
+
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            SandboxBuilder::new(rootfs, work, target)
+                .output_directory(&output, "/output")
+                .mount()
+                .unwrap()
+        },
+        |mut sandbox| {
+            let status = sandbox
+                .run(common::config("echo 42 > /output/result"))
+                .unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+            // 写入直接出现在主机目录中，不在 upper 目录里
+            assert_eq!(
+                std::fs::read_to_string(format!("{}/result", output)).unwrap(),
+                "42\n"
+            );
+            assert!(!sandbox.upper_directory().join("output/result").exists());
+            let target = sandbox.sandbox_directory.join("output");
+            assert_eq!(sandbox.remove().unwrap(), Unmount::Clean);
+            assert!(!target.join("result").exists());
+        },
+    );
+
+    std::fs::remove_dir_all(output).unwrap();
+}
+
+#[test]
+fn output_directory_symlink() {
+    require_environment!();
+    let output = common::temp_path();
+    let escape = common::temp_path();
+    std::fs::create_dir(&output).unwrap();
+    std::fs::create_dir(&escape).unwrap();
+
+    common::with_sandbox_from(
+        |rootfs, work, target| {
+            SandboxBuilder::new(rootfs, work, target)
+                .output_directory(&output, "/data/out")
+                .mount()
+                .unwrap()
+        },
+        |mut sandbox| {
+            // 模拟程序把挂载目标的上级目录换成指向主机目录的符号链接
+            let upper = sandbox.upper_directory().to_path_buf();
+            std::fs::rename(upper.join("data"), upper.join("moved")).unwrap();
+            std::os::unix::fs::symlink(&escape, upper.join("data")).unwrap();
+            match sandbox.reset_with(&ResetStrategy::Keep(vec!["/data".into()])) {
+                Err(SandboxError::UnsafeMountTarget(_)) => {}
+                result => panic!("unexpected result {:?}", result),
+            }
+            assert!(!std::path::Path::new(&escape).join("out").exists());
+            sandbox.remove().unwrap();
+        },
+    );
+
+    std::fs::remove_dir_all(output).unwrap();
+    std::fs::remove_dir_all(escape).unwrap();
+}
+
+#[test]
+fn concurrency_limit() {
+    require_environment!();
+    let limit = ConcurrencyLimit::new(1);
+    let first = SandboxBuilder::ephemeral(common::rootfs())
+        .concurrency_limit(&limit)
+        .mount()
+        .unwrap();
+    assert_eq!(limit.available(), 0);
+    assert!(limit
+        .acquire_timeout(std::time::Duration::from_millis(100))
+        .is_none());
+
+    // 第二个沙箱排队，直到第一个卸载
+    let (tx, rx) = std::sync::mpsc::channel();
+    let waiting = limit.clone();
+    let thread = std::thread::spawn(move || {
+        let second = SandboxBuilder::ephemeral(common::rootfs())
+            .concurrency_limit(&waiting)
+            .mount()
+            .unwrap();
+        tx.send(()).unwrap();
+        second.run(common::config("true")).unwrap()
+    });
+    assert!(rx
+        .recv_timeout(std::time::Duration::from_millis(300))
+        .is_err());
+    drop(first);
+    rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+    testing::assert_verdict(&thread.join().unwrap(), SandboxStatusKind::Success);
+    assert_eq!(limit.available(), 1);
+}
+
+#[test]
+fn reset_strategy() {
+    require_environment!();
+    // 失败时指向调用的那一行，从而知道是哪个命令
+    #[track_caller]
+    fn check(sandbox: &Sandbox, command: &str) {
+        let status = sandbox.run(common::config(command)).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    }
+    common::with_sandbox(|mut sandbox| {
+        check(
+            &sandbox,
+            "echo 1 > /main; mkdir -p /tmp/a; echo 2 > /tmp/a/b",
+        );
+        sandbox.snapshot().unwrap();
+
+        check(&sandbox, "echo 3 > /tmp/c; echo 4 > /tmp/a/d; echo 5 > /e");
+        let keep = ResetStrategy::Keep(vec!["/main".into(), "tmp/a/b".into()]);
+        sandbox.reset_with(&keep).unwrap();
+        check(
+            &sandbox,
+            "test -f /main && test -f /tmp/a/b && test ! -e /tmp/a/d && test ! -e /tmp/c && test ! -e /e",
+        );
+
+        check(&sandbox, "rm /main; echo 6 > /f");
+        sandbox.reset_with(&ResetStrategy::Snapshot).unwrap();
+        check(&sandbox, "test -f /main && test ! -e /f");
+
+        sandbox.reset_with(&ResetStrategy::Wipe).unwrap();
+        check(&sandbox, "test ! -e /main && test ! -e /tmp/a");
+    });
+}
@@ -0,0 +1,88 @@
+use nova_sandbox::*;
+use std::fs;
+
+#[macro_use]
+mod common;
+
+fn mounted(directory: &str) -> bool {
+    fs::read_to_string("/proc/mounts")
+        .unwrap()
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some(directory))
+}
+
+#[test]
+fn shared_rootfs() {
+    require_environment!();
+    let mount_directory = common::temp_path();
+    fs::create_dir(&mount_directory).unwrap();
+
+    let shared = SharedRootfs::new(common::rootfs(), &mount_directory).unwrap();
+    let directories: Vec<(String, String)> = (0..2)
+        .map(|_| {
+            let work_directory = common::temp_path();
+            let sandbox_directory = common::temp_path();
+            fs::create_dir(&work_directory).unwrap();
+            fs::create_dir(&sandbox_directory).unwrap();
+            (work_directory, sandbox_directory)
+        })
+        .collect();
+    let sandboxes: Vec<Sandbox> = directories
+        .iter()
+        .map(|(work, target)| shared.sandbox(work, target).unwrap())
+        .collect();
+    drop(shared);
+
+    for sandbox in sandboxes.iter() {
+        let status = sandbox.run(common::config("echo hello > /file")).unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    }
+    // 每个沙箱的写入互不影响
+    for (work, _) in directories.iter() {
+        assert!(std::path::Path::new(work).join("file").exists());
+    }
+
+    let mut sandboxes = sandboxes.into_iter();
+    drop(sandboxes.next());
+    assert!(mounted(&mount_directory));
+    drop(sandboxes.next());
+    assert!(!mounted(&mount_directory));
+
+    for (work, target) in directories.iter() {
+        fs::remove_dir_all(work).unwrap();
+        fs::remove_dir_all(target).unwrap();
+    }
+    fs::remove_dir(&mount_directory).unwrap();
+}
+
+#[test]
+fn readonly_rootfs() {
+    require_environment!();
+    let mount_directory = common::temp_path();
+    fs::create_dir(&mount_directory).unwrap();
+    let shared = SharedRootfs::new(common::rootfs(), &mount_directory).unwrap();
+    assert!(fs::write(shared.directory().join("file"), "").is_err());
+    let before = rootfs_digest(common::rootfs()).unwrap();
+
+    common::with_sandbox_from(
+        |_, work, target| {
+            // upper 和 work 目录在另一个（可写的）文件系统上
+            SandboxBuilder::new(shared.directory(), work, target)
+                .tmpfs(16 * 1024 * 1024)
+                .dev(true)
+                .mount()
+                .unwrap()
+        },
+        |sandbox| {
+            let command = "echo x > /new && mkdir -p /tmp/dir && echo y >> /usr/bin/yes \
+                           && chmod 700 /usr/bin/env && rm /usr/bin/wc && echo z > /dev/null";
+            let status = sandbox.run(common::config(command)).unwrap();
+            testing::assert_verdict(&status, SandboxStatusKind::Success);
+        },
+    );
+
+    // 所有写入都只在 upper 目录中，rootfs 没有任何变化
+    assert_eq!(rootfs_digest(common::rootfs()).unwrap(), before);
+    drop(shared);
+    fs::remove_dir(&mount_directory).unwrap();
+}
@@ -0,0 +1,222 @@
+use nix::sys::signal::Signal;
+use nova_sandbox::*;
+
+#[macro_use]
+mod common;
+
+#[test]
+fn exited() {
+    require_environment!();
+    let status = common::run_sandbox("exit 3");
+    log::debug!("{:?}", status);
+    assert_eq!(status.termination, Termination::Exited(3));
+    assert_eq!(status.return_code, 3);
+    assert_eq!(status.memory_counter, Some(MemoryCounter::Memsw));
+    assert_eq!(status.swap_used, Some(false));
+}
+
+#[test]
+fn used_time_measured() {
+    require_environment!();
+    // 很快结束的程序报告实测的时间，而不是时间限制
+    let status = common::run_sandbox("true");
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+    assert!(status.used_time.unwrap() < 100);
+}
+
+#[test]
+fn signaled() {
+    require_environment!();
+    // 由 OOM killer 结束
+    let status = common::run_sandbox("for i in $(seq 1 10000000000); do echo $i; done;");
+    log::debug!("{:?}", status);
+    assert_eq!(status.termination, Termination::Signaled(Signal::SIGKILL));
+}
+
+#[test]
+fn killed() {
+    require_environment!();
+    let status = common::run_sandbox("sleep 2");
+    log::debug!("{:?}", status);
+    assert_eq!(status.termination, Termination::Killed);
+}
+
+#[test]
+fn timeout_signal() {
+    require_environment!();
+    let mut config = common::config("trap 'exit 3' TERM; while true; do :; done");
+    config.timeout_signal = Signal::SIGTERM;
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    assert_eq!(status.kill_signal, Some(Signal::SIGTERM));
+    assert_eq!(status.termination, Termination::Exited(3));
+    testing::assert_time_limit_exceeded(&status);
+
+    // 忽略 timeout_signal 时最终被 SIGKILL 杀死
+    let mut config = common::config("trap '' TERM; while true; do :; done");
+    config.timeout_signal = Signal::SIGTERM;
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    assert_eq!(status.kill_signal, Some(Signal::SIGKILL));
+    assert_eq!(status.termination, Termination::Killed);
+}
+
+#[test]
+fn background() {
+    require_environment!();
+    // 程序退出后沙箱结束 PID namespace，内核会杀死留在后台的进程
+    let status = common::run_sandbox("(sleep 2 &); exit 0");
+    log::debug!("{:?}", status);
+    assert!(status.wall_time < 1000);
+    // 报告实测的 CPU 时间，sleep 几乎不占用 CPU
+    assert!(status.used_time.unwrap() < 100);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+}
+
+#[test]
+fn oom_pause() {
+    require_environment!();
+    let mut config = common::config("for i in $(seq 1 10000000000); do echo $i; done;");
+    config.oom_behavior = OomBehavior::Pause;
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    assert_eq!(status.termination, Termination::Killed);
+    assert_eq!(status.kill_signal, Some(Signal::SIGKILL));
+    testing::assert_verdict(&status, SandboxStatusKind::MemoryLimitExceeded);
+}
+
+#[test]
+fn orphans_reaped() {
+    require_environment!();
+    // exec 之后的 sleep 不会回收过继给它的子进程，孤儿进程由沙箱的 1 号进程回收
+    let command = "(sleep 0.1 &); (sleep 0.1 &); (sleep 0.1 &); exec sleep 1";
+    common::with_sandbox(|sandbox| {
+        let handle = sandbox.spawn(common::config(command)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(600));
+        let pids = handle
+            .cgroup_paths()
+            .into_iter()
+            .find(|path| path.starts_with("/sys/fs/cgroup/pids"))
+            .unwrap();
+        let current = std::fs::read_to_string(pids.join("pids.current")).unwrap();
+        assert_eq!(current.trim(), "1");
+        let status = handle.join().unwrap();
+        testing::assert_verdict(&status, SandboxStatusKind::Success);
+    });
+}
+
+#[test]
+fn file_size_limit() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let mut config = common::config("exec yes > /big");
+        config.file_size_limit = Some(1024 * 1024);
+        let status = sandbox.run(config).unwrap();
+        log::debug!("{:?}", status);
+        assert_eq!(status.termination, Termination::Signaled(Signal::SIGXFSZ));
+        testing::assert_verdict(&status, SandboxStatusKind::RuntimeError);
+        let size = std::fs::metadata(sandbox.upper_directory().join("big"))
+            .unwrap()
+            .len();
+        assert!(size <= 1024 * 1024);
+    });
+}
+
+#[test]
+fn detailed_stats() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let status = sandbox.run(common::config("true")).unwrap();
+        assert!(status.detailed_stats.is_none());
+
+        let mut config = common::config("seq 1 100000 | wc -l");
+        config.collect_detailed_stats = true;
+        let status = sandbox.run(config).unwrap();
+        log::debug!("{:?}", status);
+        let report = status.detailed_stats.unwrap();
+        assert!(report.memory_stat.contains_key("cache"));
+        assert_eq!(report.oom_kills.unwrap_or(0), 0);
+        assert!(report.cpu_user.is_some() && report.cpu_system.is_some());
+        assert!(report.pids_peak.is_some());
+    });
+}
+
+#[test]
+fn rusage_cpu_time() {
+    require_environment!();
+    let mut config = common::config("i=0; while [ $i -lt 50000 ]; do i=$((i+1)); done");
+    config.cpu_time_source = CpuTimeSource::Rusage;
+    config.collect_detailed_stats = true;
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+    let report = status.detailed_stats.unwrap();
+    let rusage = report.rusage_user.unwrap() + report.rusage_system.unwrap();
+    assert_eq!(status.used_time, Some(rusage.as_millis()));
+    // 只有一个进程，两种统计应当基本一致
+    let cgroup = report.cpu_user.unwrap() + report.cpu_system.unwrap();
+    assert!(
+        cgroup.abs_diff(rusage) < std::cmp::max(std::time::Duration::from_millis(50), rusage / 5)
+    );
+}
+
+#[test]
+fn wakes_on_exit() {
+    require_environment!();
+    common::with_sandbox(|sandbox| {
+        let status = sandbox.run(common::config("sleep 0.05")).unwrap();
+        log::debug!("{:?}", status);
+        // 程序结束后马上返回，不用等满一次检查的间隔（100ms）
+        assert!(status.wall_time < 100);
+    });
+}
+
+#[test]
+fn kill_without_freezer() {
+    require_environment!();
+    let mut config = common::config("sleep 10 & sleep 10 & while true; do :; done");
+    config.use_freezer_on_kill = false;
+    config.fail_on_leftover_tasks = true;
+    let start = std::time::Instant::now();
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_time_limit_exceeded(&status);
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn wall_time_limit() {
+    require_environment!();
+    let mut config = common::config("sleep 5");
+    config.wall_time_limit = Some(300);
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(
+        &status,
+        SandboxStatusKind::TimeLimitExceeded {
+            kind: TimeDimension::Wall,
+        },
+    );
+    assert!(status.wall_time < 1000);
+
+    // 没有超过实际运行时间的上限，CPU 时间也没有超限
+    let mut config = common::config("sleep 1.5");
+    config.wall_time_limit = Some(3000);
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(&status, SandboxStatusKind::Success);
+
+    // CPU 时间超限时不用等到实际运行时间的上限
+    let mut config = common::config("while true; do :; done");
+    config.wall_time_limit = Some(10000);
+    let status = common::run_sandbox_config(config);
+    log::debug!("{:?}", status);
+    testing::assert_verdict(
+        &status,
+        SandboxStatusKind::TimeLimitExceeded {
+            kind: TimeDimension::Cpu,
+        },
+    );
+    assert!(status.wall_time < 5000);
+}